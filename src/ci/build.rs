@@ -43,27 +43,30 @@ impl TaskOutput
     }
 }
 
-pub fn work(work: Work, mut sandbox: Box<Sandbox>, sender: mpsc::Sender<WorkOutput>) {
+pub fn work(work: Work, sandbox: Box<dyn Sandbox>, sender: mpsc::Sender<WorkOutput>) {
     thread::spawn(move || {
-        let mut results = Vec::new();
+        sender.send(run(work, sandbox)).ok();
+    });
+}
 
-        for task in work.tasks.iter() {
-            let result = self::task(task.clone(), &mut sandbox);
-            results.push(result.clone());
+/// Runs every task in some work, stopping at the first one that fails.
+pub fn run(work: Work, mut sandbox: Box<dyn Sandbox>) -> WorkOutput {
+    let mut results = Vec::new();
 
-            if !result.output.is_successful() { break };
-        }
+    for task in work.tasks.iter() {
+        let result = self::task(task.clone(), &mut sandbox);
+        results.push(result.clone());
 
-        let work_output = WorkOutput {
-            work: work,
-            task_results: results,
-        };
+        if !result.output.is_successful() { break };
+    }
 
-        sender.send(work_output).ok();
-    });
+    WorkOutput {
+        work,
+        task_results: results,
+    }
 }
 
-pub fn task(task: Task, sandbox: &mut Box<Sandbox>) -> TaskResult
+pub fn task(task: Task, sandbox: &mut Box<dyn Sandbox>) -> TaskResult
 {
     let path = format!("nameless-work-{}", Uuid::new_v4());
     let task_output = sandbox.run(task.command.clone(), &Path::new(&path));
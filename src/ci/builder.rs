@@ -12,6 +12,11 @@ pub struct Builder
 
     running_work: HashMap<Uuid, RunningWork>,
     completed_work: VecDeque<CompletedWork>,
+
+    /// Creates the sandbox to run each piece of work in.
+    sandbox: fn() -> Box<dyn ci::Sandbox>,
+    /// Whether work runs on a thread of its own.
+    threaded: bool,
 }
 
 pub struct RunningWork
@@ -31,6 +36,18 @@ pub struct CompletedWork
 impl Builder
 {
     pub fn new() -> Self {
+        Builder::with_sandbox(|| Box::new(ci::sandbox::Basic), true)
+    }
+
+    /// Creates a builder that runs work as soon as it is given it, rather
+    /// than on another thread.
+    ///
+    /// Work always finishes in the same order, which simulations rely on.
+    pub fn inline(sandbox: fn() -> Box<dyn ci::Sandbox>) -> Self {
+        Builder::with_sandbox(sandbox, false)
+    }
+
+    fn with_sandbox(sandbox: fn() -> Box<dyn ci::Sandbox>, threaded: bool) -> Self {
         let (tx, rx) = mpsc::channel();
 
         Builder {
@@ -38,6 +55,8 @@ impl Builder
             rx: rx,
             running_work: HashMap::new(),
             completed_work: VecDeque::new(),
+            sandbox,
+            threaded,
         }
     }
 
@@ -48,8 +67,11 @@ impl Builder
 
         self.running_work.insert(work.uuid, pending_work);
 
-        let ci = ci::sandbox::Basic;
-        ci::build::work(work, Box::new(ci), tx);
+        if self.threaded {
+            ci::build::work(work, (self.sandbox)(), tx);
+        } else {
+            tx.send(ci::build::run(work, (self.sandbox)())).ok();
+        }
     }
 
     pub fn tick(&mut self) {
@@ -74,6 +96,11 @@ impl Builder
         self.completed_work.drain(..)
     }
 
+    /// The number of pieces of work that haven't finished yet.
+    pub fn running_work_count(&self) -> usize {
+        self.running_work.len()
+    }
+
     /// Decides whether or not we are ready to do more work.
     pub fn should_pickup_work(&self) -> bool {
         true
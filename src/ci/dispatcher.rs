@@ -1,34 +1,46 @@
+use {Error, Random};
 use ci::{self, Job, Task};
 
-use std::collections::{HashMap, VecDeque};
+use std::collections::{BTreeMap, HashSet, VecDeque};
 
 use uuid::Uuid;
 
+/// The number of completed jobs that are kept around for their results.
+///
+/// Once there are more, the oldest are forgotten.
+pub const MAX_COMPLETED_JOBS: usize = 100;
+
 pub struct Dispatcher
 {
     pending_jobs: VecDeque<Job>,
     running_jobs: VecDeque<RunningJob>,
     completed_jobs: VecDeque<CompletedJob>,
+    random: Random,
 }
 
 struct RunningJob
 {
     job: Job,
     pending_tasks: VecDeque<Task>,
-    running_work: HashMap<Uuid, RunningWork>,
+    running_work: BTreeMap<Uuid, RunningWork>,
     completed_tasks: Vec<ci::build::TaskResult>,
+    cancelled_tasks: Vec<Task>,
 }
 
 pub struct CompletedJob
 {
     pub job: Job,
     pub task_results: Vec<ci::build::TaskResult>,
+    /// The tasks that never ran because a task they depend on failed.
+    pub cancelled_tasks: Vec<Task>,
 }
 
 struct RunningWork
 {
     uuid: Uuid,
-    running_tasks: HashMap<Uuid, Task>,
+    /// The UUID of the node that is executing the work.
+    executor: Uuid,
+    running_tasks: Vec<Task>,
     completed_tasks: Vec<ci::build::TaskResult>,
 }
 
@@ -38,51 +50,96 @@ pub struct CompletedWork
     pub task_results: Vec<ci::build::TaskResult>,
 }
 
+/// How far a job has got.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum JobState
+{
+    /// None of the tasks of the job have been handed out yet.
+    Pending,
+    Running,
+    /// Every task of the job has finished or been cancelled.
+    Completed,
+}
+
+/// How far a task has got.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TaskState<'a>
+{
+    /// Waiting to be handed out.
+    Pending,
+    /// Being executed by a node.
+    Running { executor: Uuid },
+    Finished(&'a ci::build::TaskResult),
+    /// The task will never run, because a task it depends on failed or
+    /// its job was cancelled.
+    Cancelled,
+}
+
+/// A snapshot of a job and each of its tasks.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct JobStatus<'a>
+{
+    pub job: &'a Job,
+    pub state: JobState,
+    /// Every task in the job, in the order the job lists them.
+    pub tasks: Vec<(&'a Task, TaskState<'a>)>,
+}
+
 impl Dispatcher
 {
-    pub fn new() -> Self {
+    pub fn new(random: &Random) -> Self {
         Dispatcher {
             pending_jobs: VecDeque::new(),
             running_jobs: VecDeque::new(),
             completed_jobs: VecDeque::new(),
+            random: random.clone(),
         }
     }
 
     /// Adds a new job to the queue.
-    pub fn enqueue(&mut self, job: Job) {
+    ///
+    /// Jobs with tasks that could never run are refused.
+    pub fn enqueue(&mut self, job: Job) -> Result<(), Error> {
+        job.validate()?;
+
         self.pending_jobs.push_back(job);
+        Ok(())
     }
 
-    /// Poll the dispatcher for work.
-    pub fn poll(&mut self) -> Option<ci::build::Work> {
-        // Push a pending job if running jobs have no work.
-        if !self.running_jobs.iter().any(|job| job.has_pending_tasks()) {
+    /// Poll the dispatcher for work to be executed by a node.
+    ///
+    /// Tasks are only handed out once the tasks they depend on have succeeded.
+    pub fn poll(&mut self, executor: &Uuid) -> Option<ci::build::Work> {
+        // Push a pending job if running jobs have no work that can be started.
+        if !self.running_jobs.iter().any(|job| job.has_ready_tasks()) {
             if let Some(pending_job) = self.pending_jobs.pop_front() {
                 // We may need to move the next job onto the queue.
                 self.running_jobs.push_back(RunningJob {
                     pending_tasks: pending_job.tasks.iter().cloned().collect(),
-                    running_work: HashMap::new(),
+                    running_work: BTreeMap::new(),
                     completed_tasks: Vec::new(),
+                    cancelled_tasks: Vec::new(),
                     job: pending_job,
                 });
             }
         }
 
-        if let Some(running_job) = self.running_jobs.iter_mut().find(|job| job.has_pending_tasks()) {
-            let tasks = if let Some(pending_task) = running_job.pending_tasks.pop_front() {
-                vec![pending_task]
+        if let Some(running_job) = self.running_jobs.iter_mut().find(|job| job.has_ready_tasks()) {
+            let tasks = if let Some(ready_task) = running_job.take_ready_task() {
+                vec![ready_task]
             } else {
                 vec![]
             };
 
             let work = ci::build::Work {
-                uuid: Uuid::new_v4(),
+                uuid: self.random.uuid(),
                 tasks: tasks.into_iter().collect(),
             };
 
             let running_work = RunningWork {
                 uuid: work.uuid.clone(),
-                running_tasks: work.tasks.iter().map(|t| (t.uuid.clone(), t.clone())).collect(),
+                executor: *executor,
+                running_tasks: work.tasks.iter().cloned().collect(),
                 completed_tasks: Vec::new(),
             };
 
@@ -95,17 +152,68 @@ impl Dispatcher
     }
 
     /// Marks some work as completed.
-    pub fn complete(&mut self, work: CompletedWork) {
+    ///
+    /// Work that we aren't waiting on, such as work that has already
+    /// been reassigned to another node or already completed, is ignored.
+    ///
+    /// Returns `false` if the work was ignored.
+    pub fn complete(&mut self, work: CompletedWork) -> bool {
         {
-            let job_uuid = self.find_job_uuid_containing_work_uuid(&work.uuid).unwrap();
+            let job_uuid = match self.find_job_uuid_containing_work_uuid(&work.uuid) {
+                Some(job_uuid) => job_uuid,
+                None => return false,
+            };
+
             let running_job = self.running_jobs.iter_mut().find(|job| job.job.uuid == job_uuid).unwrap();
             let mut running_work = running_job.running_work.remove(&work.uuid).unwrap();
 
             running_work.completed_tasks.extend(work.task_results);
             running_job.completed_tasks.extend(running_work.completed_tasks);
+            running_job.cancel_blocked_tasks();
         }
 
         self.move_finished_jobs();
+        true
+    }
+
+    /// Puts all work being executed by a node back onto the queue.
+    ///
+    /// This should be called when a node leaves the network so that
+    /// another node can pick its tasks up.
+    ///
+    /// Returns the number of tasks that were reassigned.
+    pub fn reassign_work_from(&mut self, executor: &Uuid) -> usize {
+        let mut reassigned_task_count = 0;
+
+        for running_job in self.running_jobs.iter_mut() {
+            let lost_work_uuids: Vec<_> = running_job.running_work.values()
+                .filter(|work| &work.executor == executor)
+                .map(|work| work.uuid)
+                .collect();
+
+            for work_uuid in lost_work_uuids {
+                let lost_work = running_job.running_work.remove(&work_uuid).unwrap();
+                reassigned_task_count += lost_work.running_tasks.len();
+
+                // Put the tasks back at the front so they keep their order.
+                for task in lost_work.running_tasks.into_iter().rev() {
+                    running_job.pending_tasks.push_front(task);
+                }
+            }
+        }
+
+        reassigned_task_count
+    }
+
+    /// Gets every node that is executing work for us.
+    pub fn executors(&self) -> Vec<Uuid> {
+        let mut executors: Vec<_> = self.running_jobs.iter()
+            .flat_map(|running_job| running_job.running_work.values().map(|work| work.executor))
+            .collect();
+
+        executors.sort();
+        executors.dedup();
+        executors
     }
 
     /// Checks if the dispatcher has work ready.
@@ -113,6 +221,60 @@ impl Dispatcher
         !self.pending_jobs.is_empty() || !self.running_jobs.is_empty()
     }
 
+    /// Cancels every task of a job that hasn't finished yet.
+    ///
+    /// Work that has already been handed out isn't stopped, but its
+    /// results are ignored.
+    ///
+    /// Returns `false` if the job isn't pending or running.
+    pub fn cancel(&mut self, job: &Uuid) -> bool {
+        if let Some(index) = self.pending_jobs.iter().position(|pending_job| &pending_job.uuid == job) {
+            let pending_job = self.pending_jobs.remove(index).unwrap();
+
+            self.push_completed_job(CompletedJob {
+                task_results: Vec::new(),
+                cancelled_tasks: pending_job.tasks.clone(),
+                job: pending_job,
+            });
+            return true;
+        }
+
+        match self.running_jobs.iter_mut().find(|running_job| &running_job.job.uuid == job) {
+            Some(running_job) => running_job.cancel(),
+            None => return false,
+        }
+
+        self.move_finished_jobs();
+        true
+    }
+
+    /// Gets the status of every job, completed jobs first, then running
+    /// jobs, then pending jobs.
+    ///
+    /// Completed jobs are only included until they are taken with `completed_jobs`,
+    /// and only the most recent `MAX_COMPLETED_JOBS` of them are kept.
+    pub fn jobs(&self) -> Vec<JobStatus<'_>> {
+        let completed_jobs = self.completed_jobs.iter().map(|completed_job| completed_job.status());
+        let running_jobs = self.running_jobs.iter().map(|running_job| running_job.status());
+        let pending_jobs = self.pending_jobs.iter().map(|pending_job| JobStatus {
+            job: pending_job,
+            state: JobState::Pending,
+            tasks: pending_job.tasks.iter().map(|task| (task, TaskState::Pending)).collect(),
+        });
+
+        completed_jobs.chain(running_jobs).chain(pending_jobs).collect()
+    }
+
+    /// Gets the status of a job.
+    pub fn job_status(&self, job: &Uuid) -> Option<JobStatus<'_>> {
+        self.jobs().into_iter().find(|status| &status.job.uuid == job)
+    }
+
+    /// Gets the results of the tasks of a running job that have finished so far.
+    pub fn finished_tasks(&self, job: &Uuid) -> Option<&[ci::build::TaskResult]> {
+        self.running_jobs.iter().find(|running_job| &running_job.job.uuid == job).map(|running_job| &running_job.completed_tasks[..])
+    }
+
     pub fn completed_jobs(&mut self) -> impl Iterator<Item=CompletedJob> {
         let completed_jobs: Vec<_> = self.completed_jobs.drain(..).collect();
         completed_jobs.into_iter()
@@ -125,30 +287,162 @@ impl Dispatcher
             map(|a| a.0).
             collect();
 
-        let mut completed_jobs = Vec::new();
-        for idx in finished_indices {
+        // Remove from the back so that the other indices stay valid.
+        for idx in finished_indices.into_iter().rev() {
             let running_job = self.running_jobs.remove(idx).unwrap();
 
-            completed_jobs.push(CompletedJob {
+            self.push_completed_job(CompletedJob {
                 job: running_job.job,
                 task_results: running_job.completed_tasks,
+                cancelled_tasks: running_job.cancelled_tasks,
             });
         }
     }
 
+    /// Keeps the results of a job, forgetting the oldest results if there are too many.
+    fn push_completed_job(&mut self, completed_job: CompletedJob) {
+        if self.completed_jobs.len() == MAX_COMPLETED_JOBS {
+            self.completed_jobs.pop_front();
+        }
+
+        self.completed_jobs.push_back(completed_job);
+    }
+
     fn find_job_uuid_containing_work_uuid(&self, work_uuid: &Uuid) -> Option<Uuid> {
         self.running_jobs.iter().find(|job| job.running_work.contains_key(work_uuid)).map(|job| job.job.uuid)
     }
 }
 
+impl CompletedJob
+{
+    /// Checks if every task in the job ran and succeeded.
+    pub fn is_successful(&self) -> bool {
+        self.cancelled_tasks.is_empty() && self.task_results.iter().all(|result| result.output.is_successful())
+    }
+
+    /// Gets the result code of the job as a whole.
+    ///
+    /// This is zero if the job succeeded, and otherwise the result code of
+    /// the first task in the job that failed.
+    pub fn result_code(&self) -> i64 {
+        self.status().result_code().unwrap()
+    }
+
+    /// Gets how far each task of the job got.
+    pub fn status(&self) -> JobStatus<'_> {
+        let tasks = self.job.tasks.iter().map(|task| {
+            // Tasks without results never ran.
+            let state = match self.task_results.iter().find(|result| result.task.uuid == task.uuid) {
+                Some(result) => TaskState::Finished(result),
+                None => TaskState::Cancelled,
+            };
+
+            (task, state)
+        }).collect();
+
+        JobStatus { job: &self.job, state: JobState::Completed, tasks }
+    }
+}
+
+impl<'a> JobStatus<'a>
+{
+    /// Gets the result code of the job as a whole, if it has completed.
+    ///
+    /// This is zero if the job succeeded, and otherwise the result code of
+    /// the first task in the job that failed.
+    pub fn result_code(&self) -> Option<i64> {
+        if self.state != JobState::Completed { return None }
+
+        let failure = self.tasks.iter().filter_map(|(_, state)| match *state {
+            TaskState::Finished(result) if !result.output.is_successful() => Some(result.output.result_code),
+            _ => None,
+        }).next();
+
+        match failure {
+            Some(result_code) => Some(result_code),
+            // Tasks are only cancelled when another task fails or the job is
+            // cancelled, but don't report success if we get here.
+            None if self.tasks.iter().any(|(_, state)| state == &TaskState::Cancelled) => Some(1),
+            None => Some(0),
+        }
+    }
+}
+
 impl RunningJob
 {
     pub fn is_complete(&self) -> bool {
         self.pending_tasks.is_empty() && self.running_work.is_empty()
     }
 
-    pub fn has_pending_tasks(&self) -> bool {
-        !self.pending_tasks.is_empty()
+    /// Checks if any task can be started yet.
+    pub fn has_ready_tasks(&self) -> bool {
+        self.pending_tasks.iter().any(|task| self.is_ready(task))
+    }
+
+    /// Takes the first task that can be started.
+    fn take_ready_task(&mut self) -> Option<Task> {
+        let index = self.pending_tasks.iter().position(|task| self.is_ready(task))?;
+        self.pending_tasks.remove(index)
+    }
+
+    /// Checks if every task that a task depends on has succeeded.
+    fn is_ready(&self, task: &Task) -> bool {
+        task.dependencies.iter().all(|dependency| {
+            self.completed_tasks.iter().any(|result| &result.task.uuid == dependency && result.output.is_successful())
+        })
+    }
+
+    /// Cancels every task that hasn't finished, including those being executed.
+    fn cancel(&mut self) {
+        let running_tasks = ::std::mem::take(&mut self.running_work).into_values().flat_map(|work| work.running_tasks);
+
+        self.cancelled_tasks.extend(self.pending_tasks.drain(..).chain(running_tasks));
+    }
+
+    fn status(&self) -> JobStatus<'_> {
+        let tasks = self.job.tasks.iter().map(|task| {
+            let executor = self.running_work.values()
+                .find(|work| work.running_tasks.iter().any(|running_task| running_task.uuid == task.uuid))
+                .map(|work| work.executor);
+
+            let state = if let Some(result) = self.completed_tasks.iter().find(|result| result.task.uuid == task.uuid) {
+                TaskState::Finished(result)
+            } else if let Some(executor) = executor {
+                TaskState::Running { executor }
+            } else if self.cancelled_tasks.iter().any(|cancelled_task| cancelled_task.uuid == task.uuid) {
+                TaskState::Cancelled
+            } else {
+                TaskState::Pending
+            };
+
+            (task, state)
+        }).collect();
+
+        JobStatus { job: &self.job, state: JobState::Running, tasks }
+    }
+
+    /// Cancels the pending tasks that depend on a task that failed or
+    /// was itself cancelled, as they can never run.
+    fn cancel_blocked_tasks(&mut self) {
+        loop {
+            let doomed: HashSet<_> = self.completed_tasks.iter()
+                .filter(|result| !result.output.is_successful())
+                .map(|result| result.task.uuid)
+                .chain(self.cancelled_tasks.iter().map(|task| task.uuid))
+                .collect();
+
+            let blocked_index = self.pending_tasks.iter()
+                .position(|task| task.dependencies.iter().any(|dependency| doomed.contains(dependency)));
+
+            match blocked_index {
+                Some(index) => {
+                    let task = self.pending_tasks.remove(index).unwrap();
+                    println!("cancelling task {} of job {} as a task it depends on failed", task, self.job.uuid);
+                    self.cancelled_tasks.push(task);
+                },
+                None => break,
+            }
+        }
     }
 }
 
@@ -160,22 +454,13 @@ mod test
     pub use ci::build::*;
     pub use uuid::Uuid;
 
-    fn setup() -> (Dispatcher, Job, Job, Task, Task) {
-        let task1 = Task {
-            uuid: Uuid::new_v4(),
-            command: Command {
-                executable: "echo".to_owned(),
-                arguments: vec!["foo".to_owned(), "bar".to_owned()],
-            },
-        };
+    fn executor() -> Uuid {
+        Uuid::parse_str("2c6bb858-72ea-4db6-a9ab-6ec0ca1f18ab").unwrap()
+    }
 
-        let task2 = Task {
-            uuid: Uuid::new_v4(),
-            command: Command {
-                executable: "cat".to_owned(),
-                arguments: vec!["/etc/hosts".to_owned()],
-            },
-        };
+    fn setup() -> (Dispatcher, Job, Job, Task, Task) {
+        let task1 = Task::new(Command::new("echo", vec!["foo".to_owned(), "bar".to_owned()]));
+        let task2 = Task::new(Command::new("cat", vec!["/etc/hosts".to_owned()]));
 
         let job1 = Job {
             uuid: Uuid::new_v4(),
@@ -187,10 +472,10 @@ mod test
             tasks: vec![task2.clone()],
         };
 
-        let mut dispatcher = Dispatcher::new();
-        dispatcher.enqueue(job1.clone());
+        let mut dispatcher = Dispatcher::new(&Random::system());
+        dispatcher.enqueue(job1.clone()).unwrap();
         assert_eq!(dispatcher.pending_jobs.len(), 1);
-        dispatcher.enqueue(job2.clone());
+        dispatcher.enqueue(job2.clone()).unwrap();
         assert_eq!(dispatcher.pending_jobs.len(), 2);
 
         (dispatcher, job1, job2, task1, task2)
@@ -201,7 +486,7 @@ mod test
         let (mut dispatcher, job1, _, _, _) = setup();
 
         assert_eq!(dispatcher.pending_jobs.len(), 2);
-        dispatcher.enqueue(job1);
+        dispatcher.enqueue(job1).unwrap();
         assert_eq!(dispatcher.pending_jobs.len(), 3);
     }
 
@@ -209,10 +494,10 @@ mod test
     fn poll_works_in_correct_order() {
         let (mut dispatcher, _, _, task1, task2) = setup();
 
-        assert_eq!(dispatcher.poll().unwrap().tasks[0], task1);
-        assert_eq!(dispatcher.poll().unwrap().tasks[0], task2);
-        assert_eq!(dispatcher.poll(), None);
-        assert_eq!(dispatcher.poll(), None);
+        assert_eq!(dispatcher.poll(&executor()).unwrap().tasks[0], task1);
+        assert_eq!(dispatcher.poll(&executor()).unwrap().tasks[0], task2);
+        assert_eq!(dispatcher.poll(&executor()), None);
+        assert_eq!(dispatcher.poll(&executor()), None);
     }
 
     #[test]
@@ -222,7 +507,7 @@ mod test
         assert!(dispatcher.has_work());
 
         assert_eq!(dispatcher.running_jobs.len(), 0);
-        let work = dispatcher.poll().unwrap();
+        let work = dispatcher.poll(&executor()).unwrap();
         assert_eq!(dispatcher.running_jobs.len(), 1);
 
         dispatcher.complete(CompletedWork {
@@ -241,7 +526,7 @@ mod test
         assert_eq!(dispatcher.running_jobs.len(), 0);
         assert!(dispatcher.has_work());
 
-        let work = dispatcher.poll().unwrap();
+        let work = dispatcher.poll(&executor()).unwrap();
         assert_eq!(dispatcher.running_jobs.len(), 1);
         assert_eq!(dispatcher.pending_jobs.len(), 0);
 
@@ -262,5 +547,219 @@ mod test
         assert_eq!(dispatcher.pending_jobs.len(), 0);
         assert!(!dispatcher.has_work());
     }
-}
 
+    #[test]
+    fn reassign_work_from_requeues_tasks_of_lost_executor() {
+        let (mut dispatcher, _, _, task1, _) = setup();
+        let other_executor = Uuid::parse_str("3042be80-4d2b-4cce-b30c-3142e3035720").unwrap();
+
+        let work = dispatcher.poll(&executor()).unwrap();
+        assert_eq!(work.tasks[0], task1);
+
+        assert_eq!(dispatcher.reassign_work_from(&other_executor), 0);
+        assert_eq!(dispatcher.reassign_work_from(&executor()), 1);
+
+        // The task should be handed out again, to somebody else.
+        let reassigned_work = dispatcher.poll(&other_executor).unwrap();
+        assert_eq!(reassigned_work.tasks[0], task1);
+        assert!(reassigned_work.uuid != work.uuid);
+
+        // Late results from the lost executor are ignored.
+        dispatcher.complete(CompletedWork { uuid: work.uuid, task_results: Vec::new() });
+        assert_eq!(dispatcher.running_jobs.len(), 1);
+    }
+
+    #[test]
+    fn completing_work_twice_only_counts_it_once() {
+        let (mut dispatcher, job1, _, task1, _) = setup();
+
+        let work = dispatcher.poll(&executor()).unwrap();
+        let completed_work = || CompletedWork {
+            uuid: work.uuid,
+            task_results: vec![TaskResult {
+                task: task1.clone(),
+                output: TaskOutput { output: Vec::new(), result_code: 0 },
+            }],
+        };
+
+        assert!(dispatcher.complete(completed_work()));
+        assert!(!dispatcher.complete(completed_work()));
+
+        let completed_jobs: Vec<_> = dispatcher.completed_jobs().collect();
+        assert_eq!(completed_jobs.len(), 1);
+        assert_eq!(completed_jobs[0].job.uuid, job1.uuid);
+        assert_eq!(completed_jobs[0].task_results.len(), 1);
+    }
+
+    #[test]
+    fn executors_lists_each_node_with_work_once() {
+        let (mut dispatcher, _, _, _, _) = setup();
+        assert!(dispatcher.executors().is_empty());
+
+        dispatcher.poll(&executor()).unwrap();
+        dispatcher.poll(&executor()).unwrap();
+
+        assert_eq!(dispatcher.executors(), vec![executor()]);
+    }
+
+    fn result(task: &Task, result_code: i64) -> TaskResult {
+        TaskResult {
+            task: task.clone(),
+            output: TaskOutput { output: Vec::new(), result_code: result_code },
+        }
+    }
+
+    /// Hands out the next task, and completes it with a result code.
+    fn run_next(dispatcher: &mut Dispatcher, result_code: i64) -> Task {
+        let work = dispatcher.poll(&executor()).unwrap();
+        let task = work.tasks[0].clone();
+
+        dispatcher.complete(CompletedWork { uuid: work.uuid, task_results: vec![result(&task, result_code)] });
+        task
+    }
+
+    /// Creates a job where `test` and `lint` depend on `build`, and `deploy` depends on `test`.
+    fn pipeline() -> (Job, Task, Task, Task, Task) {
+        let build = Task::new(Command::new("make", Vec::new()));
+        let mut test = Task::new(Command::new("make", vec!["test".to_owned()]));
+        let mut lint = Task::new(Command::new("make", vec!["lint".to_owned()]));
+        let mut deploy = Task::new(Command::new("make", vec!["deploy".to_owned()]));
+
+        test.dependencies = vec![build.uuid];
+        lint.dependencies = vec![build.uuid];
+        deploy.dependencies = vec![test.uuid];
+
+        let job = Job {
+            uuid: Uuid::new_v4(),
+            // Out of order, to show that dependencies win over order.
+            tasks: vec![deploy.clone(), test.clone(), build.clone(), lint.clone()],
+        };
+
+        (job, build, test, lint, deploy)
+    }
+
+    #[test]
+    fn tasks_wait_for_their_dependencies() {
+        let (job, build, test, lint, deploy) = pipeline();
+        let job_uuid = job.uuid;
+        let mut dispatcher = Dispatcher::new(&Random::system());
+        dispatcher.enqueue(job).unwrap();
+
+        let build_work = dispatcher.poll(&executor()).unwrap();
+        assert_eq!(build_work.tasks[0], build);
+        // Nothing else can run until the build is done.
+        assert_eq!(dispatcher.poll(&executor()), None);
+
+        dispatcher.complete(CompletedWork { uuid: build_work.uuid, task_results: vec![result(&build, 0)] });
+        assert_eq!(dispatcher.finished_tasks(&job_uuid).unwrap()[0].task, build);
+
+        assert_eq!(run_next(&mut dispatcher, 0), test);
+        assert_eq!(run_next(&mut dispatcher, 0), deploy);
+        assert_eq!(run_next(&mut dispatcher, 0), lint);
+
+        let completed_jobs: Vec<_> = dispatcher.completed_jobs().collect();
+        assert_eq!(completed_jobs[0].task_results.len(), 4);
+        assert!(completed_jobs[0].cancelled_tasks.is_empty());
+        assert_eq!(completed_jobs[0].result_code(), 0);
+    }
+
+    #[test]
+    fn failed_tasks_cancel_everything_that_depends_on_them() {
+        let (job, build, _, _, _) = pipeline();
+        let mut dispatcher = Dispatcher::new(&Random::system());
+        dispatcher.enqueue(job).unwrap();
+
+        assert_eq!(run_next(&mut dispatcher, 2), build);
+        assert_eq!(dispatcher.poll(&executor()), None);
+        assert!(!dispatcher.has_work());
+
+        let completed_jobs: Vec<_> = dispatcher.completed_jobs().collect();
+        assert_eq!(completed_jobs[0].task_results.len(), 1);
+        assert_eq!(completed_jobs[0].cancelled_tasks.len(), 3);
+        assert!(!completed_jobs[0].is_successful());
+        assert_eq!(completed_jobs[0].result_code(), 2);
+    }
+
+    #[test]
+    fn blocked_jobs_let_later_jobs_run() {
+        let (job, build, _, _, _) = pipeline();
+        let (mut dispatcher, _, _, task1, _) = setup();
+        dispatcher.pending_jobs.push_front(job);
+
+        assert_eq!(dispatcher.poll(&executor()).unwrap().tasks[0], build);
+        assert_eq!(dispatcher.poll(&executor()).unwrap().tasks[0], task1);
+    }
+
+    #[test]
+    fn jobs_with_cyclic_dependencies_are_refused() {
+        let mut a = Task::new(Command::new("true", Vec::new()));
+        let mut b = Task::new(Command::new("true", Vec::new()));
+        a.dependencies = vec![b.uuid];
+        b.dependencies = vec![a.uuid];
+
+        let mut dispatcher = Dispatcher::new(&Random::system());
+        assert!(dispatcher.enqueue(Job { uuid: Uuid::new_v4(), tasks: vec![a, b] }).is_err());
+        assert!(!dispatcher.has_work());
+    }
+
+    #[test]
+    fn job_status_shows_how_far_each_task_has_got() {
+        let (job, build, test, lint, deploy) = pipeline();
+        let job_uuid = job.uuid;
+        let mut dispatcher = Dispatcher::new(&Random::system());
+        dispatcher.enqueue(job).unwrap();
+        assert_eq!(dispatcher.job_status(&job_uuid).unwrap().state, JobState::Pending);
+
+        run_next(&mut dispatcher, 0);
+        dispatcher.poll(&executor()).unwrap();
+
+        let status = dispatcher.job_status(&job_uuid).unwrap();
+        let state_of = |task: &Task| status.tasks.iter().find(|&&(t, _)| t == task).unwrap().1.clone();
+
+        assert_eq!(status.state, JobState::Running);
+        assert_eq!(state_of(&build), TaskState::Finished(&result(&build, 0)));
+        assert_eq!(state_of(&test), TaskState::Running { executor: executor() });
+        assert_eq!(state_of(&lint), TaskState::Pending);
+        assert_eq!(state_of(&deploy), TaskState::Pending);
+        assert_eq!(status.result_code(), None);
+    }
+
+    #[test]
+    fn cancelled_jobs_complete_straight_away() {
+        let (mut dispatcher, job1, job2, _, _) = setup();
+        let work = dispatcher.poll(&executor()).unwrap();
+
+        assert!(dispatcher.cancel(&job1.uuid));
+        assert!(dispatcher.cancel(&job2.uuid));
+        assert!(!dispatcher.cancel(&job2.uuid));
+        assert!(!dispatcher.has_work());
+
+        // The work handed out before the job was cancelled doesn't count.
+        assert!(!dispatcher.complete(CompletedWork { uuid: work.uuid, task_results: Vec::new() }));
+
+        let statuses = dispatcher.jobs();
+        assert_eq!(statuses.len(), 2);
+        assert!(statuses.iter().all(|status| status.state == JobState::Completed));
+        assert!(statuses.iter().all(|status| status.tasks.iter().all(|&(_, ref state)| state == &TaskState::Cancelled)));
+        assert!(statuses.iter().all(|status| status.result_code() == Some(1)));
+    }
+
+    #[test]
+    fn only_the_most_recent_completed_jobs_are_kept() {
+        let mut dispatcher = Dispatcher::new(&Random::system());
+
+        let jobs: Vec<_> = (0..MAX_COMPLETED_JOBS + 1).map(|_| Job {
+            uuid: Uuid::new_v4(),
+            tasks: vec![Task::new(Command::new("true", vec![]))],
+        }).collect();
+
+        for job in jobs.iter() {
+            dispatcher.enqueue(job.clone()).unwrap();
+            assert!(dispatcher.cancel(&job.uuid));
+        }
+
+        assert!(dispatcher.job_status(&jobs[0].uuid).is_none());
+        assert!(dispatcher.job_status(&jobs[MAX_COMPLETED_JOBS].uuid).is_some());
+        assert_eq!(dispatcher.completed_jobs().count(), MAX_COMPLETED_JOBS);
+    }
+}
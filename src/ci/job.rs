@@ -1,5 +1,12 @@
+use Error;
+
 use uuid::Uuid;
 
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::path::PathBuf;
+use std::time::Duration;
+
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct Job
 {
@@ -11,7 +18,11 @@ pub struct Job
 pub struct Task
 {
     pub uuid: Uuid,
+    /// A human-readable name for the task, unique within its job.
+    pub name: Option<String>,
     pub command: Command,
+    /// The tasks in the same job that must succeed before this one can run.
+    pub dependencies: Vec<Uuid>,
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
@@ -19,5 +30,262 @@ pub struct Command
 {
     pub executable: String,
     pub arguments: Vec<String>,
+    /// Variables to set in the environment of the command.
+    pub environment: BTreeMap<String, String>,
+    /// The directory to run the command in, relative to the directory of its task.
+    pub working_dir: Option<PathBuf>,
+    /// How long the command may run for before it is killed.
+    pub timeout: Option<Duration>,
+}
+
+/// A task as a user describes it, in a job file or through the control API.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TaskDescription
+{
+    pub name: Option<String>,
+    /// The executable, followed by its arguments.
+    pub command: Vec<String>,
+    /// Variables set on the task, which take precedence over those set for the whole job.
+    pub environment: BTreeMap<String, String>,
+    pub working_dir: Option<PathBuf>,
+    pub timeout: Option<Duration>,
+    /// The names of the tasks that must succeed before this one can run.
+    pub depends_on: Vec<String>,
+}
+
+/// How far a depth-first search has got through a task.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Visit
+{
+    InProgress,
+    Done,
+}
+
+impl Job
+{
+    /// Builds a job out of descriptions of its tasks, and the variables set
+    /// for every task.
+    ///
+    /// The job may still depend on itself, which `validate` checks.
+    pub fn from_descriptions(descriptions: Vec<TaskDescription>,
+                             environment: &BTreeMap<String, String>) -> Result<Self, Error> {
+        if descriptions.is_empty() {
+            return Err(invalid("the job has no tasks"));
+        }
+
+        // Tasks are given their UUIDs up front, so that tasks can depend on
+        // tasks that come after them.
+        let uuids: Vec<_> = descriptions.iter().map(|_| Uuid::new_v4()).collect();
+        let mut uuids_by_name = BTreeMap::new();
+
+        for (description, &uuid) in descriptions.iter().zip(uuids.iter()) {
+            if let Some(ref name) = description.name {
+                if uuids_by_name.insert(name.clone(), uuid).is_some() {
+                    return Err(invalid(&format!("there is more than one task named `{}`", name)));
+                }
+            }
+        }
+
+        let tasks = descriptions.into_iter().zip(uuids).map(|(description, uuid)| {
+            let dependencies = description.dependencies(&uuids_by_name)?;
+            let mut task = description.into_task(uuid, environment)?;
+            task.dependencies = dependencies;
+
+            Ok(task)
+        }).collect::<Result<_, Error>>()?;
+
+        Ok(Job {
+            uuid: Uuid::new_v4(),
+            tasks,
+        })
+    }
+
+    /// Checks that every dependency is a task in the job, and that no task
+    /// depends on itself, even through other tasks.
+    pub fn validate(&self) -> Result<(), Error> {
+        let tasks: HashMap<_, _> = self.tasks.iter().map(|task| (task.uuid, task)).collect();
+
+        for task in self.tasks.iter() {
+            for dependency in task.dependencies.iter() {
+                if !tasks.contains_key(dependency) {
+                    return Err(Error::InvalidJob {
+                        reason: format!("task {} depends on {}, which is not in the job", task, dependency),
+                    });
+                }
+            }
+        }
+
+        let mut visits = HashMap::new();
+        for task in self.tasks.iter() {
+            if let Some(cycle) = find_cycle(task, &tasks, &mut visits) {
+                return Err(Error::InvalidJob {
+                    reason: format!("task {} depends on itself", cycle),
+                });
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Task
+{
+    /// Creates a nameless task.
+    pub fn new(command: Command) -> Self {
+        Task {
+            uuid: Uuid::new_v4(),
+            name: None,
+            command,
+            dependencies: Vec::new(),
+        }
+    }
 }
 
+impl TaskDescription
+{
+    /// Checks the description, and builds the task it describes, without
+    /// any dependencies.
+    pub fn into_task(self, uuid: Uuid, environment: &BTreeMap<String, String>) -> Result<Task, Error> {
+        let mut arguments = self.command;
+        if arguments.is_empty() || arguments[0].is_empty() {
+            return Err(invalid("`command` must not be empty"));
+        }
+
+        if let Some(ref working_dir) = self.working_dir {
+            if working_dir.is_absolute() {
+                return Err(invalid("`workdir` must be relative to the directory of the task"));
+            }
+        }
+
+        let executable = arguments.remove(0);
+        let mut command = Command::new(executable, arguments);
+
+        command.environment = environment.clone();
+        command.environment.extend(self.environment);
+        command.working_dir = self.working_dir;
+        command.timeout = self.timeout;
+
+        Ok(Task {
+            uuid,
+            name: self.name,
+            command,
+            dependencies: Vec::new(),
+        })
+    }
+
+    /// Finds the UUIDs of the tasks this one depends on, given the UUIDs of
+    /// the tasks in its job by name.
+    pub fn dependencies(&self, uuids_by_name: &BTreeMap<String, Uuid>) -> Result<Vec<Uuid>, Error> {
+        self.depends_on.iter().map(|name| match uuids_by_name.get(name) {
+            Some(&uuid) => Ok(uuid),
+            None => Err(invalid(&match self.name {
+                Some(ref task_name) => format!("task `{}` depends on unknown task `{}`", task_name, name),
+                None => format!("a task depends on unknown task `{}`", name),
+            })),
+        }).collect()
+    }
+}
+
+impl Command
+{
+    /// Creates a command that runs in the default environment, with no timeout.
+    pub fn new<S>(executable: S, arguments: Vec<String>) -> Self
+        where S: Into<String> {
+        Command {
+            executable: executable.into(),
+            arguments,
+            environment: BTreeMap::new(),
+            working_dir: None,
+            timeout: None,
+        }
+    }
+}
+
+impl fmt::Display for Task
+{
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self.name {
+            Some(ref name) => write!(fmt, "`{}`", name),
+            None => write!(fmt, "{}", self.uuid),
+        }
+    }
+}
+
+fn invalid(reason: &str) -> Error {
+    Error::InvalidJob { reason: reason.to_owned() }
+}
+
+/// Searches the dependencies of a task for a cycle, returning a task in it.
+fn find_cycle<'a>(task: &'a Task,
+                  tasks: &HashMap<Uuid, &'a Task>,
+                  visits: &mut HashMap<Uuid, Visit>) -> Option<&'a Task> {
+    match visits.get(&task.uuid) {
+        Some(&Visit::InProgress) => return Some(task),
+        Some(&Visit::Done) => return None,
+        None => (),
+    }
+
+    visits.insert(task.uuid, Visit::InProgress);
+
+    for dependency in task.dependencies.iter() {
+        if let Some(cycle) = find_cycle(tasks[dependency], tasks, visits) {
+            return Some(cycle);
+        }
+    }
+
+    visits.insert(task.uuid, Visit::Done);
+    None
+}
+
+#[cfg(test)]
+mod test
+{
+    pub use super::*;
+
+    fn job(dependencies: &[&[usize]]) -> Job {
+        let mut tasks: Vec<_> = dependencies.iter().map(|_| Task::new(Command::new("true", Vec::new()))).collect();
+        let uuids: Vec<_> = tasks.iter().map(|task| task.uuid).collect();
+
+        for (task, dependencies) in tasks.iter_mut().zip(dependencies) {
+            task.dependencies = dependencies.iter().map(|&index| uuids[index]).collect();
+        }
+
+        Job { uuid: Uuid::new_v4(), tasks: tasks }
+    }
+
+    #[test]
+    fn jobs_without_cycles_are_valid() {
+        assert!(job(&[&[], &[0], &[0], &[1, 2]]).validate().is_ok());
+    }
+
+    #[test]
+    fn jobs_with_cycles_are_invalid() {
+        assert!(job(&[&[0]]).validate().is_err());
+        assert!(job(&[&[], &[2], &[3], &[1]]).validate().is_err());
+    }
+
+    #[test]
+    fn descriptions_are_checked() {
+        let describe = |command: &[&str]| TaskDescription {
+            command: command.iter().map(|word| word.to_string()).collect(),
+            ..Default::default()
+        };
+
+        assert!(Job::from_descriptions(vec![describe(&["true"])], &BTreeMap::new()).is_ok());
+        assert!(Job::from_descriptions(Vec::new(), &BTreeMap::new()).is_err());
+        assert!(Job::from_descriptions(vec![describe(&[])], &BTreeMap::new()).is_err());
+        assert!(Job::from_descriptions(vec![describe(&[""])], &BTreeMap::new()).is_err());
+        assert!(Job::from_descriptions(vec![TaskDescription { working_dir: Some(PathBuf::from("/tmp")), ..describe(&["true"]) }],
+                                       &BTreeMap::new()).is_err());
+        assert!(Job::from_descriptions(vec![TaskDescription { depends_on: vec!["b".to_owned()], ..describe(&["true"]) }],
+                                       &BTreeMap::new()).is_err());
+    }
+
+    #[test]
+    fn dependencies_must_be_in_the_job() {
+        let mut job = job(&[&[]]);
+        job.tasks[0].dependencies.push(Uuid::new_v4());
+
+        assert!(job.validate().is_err());
+    }
+}
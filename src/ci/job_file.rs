@@ -0,0 +1,519 @@
+//! Jobs described in a file.
+//!
+//! A job file is usually named `parapet.toml`, and is written in a small
+//! subset of TOML. Each `[task.NAME]` table is a task.
+//!
+//! ```text
+//! # Variables set for every task.
+//! [env]
+//! RUST_BACKTRACE = "1"
+//!
+//! [task.build]
+//! command = ["cargo", "build", "--release"]
+//! workdir = "parapet"
+//! # In seconds.
+//! timeout = 600
+//!
+//! [task.build.env]
+//! CARGO_INCREMENTAL = "0"
+//!
+//! [task.test]
+//! command = "cargo test"
+//! depends_on = ["build"]
+//! ```
+//!
+//! A command written as a string is split on whitespace. Commands with
+//! arguments containing whitespace must be written as arrays.
+//!
+//! Tasks are handed out in the order they are written, but a task only
+//! runs once the tasks it depends on have succeeded.
+
+use Error;
+use ci::{Job, TaskDescription};
+
+use uuid::Uuid;
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// The name job files are given, unless they are named explicitly.
+pub const FILE_NAME: &str = "parapet.toml";
+
+/// A value on the right hand side of a `key = value` line.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Value
+{
+    String(String),
+    Integer(i64),
+    Array(Vec<Value>),
+}
+
+/// The table that `key = value` lines are currently being added to.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Table
+{
+    /// Lines before the first table header.
+    Top,
+    /// Variables for every task.
+    Env,
+    /// The index of a task.
+    Task(usize),
+    /// Variables for the task at an index.
+    TaskEnv(usize),
+}
+
+/// A task that is still being read.
+struct Definition
+{
+    uuid: Uuid,
+    name: String,
+    /// The line of the table header, for errors about the whole task.
+    line: usize,
+    command: Option<Vec<String>>,
+    environment: BTreeMap<String, String>,
+    working_dir: Option<PathBuf>,
+    timeout: Option<Duration>,
+    /// The names of the tasks this one depends on, and the line they were given on.
+    depends_on: Option<(Vec<String>, usize)>,
+}
+
+/// Loads a job from a file.
+pub fn load<P>(path: P) -> Result<Job, Error>
+    where P: AsRef<Path> {
+    let mut text = String::new();
+    fs::File::open(path)?.read_to_string(&mut text)?;
+
+    parse(&text)
+}
+
+/// Parses the contents of a job file.
+pub fn parse(text: &str) -> Result<Job, Error> {
+    let mut environment = BTreeMap::new();
+    let mut definitions: Vec<Definition> = Vec::new();
+    let mut table = Table::Top;
+
+    for (index, line) in text.lines().enumerate() {
+        let line_number = index + 1;
+        let mut parser = Parser { text: line, line: line_number };
+        parser.skip_whitespace();
+
+        if parser.is_done() { continue }
+
+        if parser.eat('[') {
+            table = parse_header(&mut parser, &mut definitions)?;
+            continue;
+        }
+
+        let key = parser.key()?;
+        parser.skip_whitespace();
+        if !parser.eat('=') {
+            return Err(invalid(line_number, "expected `key = value`"));
+        }
+
+        let value = parser.value()?;
+        parser.end()?;
+
+        match table {
+            Table::Top => return Err(invalid(line_number, "expected a `[task.NAME]` table before any keys")),
+            Table::Env => set_variable(&mut environment, key, value, line_number)?,
+            Table::TaskEnv(index) => set_variable(&mut definitions[index].environment, key, value, line_number)?,
+            Table::Task(index) => {
+                let definition = &mut definitions[index];
+
+                match key.as_str() {
+                    "command" => {
+                        if definition.command.is_some() { return Err(duplicate_key(line_number, &key)) }
+                        definition.command = Some(parse_command(value, line_number)?);
+                    },
+                    "workdir" => {
+                        if definition.working_dir.is_some() { return Err(duplicate_key(line_number, &key)) }
+
+                        definition.working_dir = Some(PathBuf::from(expect_string(value, line_number, "`workdir` must be a string")?));
+                    },
+                    "depends_on" => {
+                        if definition.depends_on.is_some() { return Err(duplicate_key(line_number, &key)) }
+
+                        let names = match value {
+                            Value::String(name) => vec![name],
+                            Value::Array(values) => {
+                                values.into_iter()
+                                    .map(|value| expect_string(value, line_number, "`depends_on` must only contain task names"))
+                                    .collect::<Result<_, _>>()?
+                            },
+                            Value::Integer(..) => return Err(invalid(line_number, "`depends_on` must be a task name or an array of them")),
+                        };
+
+                        definition.depends_on = Some((names, line_number));
+                    },
+                    "timeout" => {
+                        if definition.timeout.is_some() { return Err(duplicate_key(line_number, &key)) }
+
+                        definition.timeout = match value {
+                            Value::Integer(seconds) if seconds > 0 => Some(Duration::from_secs(seconds as u64)),
+                            _ => return Err(invalid(line_number, "`timeout` must be a positive number of seconds")),
+                        };
+                    },
+                    _ => return Err(invalid(line_number, &format!("unknown key `{}`", key))),
+                }
+            },
+        }
+    }
+
+    if definitions.is_empty() {
+        return Err(invalid(0, "the job has no tasks"));
+    }
+
+    let uuids: BTreeMap<_, _> = definitions.iter().map(|definition| (definition.name.clone(), definition.uuid)).collect();
+
+    let tasks = definitions.into_iter().map(|definition| {
+        let line = definition.line;
+        let (depends_on, depends_on_line) = definition.depends_on.unwrap_or((Vec::new(), line));
+        let description = TaskDescription {
+            name: Some(definition.name.clone()),
+            command: match definition.command {
+                Some(command) => command,
+                None => return Err(invalid(definition.line, &format!("task `{}` has no `command`", definition.name))),
+            },
+            environment: definition.environment,
+            working_dir: definition.working_dir,
+            timeout: definition.timeout,
+            depends_on,
+        };
+
+        let dependencies = description.dependencies(&uuids).map_err(|e| at_line(e, depends_on_line))?;
+        let mut task = description.into_task(definition.uuid, &environment).map_err(|e| at_line(e, line))?;
+        task.dependencies = dependencies;
+
+        Ok(task)
+    }).collect::<Result<_, _>>()?;
+
+    let job = Job {
+        uuid: Uuid::new_v4(),
+        tasks,
+    };
+
+    job.validate().map_err(|e| at_line(e, 0))?;
+    Ok(job)
+}
+
+/// Parses a table header, after its opening bracket.
+fn parse_header(parser: &mut Parser, definitions: &mut Vec<Definition>) -> Result<Table, Error> {
+    let line = parser.line;
+
+    let mut keys = Vec::new();
+    loop {
+        parser.skip_whitespace();
+        keys.push(parser.key()?);
+        parser.skip_whitespace();
+
+        if parser.eat(']') { break }
+        if !parser.eat('.') { return Err(invalid(line, "expected `.` or `]` in table header")) }
+    }
+    parser.end()?;
+
+    let keys: Vec<_> = keys.iter().map(|key| key.as_str()).collect();
+    let find = |definitions: &Vec<Definition>, name: &str| definitions.iter().position(|definition| definition.name == name);
+
+    match keys[..] {
+        ["env"] => Ok(Table::Env),
+        ["task", name] => {
+            if find(definitions, name).is_some() {
+                return Err(invalid(line, &format!("task `{}` is defined more than once", name)));
+            }
+
+            definitions.push(Definition {
+                uuid: Uuid::new_v4(),
+                name: name.to_owned(),
+                line,
+                command: None,
+                environment: BTreeMap::new(),
+                working_dir: None,
+                timeout: None,
+                depends_on: None,
+            });
+
+            Ok(Table::Task(definitions.len() - 1))
+        },
+        ["task", name, "env"] => match find(definitions, name) {
+            Some(index) => Ok(Table::TaskEnv(index)),
+            None => Err(invalid(line, &format!("`[task.{}]` must come before its `env` table", name))),
+        },
+        _ => Err(invalid(line, &format!("unknown table `[{}]`", keys.join(".")))),
+    }
+}
+
+fn parse_command(value: Value, line: usize) -> Result<Vec<String>, Error> {
+    let words: Vec<String> = match value {
+        Value::String(command) => command.split_whitespace().map(|word| word.to_owned()).collect(),
+        Value::Array(values) => {
+            values.into_iter()
+                .map(|value| expect_string(value, line, "`command` must only contain strings"))
+                .collect::<Result<_, _>>()?
+        },
+        Value::Integer(..) => return Err(invalid(line, "`command` must be a string or an array of strings")),
+    };
+
+    Ok(words)
+}
+
+fn set_variable(environment: &mut BTreeMap<String, String>,
+                name: String,
+                value: Value,
+                line: usize) -> Result<(), Error> {
+    let value = match value {
+        Value::String(value) => value,
+        Value::Integer(value) => value.to_string(),
+        Value::Array(..) => return Err(invalid(line, &format!("variable `{}` must be a string", name))),
+    };
+
+    if environment.contains_key(&name) { return Err(duplicate_key(line, &name)) }
+
+    environment.insert(name, value);
+    Ok(())
+}
+
+fn expect_string(value: Value, line: usize, reason: &str) -> Result<String, Error> {
+    match value {
+        Value::String(value) => Ok(value),
+        _ => Err(invalid(line, reason)),
+    }
+}
+
+/// Reads the pieces of a single line.
+struct Parser<'a>
+{
+    /// The rest of the line.
+    text: &'a str,
+    line: usize,
+}
+
+impl<'a> Parser<'a>
+{
+    fn peek(&self) -> Option<char> { self.text.chars().next() }
+
+    fn advance(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.text = &self.text[c.len_utf8()..];
+        Some(c)
+    }
+
+    fn eat(&mut self, expected: char) -> bool {
+        if self.peek() == Some(expected) {
+            self.advance();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        self.text = self.text.trim_start();
+    }
+
+    /// Checks if only whitespace and comments are left.
+    fn is_done(&self) -> bool {
+        let rest = self.text.trim_start();
+        rest.is_empty() || rest.starts_with('#')
+    }
+
+    /// Checks that nothing but a comment is left.
+    fn end(&mut self) -> Result<(), Error> {
+        if self.is_done() { Ok(()) } else { Err(invalid(self.line, "unexpected text at the end of the line")) }
+    }
+
+    /// Reads a bare or quoted key.
+    fn key(&mut self) -> Result<String, Error> {
+        match self.peek() {
+            Some('"') | Some('\'') => self.string(),
+            _ => {
+                let length = self.text.find(|c: char| !(c.is_alphanumeric() || c == '_' || c == '-')).unwrap_or(self.text.len());
+                if length == 0 { return Err(invalid(self.line, "expected a key")) }
+
+                let key = self.text[..length].to_owned();
+                self.text = &self.text[length..];
+                Ok(key)
+            },
+        }
+    }
+
+    fn value(&mut self) -> Result<Value, Error> {
+        self.skip_whitespace();
+
+        match self.peek() {
+            Some('"') | Some('\'') => Ok(Value::String(self.string()?)),
+            Some('[') => {
+                self.advance();
+                let mut values = Vec::new();
+
+                loop {
+                    self.skip_whitespace();
+                    if self.eat(']') { break }
+
+                    values.push(self.value()?);
+
+                    self.skip_whitespace();
+                    if self.eat(']') { break }
+                    if !self.eat(',') { return Err(invalid(self.line, "expected `,` or `]` in array")) }
+                }
+
+                Ok(Value::Array(values))
+            },
+            Some(c) if c.is_ascii_digit() || c == '-' || c == '+' => {
+                let length = self.text.find(|c: char| !(c.is_ascii_digit() || c == '-' || c == '+' || c == '_')).unwrap_or(self.text.len());
+                let number: String = self.text[..length].chars().filter(|&c| c != '_').collect();
+                self.text = &self.text[length..];
+
+                number.parse().map(Value::Integer).map_err(|_| invalid(self.line, "invalid integer"))
+            },
+            Some(..) => Err(invalid(self.line, "expected a string, an integer or an array")),
+            None => Err(invalid(self.line, "expected a value")),
+        }
+    }
+
+    /// Reads a string in double quotes, with escapes, or in single quotes, without.
+    fn string(&mut self) -> Result<String, Error> {
+        let quote = self.advance().unwrap();
+        let mut string = String::new();
+
+        loop {
+            match self.advance() {
+                Some(c) if c == quote => return Ok(string),
+                Some('\\') if quote == '"' => {
+                    string.push(match self.advance() {
+                        Some('n') => '\n',
+                        Some('t') => '\t',
+                        Some('r') => '\r',
+                        Some('\\') => '\\',
+                        Some('"') => '"',
+                        _ => return Err(invalid(self.line, "invalid escape in string")),
+                    });
+                },
+                Some(c) => string.push(c),
+                None => return Err(invalid(self.line, "unterminated string")),
+            }
+        }
+    }
+}
+
+fn duplicate_key(line: usize, key: &str) -> Error {
+    invalid(line, &format!("`{}` is set more than once", key))
+}
+
+fn invalid(line: usize, reason: &str) -> Error {
+    Error::InvalidJobFile { line, reason: reason.to_owned() }
+}
+
+/// Points a problem with a job at a line of its file.
+fn at_line(error: Error, line: usize) -> Error {
+    match error {
+        Error::InvalidJob { reason } => invalid(line, &reason),
+        e => e,
+    }
+}
+
+#[cfg(test)]
+mod test
+{
+    pub use super::*;
+
+    fn error_line(text: &str) -> usize {
+        match parse(text) {
+            Err(Error::InvalidJobFile { line, .. }) => line,
+            result => panic!("expected the job file to be invalid, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn tasks_are_read_in_order() {
+        let job = parse(r#"
+            # Variables for every task.
+            [env]
+            RUST_BACKTRACE = "1"
+            LEVEL = "job"
+
+            [task.build]
+            command = ["cargo", "build", "--message-format", "short"] # trailing comment
+            workdir = "parapet"
+            timeout = 600
+
+            [task.build.env]
+            LEVEL = "task"
+
+            [task.test]
+            command = "cargo  test --all"
+        "#).unwrap();
+
+        assert_eq!(job.tasks.len(), 2);
+
+        let build = &job.tasks[0];
+        assert_eq!(build.name, Some("build".to_owned()));
+        assert_eq!(build.command.executable, "cargo");
+        assert_eq!(build.command.arguments, vec!["build", "--message-format", "short"]);
+        assert_eq!(build.command.working_dir, Some(PathBuf::from("parapet")));
+        assert_eq!(build.command.timeout, Some(Duration::from_secs(600)));
+        assert_eq!(build.command.environment["RUST_BACKTRACE"], "1");
+        assert_eq!(build.command.environment["LEVEL"], "task");
+
+        let test = &job.tasks[1];
+        assert_eq!(test.name, Some("test".to_owned()));
+        assert_eq!(test.command.arguments, vec!["test", "--all"]);
+        assert_eq!(test.command.environment["LEVEL"], "job");
+        assert_eq!(test.command.working_dir, None);
+        assert_eq!(test.command.timeout, None);
+    }
+
+    #[test]
+    fn strings_can_contain_escapes_and_comment_characters() {
+        let job = parse(r#"
+            [task.quote]
+            command = ["echo", "say \"hi\" # not a comment", 'C:\raw']
+        "#).unwrap();
+
+        assert_eq!(job.tasks[0].command.arguments, vec!["say \"hi\" # not a comment", "C:\\raw"]);
+    }
+
+    #[test]
+    fn errors_point_at_the_offending_line() {
+        assert_eq!(error_line("[task.a]\ncommand = \"true\"\nbogus = 1\n"), 3);
+        assert_eq!(error_line("[task.a]\ncommand = \"true\"\n\n[task.a]\ncommand = \"false\"\n"), 4);
+        assert_eq!(error_line("[task.a]\ncommand = [\"true\"\n"), 2);
+        assert_eq!(error_line("[task.a]\ncommand = \"true\"\ntimeout = \"soon\"\n"), 3);
+        assert_eq!(error_line("[task.a]\ncommand = \"true\"\n[task.b.env]\n"), 3);
+        assert_eq!(error_line("command = \"true\"\n"), 1);
+        assert_eq!(error_line("# nothing\n\n[task.a]\nworkdir = \"x\"\n"), 3);
+        assert_eq!(error_line("[task.a]\ncommand = [\"\"]\n"), 1);
+    }
+
+    #[test]
+    fn tasks_can_depend_on_each_other() {
+        let job = parse(r#"
+            [task.test]
+            command = "cargo test"
+            depends_on = ["build", "lint"]
+
+            [task.build]
+            command = "cargo build"
+
+            [task.lint]
+            command = "cargo clippy"
+            depends_on = "build"
+        "#).unwrap();
+
+        assert_eq!(job.tasks[0].dependencies, vec![job.tasks[1].uuid, job.tasks[2].uuid]);
+        assert_eq!(job.tasks[2].dependencies, vec![job.tasks[1].uuid]);
+    }
+
+    #[test]
+    fn dependencies_must_exist_and_not_form_cycles() {
+        assert_eq!(error_line("[task.a]\ncommand = \"true\"\ndepends_on = [\"b\"]\n"), 3);
+        assert_eq!(error_line("[task.a]\ncommand = \"true\"\ndepends_on = \"b\"\n[task.b]\ncommand = \"true\"\ndepends_on = \"a\"\n"), 0);
+    }
+
+    #[test]
+    fn jobs_must_have_tasks() {
+        assert_eq!(error_line("# nothing to do\n"), 0);
+    }
+}
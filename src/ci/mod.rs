@@ -14,6 +14,7 @@ pub mod cache;
 pub mod builder;
 pub mod dispatcher;
 pub mod job;
+pub mod job_file;
 
 pub mod build;
 
@@ -10,7 +10,7 @@ pub struct Project
     /// The file cache.
     pub cache: ci::Cache,
     /// The sandboxing implementation.
-    pub sandbox: Box<ci::Sandbox>,
+    pub sandbox: Box<dyn ci::Sandbox>,
 }
 
 impl Project
@@ -1,33 +1,177 @@
 use ci::{build, Command, Sandbox};
 
+use libc;
+
+use std::io::Read;
+use std::os::unix::process::{CommandExt, ExitStatusExt};
 use std::path::Path;
-use std::{process, fs};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use std::{process, fs, thread};
+
+/// The result code given to commands that are killed for taking too long.
+///
+/// This is the same code that `timeout(1)` uses.
+pub const TIMED_OUT_RESULT_CODE: i64 = 124;
+
+/// The result code given to commands that couldn't be started.
+///
+/// This is the same code that shells use for commands that aren't found.
+pub const SPAWN_FAILED_RESULT_CODE: i64 = 127;
+
+/// How often to check whether a command with a timeout has finished.
+const POLL_INTERVAL_MS: u64 = 10;
 
 pub struct Basic;
 
 impl Sandbox for Basic
 {
     fn run(&mut self, command: Command, working_dir: &Path) -> build::TaskOutput {
+        let working_dir = match command.working_dir {
+            Some(ref dir) => working_dir.join(dir),
+            None => working_dir.to_owned(),
+        };
+
         if !working_dir.exists() {
             fs::create_dir_all(&working_dir).expect("could not create ci directory");
         }
 
-        let output = process::Command::new(&command.executable)
+        let spawned = process::Command::new(&command.executable)
             .args(&command.arguments)
+            .envs(&command.environment)
             .current_dir(working_dir)
-            .output()
-            .expect("could not spawn command");
+            .stdout(process::Stdio::piped())
+            // Anything the command starts goes in the same group, so it can all be killed together.
+            .process_group(0)
+            .spawn();
+
+        let mut child = match spawned {
+            Ok(child) => child,
+            Err(e) => return build::TaskOutput {
+                output: format!("could not run {}: {}\n", command.executable, e).into_bytes(),
+                result_code: SPAWN_FAILED_RESULT_CODE,
+            },
+        };
+
+        // Read the output as it comes so that the command never blocks
+        // on a full pipe while we wait for it.
+        let output = Arc::new(Mutex::new(Vec::new()));
+        let mut stdout = child.stdout.take().unwrap();
+        let reader = {
+            let output = output.clone();
+
+            thread::spawn(move || {
+                let mut buffer = [0; 4096];
+
+                loop {
+                    match stdout.read(&mut buffer) {
+                        Ok(0) | Err(..) => break,
+                        Ok(count) => output.lock().unwrap().extend_from_slice(&buffer[..count]),
+                    }
+                }
+            })
+        };
+
+        let result_code = match command.timeout {
+            Some(timeout) => wait_with_timeout(&mut child, timeout),
+            None => Some(result_code(child.wait().expect("could not wait for command"))),
+        };
+
+        let result_code = match result_code {
+            Some(result_code) => {
+                reader.join().unwrap();
+                result_code
+            },
+            // Something may have escaped the process group and still be
+            // holding the pipe open, so keep whatever output we have.
+            None => TIMED_OUT_RESULT_CODE,
+        };
 
         let output = build::TaskOutput {
             // FIXME: grab stderr
-            output: output.stdout,
-            result_code: match output.status.code() {
-                Some(code) => code as _,
-                None => 0,
-            },
+            output: output.lock().unwrap().clone(),
+            result_code,
         };
 
         output
     }
 }
 
+/// Waits for a command to finish, killing it and everything it started if
+/// it runs for too long.
+///
+/// Returns `None` if the command was killed.
+fn wait_with_timeout(child: &mut process::Child, timeout: Duration) -> Option<i64> {
+    let deadline = Instant::now() + timeout;
+
+    loop {
+        if let Some(status) = child.try_wait().expect("could not wait for command") {
+            return Some(result_code(status));
+        }
+
+        if Instant::now() >= deadline {
+            // The command may have finished since we checked, but the rest
+            // of its group may not have.
+            unsafe { libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL) };
+            child.wait().ok();
+
+            return None;
+        }
+
+        thread::sleep(Duration::from_millis(POLL_INTERVAL_MS));
+    }
+}
+
+/// Gets the result code of a finished command.
+///
+/// Commands killed by a signal are given `128 + signal`, as shells do.
+fn result_code(status: process::ExitStatus) -> i64 {
+    match status.code() {
+        Some(code) => code as _,
+        None => 128 + status.signal().unwrap_or(0) as i64,
+    }
+}
+
+#[cfg(test)]
+mod test
+{
+    pub use super::*;
+    pub use std::collections::BTreeMap;
+    pub use std::env;
+
+    fn command(executable: &str, arguments: &[&str], timeout: Option<Duration>) -> Command {
+        Command {
+            executable: executable.to_owned(),
+            arguments: arguments.iter().map(|argument| argument.to_string()).collect(),
+            environment: BTreeMap::new(),
+            working_dir: None,
+            timeout: timeout,
+        }
+    }
+
+    #[test]
+    fn commands_that_cannot_be_started_fail() {
+        let output = Basic.run(command("/nonexistent/parapet-test", &[], None), &env::temp_dir());
+
+        assert_eq!(output.result_code, SPAWN_FAILED_RESULT_CODE);
+        assert!(String::from_utf8(output.output).unwrap().contains("/nonexistent/parapet-test"));
+    }
+
+    #[test]
+    fn commands_killed_by_a_signal_fail() {
+        let output = Basic.run(command("sh", &["-c", "kill -SEGV $$"], None), &env::temp_dir());
+
+        assert_eq!(output.result_code, 128 + libc::SIGSEGV as i64);
+    }
+
+    #[test]
+    fn timeouts_kill_everything_the_command_started() {
+        let started = Instant::now();
+        let output = Basic.run(command("sh", &["-c", "echo started; sleep 30 & sleep 30"], Some(Duration::from_millis(200))),
+                               &env::temp_dir());
+
+        assert_eq!(output.result_code, TIMED_OUT_RESULT_CODE);
+        assert_eq!(output.output, b"started\n");
+        assert!(started.elapsed() < Duration::from_secs(10));
+    }
+}
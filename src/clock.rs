@@ -0,0 +1,78 @@
+//! Telling the time.
+//!
+//! Nodes read the time through a `Clock` rather than asking the system, so
+//! that simulated networks can control how fast time passes.
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+/// Where a node gets the current time from.
+#[derive(Clone, Debug)]
+pub struct Clock
+{
+    /// The current time, if the clock is simulated.
+    ///
+    /// Every copy of a simulated clock shares the same time.
+    simulated: Option<Arc<Mutex<SystemTime>>>,
+}
+
+impl Clock
+{
+    /// A clock that follows the system clock.
+    pub fn system() -> Self {
+        Clock { simulated: None }
+    }
+
+    /// A clock that starts at some time, and only moves when it is advanced.
+    pub fn simulated(start: SystemTime) -> Self {
+        Clock { simulated: Some(Arc::new(Mutex::new(start))) }
+    }
+
+    pub fn now(&self) -> SystemTime {
+        match self.simulated {
+            Some(ref now) => *now.lock().unwrap(),
+            None => SystemTime::now(),
+        }
+    }
+
+    /// Moves a simulated clock forward.
+    ///
+    /// Panics if the clock follows the system clock.
+    pub fn advance(&self, duration: Duration) {
+        let now = self.simulated.as_ref().expect("only simulated clocks can be advanced");
+        *now.lock().unwrap() += duration;
+    }
+
+    pub fn is_simulated(&self) -> bool { self.simulated.is_some() }
+}
+
+impl Default for Clock
+{
+    fn default() -> Self { Clock::system() }
+}
+
+#[cfg(test)]
+mod test
+{
+    pub use super::*;
+    pub use std::time::UNIX_EPOCH;
+
+    #[test]
+    fn simulated_clocks_only_move_when_advanced() {
+        let start = UNIX_EPOCH + Duration::from_secs(1_500_000_000);
+        let clock = Clock::simulated(start);
+
+        assert_eq!(clock.now(), start);
+        clock.advance(Duration::from_millis(250));
+        assert_eq!(clock.now(), start + Duration::from_millis(250));
+    }
+
+    #[test]
+    fn copies_of_a_simulated_clock_share_the_time() {
+        let clock = Clock::simulated(UNIX_EPOCH);
+        let copy = clock.clone();
+
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(copy.now(), UNIX_EPOCH + Duration::from_secs(5));
+    }
+}
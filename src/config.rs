@@ -0,0 +1,134 @@
+use {Identity, Clock, Random};
+use network::Address;
+
+use std::collections::BTreeMap;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// How often to ping directly connected nodes.
+const DEFAULT_HEARTBEAT_INTERVAL_MS: u64 = 1000;
+/// How long a directly connected node may be silent before it is evicted.
+const DEFAULT_HEARTBEAT_TIMEOUT_MS: u64 = 10_000;
+/// How long to wait before trying to rejoin the network the second time.
+const DEFAULT_RECONNECT_DELAY_MS: u64 = 500;
+/// The longest to wait between attempts to rejoin the network.
+const DEFAULT_MAX_RECONNECT_DELAY_MS: u64 = 30_000;
+/// How many times to try to rejoin the network before carrying on alone.
+const DEFAULT_MAX_REJOIN_ATTEMPTS: usize = 20;
+/// How long to wait for a packet to be acknowledged before sending it again.
+const DEFAULT_RETRANSMIT_INTERVAL_MS: u64 = 1000;
+/// How many nodes to open direct connections to.
+const DEFAULT_FAN_OUT: usize = 4;
+/// The multicast group nodes find each other on.
+const DEFAULT_DISCOVERY_GROUP: (Ipv4Addr, u16) = (Ipv4Addr::new(239, 255, 53, 71), 53372);
+/// How long to look for a network on the local network segment before creating one.
+const DEFAULT_DISCOVERY_TIMEOUT_MS: u64 = 2000;
+
+/// Configuration for a local node.
+#[derive(Clone, Debug)]
+pub struct Config
+{
+    /// How often we send heartbeats to the nodes we are directly connected to.
+    pub heartbeat_interval: Duration,
+    /// How long a directly connected node can go without being heard
+    /// from before we consider it dead and evict it.
+    pub heartbeat_timeout: Duration,
+    /// A pre-shared key that nodes must know in order to join the network.
+    pub network_key: Option<String>,
+    /// Who we are.
+    pub identity: Identity,
+    /// Free-form information about us, such as our hostname.
+    pub labels: BTreeMap<String, String>,
+    /// The address to listen for other nodes on.
+    pub bind_address: IpAddr,
+    /// The port to listen for other nodes on.
+    ///
+    /// If this isn't set, the default port is used if it is free,
+    /// otherwise a random port is used.
+    pub port: Option<u16>,
+    /// The address other nodes should use to connect to us, if it
+    /// isn't the address we are listening on.
+    pub advertised_address: Option<SocketAddr>,
+    /// How long to wait for work sent to another node to be acknowledged
+    /// before sending it again.
+    ///
+    /// The wait doubles after every attempt.
+    pub retransmit_interval: Duration,
+    /// The path of a Unix domain socket to listen on, for nodes and
+    /// clients on the same host.
+    pub unix_socket: Option<PathBuf>,
+    /// How many nodes we try to be directly connected to.
+    ///
+    /// We open direct connections to nodes we learn the address of until
+    /// we have this many, so that losing one node doesn't split the network.
+    pub fan_out: usize,
+    /// How long to wait after a failed attempt to rejoin the network.
+    ///
+    /// The delay doubles after every failed attempt.
+    pub reconnect_delay: Duration,
+    /// The longest to wait between attempts to rejoin the network.
+    pub max_reconnect_delay: Duration,
+    /// How many times to try to rejoin the network before giving up.
+    ///
+    /// A node that gives up carries on as a network of its own, which
+    /// other nodes can still join.
+    pub max_rejoin_attempts: usize,
+    /// The multicast group to find other nodes on the local network segment with.
+    ///
+    /// Discovery is disabled if this isn't set.
+    pub discovery_group: Option<SocketAddr>,
+    /// How long to look for a network to join before creating a new one.
+    pub discovery_timeout: Duration,
+    /// Where we get the time from.
+    pub clock: Clock,
+    /// Where we get random numbers from, other than for secrets.
+    pub random: Random,
+    /// Whether we ask other nodes for work to run.
+    ///
+    /// Nodes that only submit jobs don't run work for anybody else.
+    pub accepts_work: bool,
+    /// Where programs on the same host can connect to control us.
+    ///
+    /// The control API is disabled if this isn't set.
+    pub control_address: Option<Address>,
+    /// The directory we keep our identity and other state in.
+    pub state_dir: Option<PathBuf>,
+}
+
+impl Config
+{
+    /// The address to listen for other nodes on.
+    pub fn listen_address(&self) -> SocketAddr {
+        SocketAddr::new(self.bind_address, self.port.unwrap_or(::SERVER_PORT))
+    }
+}
+
+impl Default for Config
+{
+    fn default() -> Self {
+        Config {
+            heartbeat_interval: Duration::from_millis(DEFAULT_HEARTBEAT_INTERVAL_MS),
+            heartbeat_timeout: Duration::from_millis(DEFAULT_HEARTBEAT_TIMEOUT_MS),
+            network_key: None,
+            identity: Identity::generate(),
+            labels: BTreeMap::new(),
+            bind_address: IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+            port: None,
+            advertised_address: None,
+            retransmit_interval: Duration::from_millis(DEFAULT_RETRANSMIT_INTERVAL_MS),
+            unix_socket: None,
+            fan_out: DEFAULT_FAN_OUT,
+            reconnect_delay: Duration::from_millis(DEFAULT_RECONNECT_DELAY_MS),
+            max_reconnect_delay: Duration::from_millis(DEFAULT_MAX_RECONNECT_DELAY_MS),
+            max_rejoin_attempts: DEFAULT_MAX_REJOIN_ATTEMPTS,
+            discovery_group: Some(SocketAddr::new(IpAddr::V4(DEFAULT_DISCOVERY_GROUP.0), DEFAULT_DISCOVERY_GROUP.1)),
+            discovery_timeout: Duration::from_millis(DEFAULT_DISCOVERY_TIMEOUT_MS),
+            clock: Clock::system(),
+            random: Random::system(),
+            accepts_work: true,
+            control_address: None,
+            state_dir: None,
+        }
+    }
+}
@@ -0,0 +1,1039 @@
+//! Controlling a running node from other programs.
+//!
+//! Clients connect to the control address, which is either a Unix domain
+//! socket or a TCP port on a loopback address, and send JSON-RPC 2.0
+//! requests, one per line. Every request with an `id` is answered on a
+//! line of its own.
+//!
+//! ```text
+//! --> {"jsonrpc": "2.0", "id": 1, "method": "submit", "params": {"tasks": [{"name": "test", "command": "cargo test"}]}}
+//! <-- {"id":1,"jsonrpc":"2.0","result":{"job":"5b0c3a9e-8b8e-4e4b-9a43-0c7f6b0e2d7f"}}
+//! ```
+//!
+//! The methods are:
+//!
+//! * `submit` - runs the tasks in `params.tasks`, or the job file at `params.file`
+//! * `list_jobs` - summarises every job we have been given
+//! * `job_status` - shows how far each task of the job `params.job` has got
+//! * `cancel` - cancels the tasks of the job `params.job` that haven't finished
+//! * `list_nodes` - lists the nodes in the network
+//! * `node_stats` - counts our jobs, work and dropped packets
+//! * `authenticate` - proves that a TCP client knows the token in `params.token`
+//!
+//! Tasks in `params.tasks` take the same keys as tasks in a job file, and
+//! `params.env` sets variables for every task.
+//!
+//! Any local process can connect to a TCP port, including a browser that a
+//! web page has told to send a request, so TCP clients must first call
+//! `authenticate` with the token from the state directory. Clients that
+//! send anything that isn't a JSON-RPC request are hung up on.
+//!
+//! A `Session` is a client for programs written against this crate.
+
+use {Config, Error};
+use ci;
+use ci::dispatcher::{JobStatus, JobState, TaskState};
+use network::{self, local, Address, Transport};
+
+use mio::net::TcpListener;
+use serde_json::{self, Value};
+use uuid::Uuid;
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// The longest request we accept, so that a client can't make us buffer forever.
+const MAX_REQUEST_SIZE: usize = 1024 * 1024;
+
+/// The file in the state directory that TCP clients read the token from.
+pub const TOKEN_FILE_NAME: &str = "control-token";
+
+/// How long a session waits for a node to answer a request.
+const SESSION_TIMEOUT_SECS: u64 = 30;
+
+/// The keys a task can be given.
+const TASK_KEYS: &[&str] = &["name", "command", "env", "workdir", "timeout", "depends_on"];
+
+/// The request wasn't valid JSON.
+const PARSE_ERROR: i64 = -32700;
+/// The request wasn't a JSON-RPC 2.0 request.
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+/// We haven't joined a network yet.
+const NOT_CONNECTED: i64 = -32000;
+/// We haven't been given a job with the UUID.
+const UNKNOWN_JOB: i64 = -32001;
+/// The job could never run, such as when its tasks depend on each other in a cycle.
+const INVALID_JOB: i64 = -32002;
+/// The job can't be cancelled, because it has already completed.
+const JOB_COMPLETED: i64 = -32003;
+/// The client hasn't authenticated, or gave the wrong token.
+const UNAUTHORIZED: i64 = -32004;
+
+/// Where programs on the same host can connect to control us.
+pub struct Control
+{
+    listener: Listener,
+    clients: Vec<Client>,
+    /// The token that TCP clients must authenticate with.
+    token: Option<Token>,
+}
+
+/// A secret that only processes that can read the state directory know.
+struct Token
+{
+    value: String,
+    /// Where the token is kept, so it can be cleaned up when we stop.
+    path: PathBuf,
+}
+
+enum Listener
+{
+    Tcp(TcpListener),
+    Unix(local::unix::Listener),
+}
+
+/// A program that is connected to us.
+struct Client
+{
+    stream: Box<dyn Transport>,
+    /// The part of the next request that we have received so far.
+    received: Vec<u8>,
+    /// Responses that we haven't been able to send yet.
+    unsent: Vec<u8>,
+    /// Whether the client has hung up, or we have given up on it.
+    closed: bool,
+    /// Whether the client may call methods other than `authenticate`.
+    authenticated: bool,
+}
+
+/// A connection to the control API of a node.
+pub struct Session
+{
+    reader: BufReader<Box<dyn Read>>,
+    writer: Box<dyn Write>,
+    next_id: u64,
+}
+
+/// A request that is at least shaped like a JSON-RPC 2.0 request.
+#[derive(Clone, Debug, PartialEq)]
+struct Request
+{
+    /// The ID to answer with, or `None` for notifications.
+    id: Option<Value>,
+    method: String,
+    params: Value,
+}
+
+/// Why a request couldn't be answered.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Failure
+{
+    code: i64,
+    message: String,
+}
+
+impl Control
+{
+    /// Listens for clients on an address.
+    ///
+    /// Anybody who can connect can run commands, so TCP ports must be on a
+    /// loopback address, and clients must authenticate with a token that
+    /// is written to the state directory.
+    pub fn bind(address: &Address, state_dir: Option<&Path>) -> io::Result<Self> {
+        let (listener, token) = match *address {
+            Address::Tcp(ref address) => {
+                if !address.ip().is_loopback() {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                              "the control address must be a loopback address or a Unix domain socket"));
+                }
+
+                let state_dir = state_dir.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput,
+                    "a state directory is needed to keep the token for TCP control clients in"))?;
+
+                let listener = TcpListener::bind(address)?;
+                (Listener::Tcp(listener), Some(Token::create(state_dir)?))
+            },
+            Address::Unix(ref path) => (Listener::Unix(local::unix::Listener::bind(path)?), None),
+        };
+
+        Ok(Control {
+            listener,
+            clients: Vec::new(),
+            token,
+        })
+    }
+
+    /// Listens on the control address from the configuration, if there is one.
+    ///
+    /// Nodes still work without it, so failing to listen is only a warning.
+    pub fn start(config: &Config) -> Option<Self> {
+        let address = config.control_address.as_ref()?;
+
+        match Control::bind(address, config.state_dir.as_deref()) {
+            Ok(control) => {
+                println!("listening for control clients on {}", address);
+                Some(control)
+            },
+            Err(e) => {
+                println!("warning: could not listen for control clients on {}: {}", address, e);
+                None
+            },
+        }
+    }
+
+    /// Answers the requests that clients have sent since we last looked.
+    pub fn serve(&mut self, node: &mut local::Node) {
+        self.accept_clients();
+
+        let mut node = match *node {
+            local::Node::Connected { ref mut node, .. } => Some(node),
+            _ => None,
+        };
+
+        let token = self.token.as_ref().map(|token| token.value.as_str());
+
+        for client in self.clients.iter_mut() {
+            client.receive();
+
+            while let Some(line) = client.next_request() {
+                client.handle(&line, token, node.as_deref_mut());
+            }
+
+            client.flush();
+        }
+
+        self.clients.retain(|client| !client.closed);
+    }
+
+    fn accept_clients(&mut self) {
+        loop {
+            let stream: Box<dyn Transport> = match self.listener {
+                Listener::Tcp(ref listener) => match listener.accept() {
+                    Ok((stream, _)) => Box::new(stream),
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                    Err(e) => {
+                        println!("could not accept control client: {}", e);
+                        break;
+                    },
+                },
+                Listener::Unix(ref listener) => match listener.listener.accept() {
+                    Ok(Some((stream, _))) => Box::new(stream),
+                    Ok(None) => break,
+                    Err(e) => {
+                        println!("could not accept control client: {}", e);
+                        break;
+                    },
+                },
+            };
+
+            self.clients.push(Client {
+                stream,
+                received: Vec::new(),
+                unsent: Vec::new(),
+                closed: false,
+                authenticated: self.token.is_none(),
+            });
+        }
+    }
+}
+
+impl Session
+{
+    /// Connects to the control API of a node.
+    ///
+    /// Nodes listening on TCP are authenticated with using the token in
+    /// their state directory.
+    pub fn connect(address: &Address, state_dir: Option<&Path>) -> Result<Self, Error> {
+        let timeout = Some(Duration::from_secs(SESSION_TIMEOUT_SECS));
+
+        let (reader, writer): (Box<dyn Read>, Box<dyn Write>) = match *address {
+            Address::Tcp(ref address) => {
+                let stream = TcpStream::connect(address)?;
+                stream.set_read_timeout(timeout)?;
+                (Box::new(stream.try_clone()?), Box::new(stream))
+            },
+            Address::Unix(ref path) => {
+                let stream = UnixStream::connect(path)?;
+                stream.set_read_timeout(timeout)?;
+                (Box::new(stream.try_clone()?), Box::new(stream))
+            },
+        };
+
+        let mut session = Session { reader: BufReader::new(reader), writer, next_id: 1 };
+
+        if let Address::Tcp(..) = *address {
+            let state_dir = state_dir.ok_or_else(|| Error::Control {
+                code: UNAUTHORIZED,
+                message: "a state directory is needed to read the token of a node listening on TCP".to_owned(),
+            })?;
+            let token = fs::read_to_string(state_dir.join(TOKEN_FILE_NAME))?;
+
+            session.call("authenticate", json!({ "token": token.trim() }))?;
+        }
+
+        Ok(session)
+    }
+
+    /// Makes a request, and waits for the answer.
+    pub fn call(&mut self, method: &str, params: Value) -> Result<Value, Error> {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        let request = json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params });
+        writeln!(self.writer, "{}", request)?;
+
+        let mut line = String::new();
+        if self.reader.read_line(&mut line)? == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "the node hung up").into());
+        }
+
+        let response: Value = serde_json::from_str(&line)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+
+        match (response.get("result"), response.get("error")) {
+            (Some(result), _) => Ok(result.clone()),
+            (None, Some(error)) => Err(Error::Control {
+                code: error["code"].as_i64().unwrap_or(0),
+                message: error["message"].as_str().unwrap_or("").to_owned(),
+            }),
+            (None, None) => Err(io::Error::new(io::ErrorKind::InvalidData, "the node sent an invalid response").into()),
+        }
+    }
+}
+
+impl Token
+{
+    /// Generates a new token, replacing any left behind in the state directory.
+    fn create(state_dir: &Path) -> io::Result<Self> {
+        let value: String = network::auth::challenge().iter().map(|byte| format!("{:02x}", byte)).collect();
+        let path = state_dir.join(TOKEN_FILE_NAME);
+
+        fs::create_dir_all(state_dir)?;
+
+        // Creating the file afresh means nobody else can already have it open.
+        match fs::remove_file(&path) {
+            Err(ref e) if e.kind() != io::ErrorKind::NotFound => return Err(io::Error::new(e.kind(), e.to_string())),
+            _ => (),
+        }
+
+        let mut file = fs::OpenOptions::new().write(true).create_new(true).mode(0o600).open(&path)?;
+        writeln!(file, "{}", value)?;
+
+        Ok(Token { value, path })
+    }
+
+    /// Checks a token in constant time, so it can't be guessed byte by byte.
+    fn matches(expected: &str, given: &str) -> bool {
+        expected.len() == given.len() &&
+            expected.bytes().zip(given.bytes()).fold(0, |difference, (a, b)| difference | (a ^ b)) == 0
+    }
+}
+
+impl Drop for Token
+{
+    fn drop(&mut self) {
+        // A stale token is harmless, as it is replaced before anybody can use it.
+        fs::remove_file(&self.path).ok();
+    }
+}
+
+impl Client
+{
+    /// Answers a line the client has sent.
+    ///
+    /// Clients that send something that isn't a request, or that don't
+    /// authenticate first, are hung up on.
+    fn handle(&mut self, line: &[u8], token: Option<&str>, node: Option<&mut local::connected::Node>) {
+        let request = match parse_request(line) {
+            Ok(request) => request,
+            Err((id, failure)) => {
+                // Whatever is on the other end isn't speaking JSON-RPC, so
+                // nothing else it sends will make sense either.
+                self.send(&error_response(id, failure));
+                self.hang_up();
+                return;
+            },
+        };
+
+        let response = match token {
+            Some(token) if !self.authenticated => self.authenticate(&request, token),
+            _ => respond(&request, node),
+        };
+
+        if let Some(response) = response {
+            self.send(&response);
+        }
+
+        if !self.authenticated {
+            self.hang_up();
+        }
+    }
+
+    /// Answers the first request from a client that needs to authenticate.
+    fn authenticate(&mut self, request: &Request, token: &str) -> Option<Value> {
+        let result = if request.method == "authenticate" {
+            let given = request.params.get("token").and_then(Value::as_str).unwrap_or("");
+            self.authenticated = Token::matches(token, given);
+
+            if self.authenticated { Ok(Value::Bool(true)) } else { Err(Failure::new(UNAUTHORIZED, "the token is wrong")) }
+        } else {
+            Err(Failure::new(UNAUTHORIZED, format!("authenticate with the token in {} first", TOKEN_FILE_NAME)))
+        };
+
+        response(request, result)
+    }
+
+    /// Stops listening to the client, once it has been sent what we have already said.
+    fn hang_up(&mut self) {
+        self.received.clear();
+        self.closed = true;
+    }
+
+    /// Reads everything the client has sent.
+    fn receive(&mut self) {
+        let mut buffer = [0; 4096];
+
+        while !self.closed {
+            match self.stream.read(&mut buffer) {
+                Ok(0) => self.closed = true,
+                Ok(size) => self.received.extend_from_slice(&buffer[..size]),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(..) => self.closed = true,
+            }
+        }
+
+        if self.received.len() > MAX_REQUEST_SIZE && !self.received.contains(&b'\n') {
+            let failure = Failure::new(INVALID_REQUEST, format!("requests must be smaller than {} bytes", MAX_REQUEST_SIZE));
+
+            self.send(&error_response(Value::Null, failure));
+            self.hang_up();
+        }
+    }
+
+    /// Takes the next complete line that isn't blank.
+    ///
+    /// A client that has hung up may not have ended its last request with a newline.
+    fn next_request(&mut self) -> Option<Vec<u8>> {
+        loop {
+            let line: Vec<u8> = match self.received.iter().position(|&byte| byte == b'\n') {
+                Some(index) => self.received.drain(..index + 1).collect(),
+                None if self.closed && !self.received.is_empty() => self.received.drain(..).collect(),
+                None => return None,
+            };
+
+            if !String::from_utf8_lossy(&line).trim().is_empty() {
+                return Some(line);
+            }
+        }
+    }
+
+    fn send(&mut self, response: &Value) {
+        self.unsent.extend(response.to_string().into_bytes());
+        self.unsent.push(b'\n');
+    }
+
+    /// Writes as many responses as the client will take.
+    fn flush(&mut self) {
+        while !self.unsent.is_empty() {
+            match self.stream.write(&self.unsent) {
+                Ok(0) => break,
+                Ok(size) => { self.unsent.drain(..size); },
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(..) => break,
+            }
+        }
+
+        // There's nobody to tell about responses we couldn't send.
+        if !self.unsent.is_empty() {
+            self.unsent.clear();
+            self.closed = true;
+        }
+    }
+}
+
+impl Failure
+{
+    fn new<S>(code: i64, message: S) -> Self
+        where S: Into<String> {
+        Failure { code, message: message.into() }
+    }
+}
+
+/// Parses a line that a client has sent.
+///
+/// If the line isn't a request, the error comes with the ID to answer with.
+fn parse_request(line: &[u8]) -> Result<Request, (Value, Failure)> {
+    if is_http_request_line(line) {
+        return Err((Value::Null, Failure::new(INVALID_REQUEST, "HTTP requests are not accepted")));
+    }
+
+    let request: Value = match serde_json::from_slice(line) {
+        Ok(request) => request,
+        Err(e) => return Err((Value::Null, Failure::new(PARSE_ERROR, e.to_string()))),
+    };
+
+    match (request.get("jsonrpc").and_then(Value::as_str), request.get("method").and_then(Value::as_str)) {
+        (Some("2.0"), Some(method)) => Ok(Request {
+            id: request.get("id").cloned(),
+            method: method.to_owned(),
+            params: request.get("params").cloned().unwrap_or(Value::Object(Default::default())),
+        }),
+        _ => {
+            let id = request.get("id").cloned().unwrap_or(Value::Null);
+            Err((id, Failure::new(INVALID_REQUEST, "expected a JSON-RPC 2.0 request")))
+        },
+    }
+}
+
+/// Checks if a line looks like `POST / HTTP/1.1`, such as when a web page
+/// gets a browser to send us a request.
+fn is_http_request_line(line: &[u8]) -> bool {
+    let line = String::from_utf8_lossy(line);
+    let words: Vec<_> = line.split_whitespace().collect();
+
+    words.len() == 3 && words[0].chars().all(|c| c.is_ascii_uppercase()) && words[2].starts_with("HTTP/")
+}
+
+/// Answers a single request.
+///
+/// Returns `None` for notifications, which don't get answers.
+fn respond(request: &Request, node: Option<&mut local::connected::Node>) -> Option<Value> {
+    let result = match request.method.as_str() {
+        // Clients that need to authenticate have already done so.
+        "authenticate" => Ok(Value::Bool(true)),
+        method => call(method, &request.params, node),
+    };
+
+    response(request, result)
+}
+
+/// Builds the response to a request, unless it is a notification.
+fn response(request: &Request, result: Result<Value, Failure>) -> Option<Value> {
+    let id = request.id.clone()?;
+
+    Some(match result {
+        Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+        Err(failure) => error_response(id, failure),
+    })
+}
+
+fn error_response(id: Value, failure: Failure) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "error": { "code": failure.code, "message": failure.message },
+    })
+}
+
+fn call(method: &str, params: &Value, node: Option<&mut local::connected::Node>) -> Result<Value, Failure> {
+    match method {
+        "submit" => submit(params, connected(node)?),
+        "list_jobs" => {
+            let node = connected(node)?;
+            Ok(Value::Array(node.dispatcher.jobs().iter().map(job_summary).collect()))
+        },
+        "job_status" => {
+            let job = job_uuid(params)?;
+            let node = connected(node)?;
+
+            match node.dispatcher.job_status(&job) {
+                Some(status) => Ok(job_details(&status)),
+                None => Err(unknown_job(&job)),
+            }
+        },
+        "cancel" => {
+            let job = job_uuid(params)?;
+            let node = connected(node)?;
+
+            if !node.dispatcher.cancel(&job) {
+                return match node.dispatcher.job_status(&job) {
+                    Some(..) => Err(Failure::new(JOB_COMPLETED, format!("job {} has already completed", job))),
+                    None => Err(unknown_job(&job)),
+                };
+            }
+
+            println!("job {} was cancelled by a control client", job);
+            Ok(job_summary(&node.dispatcher.job_status(&job).unwrap()))
+        },
+        "list_nodes" => Ok(list_nodes(connected(node)?)),
+        "node_stats" => Ok(node_stats(connected(node)?)),
+        _ => Err(Failure::new(METHOD_NOT_FOUND, format!("unknown method `{}`", method))),
+    }
+}
+
+fn connected(node: Option<&mut local::connected::Node>) -> Result<&mut local::connected::Node, Failure> {
+    node.ok_or_else(|| Failure::new(NOT_CONNECTED, "not yet connected to network"))
+}
+
+fn submit(params: &Value, node: &mut local::connected::Node) -> Result<Value, Failure> {
+    let job = match (params.get("file"), params.get("tasks")) {
+        (Some(path), None) => {
+            let path = path.as_str().ok_or_else(|| invalid_params("`file` must be a path"))?;
+            ci::job_file::load(path).map_err(invalid_job)?
+        },
+        (None, Some(tasks)) => parse_job(tasks, params.get("env"))?,
+        _ => return Err(invalid_params("expected either `file` or `tasks`")),
+    };
+    let job_uuid = job.uuid;
+    let task_count = job.tasks.len();
+
+    node.dispatcher.enqueue(job).map_err(invalid_job)?;
+    println!("running {} tasks submitted by a control client as job {}", task_count, job_uuid);
+
+    Ok(json!({ "job": job_uuid }))
+}
+
+/// Builds a job out of the tasks given to `submit`.
+fn parse_job(tasks: &Value, environment: Option<&Value>) -> Result<ci::Job, Failure> {
+    let tasks = tasks.as_array().ok_or_else(|| invalid_params("`tasks` must be an array"))?;
+
+    let environment = match environment {
+        Some(environment) => parse_variables(environment)?,
+        None => BTreeMap::new(),
+    };
+
+    let descriptions = tasks.iter().map(parse_task).collect::<Result<_, _>>()?;
+
+    ci::Job::from_descriptions(descriptions, &environment).map_err(|e| match e {
+        Error::InvalidJob { reason } => invalid_params(reason),
+        e => invalid_job(e),
+    })
+}
+
+fn parse_task(task: &Value) -> Result<ci::TaskDescription, Failure> {
+    let keys = task.as_object().ok_or_else(|| invalid_params("tasks must be objects"))?.keys();
+    if let Some(key) = keys.into_iter().find(|key| !TASK_KEYS.contains(&key.as_str())) {
+        return Err(invalid_params(format!("unknown task key `{}`", key)));
+    }
+
+    let name = match task.get("name") {
+        Some(name) => Some(name.as_str().ok_or_else(|| invalid_params("task names must be strings"))?.to_owned()),
+        None => None,
+    };
+
+    let command = match task.get("command") {
+        Some(Value::String(command)) => command.split_whitespace().map(|word| word.to_owned()).collect(),
+        Some(Value::Array(words)) => parse_strings(words, "`command` must only contain strings")?,
+        _ => return Err(invalid_params("every task needs a `command`, as a string or an array of strings")),
+    };
+
+    let environment = match task.get("env") {
+        Some(variables) => parse_variables(variables)?,
+        None => BTreeMap::new(),
+    };
+
+    let working_dir = match task.get("workdir") {
+        Some(working_dir) => Some(PathBuf::from(working_dir.as_str().ok_or_else(|| invalid_params("`workdir` must be a string"))?)),
+        None => None,
+    };
+
+    let timeout = match task.get("timeout") {
+        Some(timeout) => match timeout.as_u64() {
+            Some(seconds) if seconds > 0 => Some(Duration::from_secs(seconds)),
+            _ => return Err(invalid_params("`timeout` must be a positive number of seconds")),
+        },
+        None => None,
+    };
+
+    let depends_on = match task.get("depends_on") {
+        Some(Value::String(name)) => vec![name.clone()],
+        Some(Value::Array(names)) => parse_strings(names, "`depends_on` must only contain task names")?,
+        Some(..) => return Err(invalid_params("`depends_on` must be a task name or an array of them")),
+        None => Vec::new(),
+    };
+
+    Ok(ci::TaskDescription {
+        name,
+        command,
+        environment,
+        working_dir,
+        timeout,
+        depends_on,
+    })
+}
+
+fn parse_variables(variables: &Value) -> Result<BTreeMap<String, String>, Failure> {
+    let variables = variables.as_object().ok_or_else(|| invalid_params("`env` must be an object"))?;
+
+    variables.iter().map(|(key, value)| match value.as_str() {
+        Some(value) => Ok((key.clone(), value.to_owned())),
+        None => Err(invalid_params(format!("variable `{}` must be a string", key))),
+    }).collect()
+}
+
+fn parse_strings(values: &[Value], message: &str) -> Result<Vec<String>, Failure> {
+    values.iter().map(|value| match value.as_str() {
+        Some(value) => Ok(value.to_owned()),
+        None => Err(invalid_params(message)),
+    }).collect()
+}
+
+/// Gets the job that a request is about.
+fn job_uuid(params: &Value) -> Result<Uuid, Failure> {
+    params.get("job").and_then(Value::as_str).and_then(|job| Uuid::parse_str(job).ok())
+        .ok_or_else(|| invalid_params("`job` must be the UUID of a job"))
+}
+
+/// Summarises a job, counting its tasks by how far they have got.
+fn job_summary(status: &JobStatus) -> Value {
+    let mut task_counts = BTreeMap::new();
+    for (_, state) in status.tasks.iter() {
+        *task_counts.entry(task_state_name(state)).or_insert(0) += 1;
+    }
+
+    json!({
+        "job": status.job.uuid,
+        "state": job_state_name(status.state),
+        "result_code": status.result_code(),
+        "task_counts": task_counts,
+    })
+}
+
+/// Describes a job, and each of its tasks.
+fn job_details(status: &JobStatus) -> Value {
+    let tasks: Vec<_> = status.tasks.iter().map(|&(task, ref state)| {
+        let mut details = json!({
+            "uuid": task.uuid,
+            "name": task.name,
+            "command": ::std::iter::once(&task.command.executable).chain(task.command.arguments.iter()).collect::<Vec<_>>(),
+            "state": task_state_name(state),
+        });
+
+        match *state {
+            TaskState::Running { executor } => details["executor"] = json!(executor),
+            TaskState::Finished(result) => {
+                details["result_code"] = json!(result.output.result_code);
+                details["output"] = json!(String::from_utf8_lossy(&result.output.output));
+            },
+            TaskState::Pending | TaskState::Cancelled => (),
+        }
+
+        details
+    }).collect();
+
+    json!({
+        "job": status.job.uuid,
+        "state": job_state_name(status.state),
+        "result_code": status.result_code(),
+        "tasks": tasks,
+    })
+}
+
+fn list_nodes(node: &local::connected::Node) -> Value {
+    let network = &node.network;
+
+    Value::Array(network.nodes().map(|network_node| {
+        json!({
+            "uuid": network_node.uuid,
+            "name": network_node.name,
+            "labels": network_node.labels,
+            "address": network_node.address.map(|address| address.to_string()),
+            "latency_ms": network_node.latency.map(|latency| network::Weight::from_latency(latency).0),
+            "directly_connected": network_node.connection.is_some(),
+            "siblings": network.siblings(&network_node.uuid).len(),
+            "current": network_node.uuid == node.uuid,
+        })
+    }).collect())
+}
+
+fn node_stats(node: &local::connected::Node) -> Value {
+    let jobs = node.dispatcher.jobs();
+    let job_count = |state| jobs.iter().filter(|status| status.state == state).count();
+    let drops = &node.drops;
+
+    json!({
+        "uuid": node.uuid,
+        "name": node.network.get(&node.uuid).and_then(|local_node| local_node.name.clone()),
+        "nodes": node.network.node_count(),
+        "direct_connections": node.network.nodes().filter(|network_node| network_node.connection.is_some()).count(),
+        "rejoining": node.rejoin.as_ref().is_some_and(|rejoin| !rejoin.has_given_up()),
+        "jobs": {
+            "pending": job_count(JobState::Pending),
+            "running": job_count(JobState::Running),
+            "completed": job_count(JobState::Completed),
+        },
+        "running_work": node.builder.running_work_count(),
+        "unacknowledged_packets": node.reliable.unacknowledged_count(),
+        "dropped_packets": {
+            "total": drops.total(),
+            "unroutable": drops.unroutable,
+            "hop_limit_exceeded": drops.hop_limit_exceeded,
+            "malformed": drops.malformed,
+        },
+    })
+}
+
+fn job_state_name(state: JobState) -> &'static str {
+    match state {
+        JobState::Pending => "pending",
+        JobState::Running => "running",
+        JobState::Completed => "completed",
+    }
+}
+
+fn task_state_name(state: &TaskState) -> &'static str {
+    match *state {
+        TaskState::Pending => "pending",
+        TaskState::Running { .. } => "running",
+        TaskState::Finished(result) if result.output.is_successful() => "succeeded",
+        TaskState::Finished(..) => "failed",
+        TaskState::Cancelled => "cancelled",
+    }
+}
+
+fn invalid_params<S>(message: S) -> Failure
+    where S: Into<String> {
+    Failure::new(INVALID_PARAMS, message)
+}
+
+fn invalid_job(error: Error) -> Failure {
+    let message = match error {
+        Error::InvalidJobFile { line: 0, reason } | Error::InvalidJob { reason } => reason,
+        Error::InvalidJobFile { line, reason } => format!("line {}: {}", line, reason),
+        e => format!("{:?}", e),
+    };
+
+    Failure::new(INVALID_JOB, message)
+}
+
+fn unknown_job(job: &Uuid) -> Failure {
+    Failure::new(UNKNOWN_JOB, format!("there is no job {}", job))
+}
+
+#[cfg(test)]
+mod test
+{
+    pub use super::*;
+    pub use Network;
+
+    use std::os::unix::net::UnixStream;
+    use std::io::{BufRead, BufReader};
+
+    fn node() -> local::connected::Node {
+        let uuid = Uuid::new_v4();
+        let mut config = Config::default();
+        config.identity.name = Some("control".to_owned());
+
+        local::connected::Node::new(uuid, None, Network::new(uuid, config.clock.now()), &config)
+    }
+
+    fn call(node: &mut local::connected::Node, method: &str, params: Value) -> Result<Value, Failure> {
+        let request = json!({ "jsonrpc": "2.0", "id": 7, "method": method, "params": params });
+        let response = respond(&parse_request(request.to_string().as_bytes()).unwrap(), Some(node)).unwrap();
+        assert_eq!(response["id"], json!(7));
+
+        match response.get("result") {
+            Some(result) => Ok(result.clone()),
+            None => Err(Failure::new(response["error"]["code"].as_i64().unwrap(),
+                                     response["error"]["message"].as_str().unwrap())),
+        }
+    }
+
+    #[test]
+    fn submitted_jobs_can_be_followed_and_cancelled() {
+        let mut node = node();
+        let submitted = call(&mut node, "submit", json!({
+            "env": { "CI": "1" },
+            "tasks": [
+                { "name": "build", "command": "make" },
+                { "name": "test", "command": ["make", "test"], "depends_on": "build", "timeout": 60 },
+            ],
+        })).unwrap();
+        let job = submitted["job"].clone();
+
+        let jobs = call(&mut node, "list_jobs", json!({})).unwrap();
+        assert_eq!(jobs, json!([{ "job": job, "state": "pending", "result_code": null, "task_counts": { "pending": 2 } }]));
+
+        let status = call(&mut node, "job_status", json!({ "job": job })).unwrap();
+        assert_eq!(status["tasks"][1]["name"], json!("test"));
+        assert_eq!(status["tasks"][1]["command"], json!(["make", "test"]));
+
+        let cancelled = call(&mut node, "cancel", json!({ "job": job })).unwrap();
+        assert_eq!(cancelled["state"], json!("completed"));
+        assert_eq!(cancelled["task_counts"], json!({ "cancelled": 2 }));
+        assert_eq!(call(&mut node, "cancel", json!({ "job": job })).unwrap_err().code, JOB_COMPLETED);
+    }
+
+    #[test]
+    fn jobs_that_could_never_run_are_refused() {
+        let mut node = node();
+
+        let unknown_dependency = json!({ "tasks": [{ "command": "true", "depends_on": "nothing" }] });
+        assert_eq!(call(&mut node, "submit", unknown_dependency).unwrap_err().code, INVALID_PARAMS);
+
+        let cycle = json!({ "tasks": [
+            { "name": "a", "command": "true", "depends_on": "b" },
+            { "name": "b", "command": "true", "depends_on": "a" },
+        ]});
+        assert_eq!(call(&mut node, "submit", cycle).unwrap_err().code, INVALID_JOB);
+
+        let empty_command = json!({ "tasks": [{ "command": [""] }] });
+        assert_eq!(call(&mut node, "submit", empty_command).unwrap_err().code, INVALID_PARAMS);
+
+        let unknown_key = json!({ "tasks": [{ "command": "true", "retries": 3 }] });
+        assert_eq!(call(&mut node, "submit", unknown_key).unwrap_err().code, INVALID_PARAMS);
+
+        assert!(!node.dispatcher.has_work());
+    }
+
+    #[test]
+    fn unknown_jobs_and_methods_are_errors() {
+        let mut node = node();
+
+        assert_eq!(call(&mut node, "job_status", json!({ "job": Uuid::new_v4() })).unwrap_err().code, UNKNOWN_JOB);
+        assert_eq!(call(&mut node, "job_status", json!({ "job": "not a uuid" })).unwrap_err().code, INVALID_PARAMS);
+        assert_eq!(call(&mut node, "reboot", json!({})).unwrap_err().code, METHOD_NOT_FOUND);
+    }
+
+    #[test]
+    fn the_local_node_is_listed() {
+        let mut node = node();
+        let uuid = node.uuid;
+
+        let nodes = call(&mut node, "list_nodes", json!({})).unwrap();
+        assert_eq!(nodes[0]["uuid"], json!(uuid));
+        assert_eq!(nodes[0]["name"], json!("control"));
+        assert_eq!(nodes[0]["current"], json!(true));
+
+        let stats = call(&mut node, "node_stats", json!({})).unwrap();
+        assert_eq!(stats["nodes"], json!(1));
+        assert_eq!(stats["dropped_packets"]["total"], json!(0));
+    }
+
+    /// Answers what a client has sent, returning the responses.
+    ///
+    /// The client must have finished sending, so that it is hung up on
+    /// once the responses have been sent.
+    fn serve<S>(control: &mut Control, stream: S) -> Vec<Value>
+        where S: Read {
+        // Nodes that haven't joined a network can't answer anything yet.
+        control.serve(&mut local::Node::Unconnected);
+
+        BufReader::new(stream).lines().map(|line| serde_json::from_str(&line.unwrap()).unwrap()).collect()
+    }
+
+    fn send_unix(path: &Path, data: &[u8]) -> UnixStream {
+        let stream = UnixStream::connect(path).unwrap();
+        (&stream).write_all(data).unwrap();
+        stream.shutdown(::std::net::Shutdown::Write).unwrap();
+        stream
+    }
+
+    fn send_tcp(address: ::std::net::SocketAddr, data: &[u8]) -> ::std::net::TcpStream {
+        let stream = ::std::net::TcpStream::connect(address).unwrap();
+        (&stream).write_all(data).unwrap();
+        stream.shutdown(::std::net::Shutdown::Write).unwrap();
+        stream
+    }
+
+    fn unix_control(name: &str) -> (Control, PathBuf) {
+        let path = ::std::env::temp_dir().join(format!("parapet-test-control-{}-{}.sock", name, ::std::process::id()));
+        (Control::bind(&Address::Unix(path.clone()), None).unwrap(), path)
+    }
+
+    fn tcp_control(name: &str) -> (Control, ::std::net::SocketAddr, PathBuf) {
+        let state_dir = ::std::env::temp_dir().join(format!("parapet-test-control-{}-{}", name, ::std::process::id()));
+        let control = Control::bind(&Address::Tcp("127.0.0.1:0".parse().unwrap()), Some(&state_dir)).unwrap();
+
+        let address = match control.listener {
+            Listener::Tcp(ref listener) => listener.local_addr().unwrap(),
+            Listener::Unix(..) => unreachable!(),
+        };
+
+        (control, address, state_dir)
+    }
+
+    #[test]
+    fn malformed_requests_are_errors_and_notifications_are_not_answered() {
+        let mut node = node();
+
+        assert_eq!(parse_request(b"{").unwrap_err().1.code, PARSE_ERROR);
+        assert_eq!(parse_request(b"[1, 2]").unwrap_err().1.code, INVALID_REQUEST);
+        assert_eq!(respond(&parse_request(br#"{"jsonrpc": "2.0", "method": "list_jobs"}"#).unwrap(), Some(&mut node)), None);
+    }
+
+    #[test]
+    fn requests_are_answered_over_unix_sockets() {
+        let (mut control, path) = unix_control("answered");
+
+        let stream = send_unix(&path, b"{\"jsonrpc\": \"2.0\", \"id\": \"a\", \"method\": \"node_stats\"}\n\n\
+                                        {\"jsonrpc\": \"2.0\", \"id\": \"b\", \"method\": \"list_jobs\"}");
+
+        let responses = serve(&mut control, stream);
+        assert_eq!(responses.len(), 2);
+        assert_eq!(responses[0]["id"], json!("a"));
+        assert_eq!(responses[1]["id"], json!("b"));
+        assert_eq!(responses[1]["error"]["code"], json!(NOT_CONNECTED));
+    }
+
+    #[test]
+    fn clients_that_send_malformed_requests_are_hung_up_on() {
+        let (mut control, path) = unix_control("malformed");
+
+        let stream = send_unix(&path, b"{\n{\"jsonrpc\": \"2.0\", \"id\": 1, \"method\": \"list_jobs\"}\n");
+
+        let responses = serve(&mut control, stream);
+        assert_eq!(responses.len(), 1);
+        assert_eq!(responses[0]["error"]["code"], json!(PARSE_ERROR));
+    }
+
+    #[test]
+    fn http_requests_are_rejected() {
+        let (mut control, path) = unix_control("http");
+
+        let request = b"POST / HTTP/1.1\r\nHost: localhost\r\nContent-Type: text/plain\r\n\r\n\
+                        {\"jsonrpc\": \"2.0\", \"id\": 1, \"method\": \"submit\", \"params\": {\"tasks\": [{\"command\": \"true\"}]}}\n";
+
+        let responses = serve(&mut control, send_unix(&path, request));
+        assert_eq!(responses.len(), 1);
+        assert_eq!(responses[0]["id"], Value::Null);
+        assert_eq!(responses[0]["error"]["code"], json!(INVALID_REQUEST));
+    }
+
+    #[test]
+    fn tcp_clients_must_authenticate() {
+        let (mut control, address, state_dir) = tcp_control("authenticate");
+        let token = fs::read_to_string(state_dir.join(TOKEN_FILE_NAME)).unwrap();
+        let list_jobs = "{\"jsonrpc\": \"2.0\", \"id\": 2, \"method\": \"list_jobs\"}\n";
+
+        let responses = serve(&mut control, send_tcp(address, list_jobs.as_bytes()));
+        assert_eq!(responses.len(), 1);
+        assert_eq!(responses[0]["error"]["code"], json!(UNAUTHORIZED));
+
+        let wrong_token = json!({ "jsonrpc": "2.0", "id": 1, "method": "authenticate", "params": { "token": "hunter2" } });
+        let responses = serve(&mut control, send_tcp(address, format!("{}\n{}", wrong_token, list_jobs).as_bytes()));
+        assert_eq!(responses.len(), 1);
+        assert_eq!(responses[0]["error"]["code"], json!(UNAUTHORIZED));
+
+        let authenticate = json!({ "jsonrpc": "2.0", "id": 1, "method": "authenticate", "params": { "token": token.trim() } });
+        let responses = serve(&mut control, send_tcp(address, format!("{}\n{}", authenticate, list_jobs).as_bytes()));
+        assert_eq!(responses.len(), 2);
+        assert_eq!(responses[0]["result"], json!(true));
+        assert_eq!(responses[1]["error"]["code"], json!(NOT_CONNECTED));
+
+        drop(control);
+        assert!(!state_dir.join(TOKEN_FILE_NAME).exists());
+        fs::remove_dir_all(&state_dir).unwrap();
+    }
+
+    #[test]
+    fn tcp_is_only_served_on_loopback_addresses() {
+        let state_dir = ::std::env::temp_dir().join(format!("parapet-test-control-loopback-{}", ::std::process::id()));
+
+        assert!(Control::bind(&Address::Tcp("0.0.0.0:0".parse().unwrap()), Some(&state_dir)).is_err());
+        assert!(Control::bind(&Address::Tcp("127.0.0.1:0".parse().unwrap()), None).is_err());
+        assert!(Control::bind(&Address::Tcp("127.0.0.1:0".parse().unwrap()), Some(&state_dir)).is_ok());
+
+        fs::remove_dir_all(&state_dir).unwrap();
+    }
+}
@@ -1,6 +1,8 @@
-use Packet;
+use {Packet, PacketKind};
+use protocol::UserAgent;
 use std;
 use proto;
+use uuid::Uuid;
 
 #[derive(Debug)]
 pub enum Error
@@ -8,13 +10,61 @@ pub enum Error
     /// Gently stop the node.
     Stop { reason: &'static str },
 
-    UnexpectedPacket { expected: &'static str, received: Packet },
+    /// Boxed, as packets are much larger than any other error.
+    UnexpectedPacket { expected: &'static str, received: Box<Packet> },
     InvalidPong { expected: Vec<u8>, received: Vec<u8> },
+    /// The remote node speaks a protocol version we don't understand.
+    IncompatibleProtocol { ours: UserAgent, theirs: UserAgent },
+    /// The remote node requires a network key that we don't have.
+    NetworkKeyRequired,
+    /// The remote node couldn't prove that it knows the network key.
+    AuthenticationFailed,
+    /// The remote node sent a public key that we can't encrypt with.
+    InvalidPublicKey,
+    /// The remote node didn't offer to encrypt the connection.
+    EncryptionRequired,
+    /// The remote node couldn't prove that it owns the identity key of the
+    /// UUID it claimed.
+    IdentityNotProven { uuid: Uuid },
+    /// The remote node terminated the connection.
+    Terminated { reason: String },
+    /// A direct connection to a node in our network could not be made.
+    ConnectRefused { reason: String },
+    /// Another network has nodes with the same UUIDs as nodes in ours, but
+    /// they aren't the same nodes, so the networks can't be merged.
+    UuidCollision { uuids: Vec<Uuid> },
+    /// There is no path to a node that we know of.
+    Unroutable { destination: Uuid },
+    /// The identity file in the state directory could not be parsed.
+    ///
+    /// The line is zero if the problem isn't with any particular line.
+    InvalidIdentity { line: usize, reason: String },
+    /// A job file could not be parsed.
+    ///
+    /// The line is zero if the problem isn't with any particular line.
+    InvalidJobFile { line: usize, reason: String },
+    /// A job's tasks can't be run, such as when they depend on each other in a cycle.
+    InvalidJob { reason: String },
+    /// A node refused a request made through its control API.
+    Control { code: i64, message: String },
 
     Io(std::io::Error),
     Protocol(proto::Error),
 }
 
+impl Error
+{
+    /// Creates an error for receiving a packet we weren't expecting.
+    ///
+    /// If the packet is a `Terminate`, the remote has hung up on us.
+    pub fn unexpected_packet(expected: &'static str, received: Packet) -> Self {
+        match received.kind {
+            PacketKind::Terminate(terminate) => Error::Terminated { reason: terminate.reason },
+            _ => Error::UnexpectedPacket { expected, received: Box::new(received) },
+        }
+    }
+}
+
 impl From<std::io::Error> for Error
 {
     fn from(e: std::io::Error) -> Self {
@@ -0,0 +1,280 @@
+//! The identity of a node, which can be kept across restarts.
+//!
+//! An identity is stored in an `identity` file inside a state directory.
+//! The file is a list of `key = value` lines.
+//!
+//! ```text
+//! uuid = 0b0ea7c4-51a6-4c53-a2c0-4bd1a0d3e3c4
+//! name = build-box-3
+//! public_key = 5d4c...
+//! secret_key = 9a1f...
+//! ```
+
+use Error;
+use network::encryption;
+
+use rand::{self, Rng};
+use uuid::Uuid;
+use x25519_dalek;
+
+use std::fmt;
+use std::fs;
+use std::io::prelude::*;
+use std::path::Path;
+
+/// The name of the file inside the state directory.
+const FILE_NAME: &str = "identity";
+
+/// The size of a key, in bytes.
+const KEY_SIZE: usize = 32;
+
+/// Who a node is.
+#[derive(Clone, Debug)]
+pub struct Identity
+{
+    pub uuid: Uuid,
+    /// A human-readable name for the node.
+    pub name: Option<String>,
+    /// A long-term keypair belonging to the node.
+    pub keypair: Option<Keypair>,
+}
+
+/// A long-term X25519 keypair.
+#[derive(Clone)]
+pub struct Keypair
+{
+    secret_key: [u8; KEY_SIZE],
+    public_key: [u8; KEY_SIZE],
+}
+
+impl Identity
+{
+    /// Creates a brand new identity.
+    pub fn generate() -> Self {
+        Identity {
+            uuid: Uuid::new_v4(),
+            name: None,
+            keypair: Some(Keypair::generate()),
+        }
+    }
+
+    /// Loads the identity from a state directory, creating and saving
+    /// a new one if the directory doesn't have one yet.
+    pub fn load_or_create<P>(state_dir: P) -> Result<Self, Error>
+        where P: AsRef<Path> {
+        let path = state_dir.as_ref().join(FILE_NAME);
+
+        if path.exists() {
+            let mut text = String::new();
+            fs::File::open(&path)?.read_to_string(&mut text)?;
+
+            Identity::parse(&text)
+        } else {
+            let identity = Identity::generate();
+            identity.save(state_dir)?;
+
+            Ok(identity)
+        }
+    }
+
+    /// Saves the identity into a state directory.
+    pub fn save<P>(&self, state_dir: P) -> Result<(), Error>
+        where P: AsRef<Path> {
+        fs::create_dir_all(state_dir.as_ref())?;
+
+        let path = state_dir.as_ref().join(FILE_NAME);
+        let mut file = open_private(&path)?;
+        file.write_all(self.to_string().as_bytes())?;
+
+        Ok(())
+    }
+
+    /// Parses an identity file.
+    pub fn parse(text: &str) -> Result<Self, Error> {
+        let mut uuid = None;
+        let mut name = None;
+        let mut public_key = None;
+        let mut secret_key = None;
+
+        for (index, line) in text.lines().enumerate() {
+            let line_number = index + 1;
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with('#') { continue }
+
+            let mut parts = line.splitn(2, '=');
+            let key = parts.next().unwrap().trim();
+            let value = match parts.next() {
+                Some(value) => value.trim(),
+                None => return Err(invalid(line_number, "expected `key = value`")),
+            };
+
+            match key {
+                "uuid" => uuid = Some(Uuid::parse_str(value).map_err(|_| invalid(line_number, "invalid UUID"))?),
+                "name" => name = Some(value.to_owned()),
+                "public_key" => public_key = Some(parse_key(value).ok_or_else(|| invalid(line_number, "invalid public key"))?),
+                "secret_key" => secret_key = Some(parse_key(value).ok_or_else(|| invalid(line_number, "invalid secret key"))?),
+                _ => return Err(invalid(line_number, &format!("unknown key `{}`", key))),
+            }
+        }
+
+        let keypair = match (public_key, secret_key) {
+            (Some(public_key), Some(secret_key)) => {
+                let keypair = Keypair { secret_key, public_key };
+
+                if !keypair.is_valid() {
+                    return Err(invalid(0, "the public key does not match the secret key"));
+                }
+
+                Some(keypair)
+            },
+            (None, None) => None,
+            _ => return Err(invalid(0, "the public and secret keys must both be present")),
+        };
+
+        Ok(Identity {
+            uuid: uuid.ok_or_else(|| invalid(0, "missing `uuid`"))?,
+            name,
+            keypair,
+        })
+    }
+}
+
+impl Keypair
+{
+    /// Generates a new random keypair.
+    pub fn generate() -> Self {
+        let mut secret_key = [0; KEY_SIZE];
+        rand::thread_rng().fill_bytes(&mut secret_key);
+
+        Keypair {
+            secret_key,
+            public_key: x25519_dalek::x25519(secret_key, x25519_dalek::X25519_BASEPOINT_BYTES),
+        }
+    }
+
+    pub fn public_key(&self) -> Vec<u8> {
+        self.public_key.to_vec()
+    }
+
+    /// Agrees on a secret with the owner of another public key, so that
+    /// we can prove to them that we own this keypair.
+    ///
+    /// Returns `None` if the public key is malformed.
+    pub fn shared_secret(&self, their_public_key: &[u8]) -> Option<[u8; KEY_SIZE]> {
+        encryption::diffie_hellman(self.secret_key, their_public_key)
+    }
+
+    /// Checks that the public key was derived from the secret key.
+    fn is_valid(&self) -> bool {
+        x25519_dalek::x25519(self.secret_key, x25519_dalek::X25519_BASEPOINT_BYTES) == self.public_key
+    }
+}
+
+impl fmt::Display for Identity
+{
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(fmt, "uuid = {}", self.uuid)?;
+
+        if let Some(ref name) = self.name {
+            writeln!(fmt, "name = {}", name)?;
+        }
+
+        if let Some(ref keypair) = self.keypair {
+            writeln!(fmt, "public_key = {}", to_hex(&keypair.public_key))?;
+            writeln!(fmt, "secret_key = {}", to_hex(&keypair.secret_key))?;
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Keypair
+{
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        // Never print the secret key.
+        fmt.debug_struct("Keypair").field("public_key", &to_hex(&self.public_key)).finish()
+    }
+}
+
+fn invalid(line: usize, reason: &str) -> Error {
+    Error::InvalidIdentity { line, reason: reason.to_owned() }
+}
+
+/// Opens a file that only we can read, as it contains a secret key.
+#[cfg(unix)]
+fn open_private(path: &Path) -> Result<fs::File, Error> {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    Ok(fs::OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(path)?)
+}
+
+#[cfg(not(unix))]
+fn open_private(path: &Path) -> Result<fs::File, Error> {
+    Ok(fs::File::create(path)?)
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn parse_key(hex: &str) -> Option<[u8; KEY_SIZE]> {
+    if hex.len() != KEY_SIZE * 2 || !hex.is_ascii() { return None }
+
+    let mut key = [0; KEY_SIZE];
+    for (i, byte) in key.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+    }
+
+    Some(key)
+}
+
+#[cfg(test)]
+mod test
+{
+    pub use super::*;
+    pub use std::env;
+
+    fn setup() -> ::std::path::PathBuf {
+        env::temp_dir().join(format!("parapet-test-{}", Uuid::new_v4()))
+    }
+
+    #[test]
+    fn the_same_identity_is_loaded_after_a_restart() {
+        let state_dir = setup();
+
+        let first = Identity::load_or_create(&state_dir).unwrap();
+        let second = Identity::load_or_create(&state_dir).unwrap();
+
+        assert_eq!(first.uuid, second.uuid);
+        assert_eq!(first.keypair.unwrap().public_key(), second.keypair.unwrap().public_key());
+
+        fs::remove_dir_all(&state_dir).unwrap();
+    }
+
+    #[test]
+    fn identities_can_be_round_tripped() {
+        let mut identity = Identity::generate();
+        identity.name = Some("build-box-3".to_owned());
+
+        let parsed = Identity::parse(&identity.to_string()).unwrap();
+
+        assert_eq!(parsed.uuid, identity.uuid);
+        assert_eq!(parsed.name, identity.name);
+    }
+
+    #[test]
+    fn the_keypair_is_optional() {
+        let identity = Identity::parse("uuid = 0b0ea7c4-51a6-4c53-a2c0-4bd1a0d3e3c4\n").unwrap();
+
+        assert!(identity.keypair.is_none());
+    }
+
+    #[test]
+    fn errors_point_at_the_line() {
+        match Identity::parse("uuid = 0b0ea7c4-51a6-4c53-a2c0-4bd1a0d3e3c4\nname\n") {
+            Err(Error::InvalidIdentity { line, .. }) => assert_eq!(line, 2),
+            result => panic!("expected an error, got {:?}", result),
+        }
+    }
+}
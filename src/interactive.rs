@@ -35,11 +35,24 @@ pub enum Command
     /// List the nodes in the network.
     List,
 
+    /// Show the packets we have had to drop.
+    Stats,
+
+    /// Merge our network with the network of the node at an address.
+    Merge {
+        address: String,
+    },
+
     /// Run a command over the network.
     Run {
         executable: String,
         arguments: Vec<String>
     },
+
+    /// Run the tasks in a job file over the network.
+    Load {
+        path: String,
+    },
 }
 
 impl Interactive
@@ -71,6 +84,17 @@ impl Interactive
                     "exit" | "quit" | "q" => break,
                     "help" => tx.send(Message::Command(Command::Help)).unwrap(),
                     "list" => tx.send(Message::Command(Command::List)).unwrap(),
+                    "stats" => tx.send(Message::Command(Command::Stats)).unwrap(),
+                    "merge" if arguments.len() == 1 => {
+                        tx.send(Message::Command(Command::Merge {
+                            address: arguments[0].to_owned(),
+                        })).unwrap();
+                    },
+                    "load" => {
+                        tx.send(Message::Command(Command::Load {
+                            path: arguments.first().cloned().unwrap_or(ci::job_file::FILE_NAME).to_owned(),
+                        })).unwrap();
+                    },
                     "run" => {
 
                         tx.send(Message::Command(Command::Run {
@@ -99,12 +123,18 @@ impl Interactive
                     Message::Command(cmd) => match cmd {
                         Command::Help => self.help(),
                         Command::List => self.list(),
+                        Command::Stats => self.stats(),
+                        Command::Merge { address } => self.merge(&address),
                         Command::Unknown(cmd) => println!("unknown command '{}'", cmd),
                         Command::Run { executable, arguments }=> self.run_command(&executable, &arguments),
+                        Command::Load { path } => self.load(&path),
                     },
                 },
                 Err(TryRecvError::Empty) => (), // all good
-                Err(TryRecvError::Disconnected) => break,
+                Err(TryRecvError::Disconnected) => {
+                    self.0.terminate("node is shutting down");
+                    break;
+                },
             }
 
             match self.0.tick() {
@@ -121,7 +151,14 @@ impl Interactive
     }
 
     pub fn help(&self) {
-        println!("<unimplemented>")
+        println!("commands:");
+        println!("  help                 show this list");
+        println!("  list                 list the nodes in the network");
+        println!("  stats                show the packets we have had to drop");
+        println!("  merge <address>      merge with the network of the node at an address");
+        println!("  run <command...>     run a command over the network");
+        println!("  load [path]          run the tasks in a job file, {} by default", ci::job_file::FILE_NAME);
+        println!("  exit, quit, q        stop the node");
     }
 
     pub fn list(&self) {
@@ -129,7 +166,21 @@ impl Interactive
             let network = &node.network;
 
             for network_node in network.nodes() {
-                print!("{} - ({} siblings)", network_node.uuid, network.siblings(&network_node.uuid).len());
+                print!("{} - ({} siblings)", network_node, network.siblings(&network_node.uuid).len());
+
+                if let Some(address) = network_node.address {
+                    print!(" at {}", address);
+                }
+
+                if let Some(latency) = network_node.latency {
+                    print!(" ({:.2}ms away)", ::network::Weight::from_latency(latency).0);
+                }
+
+                if !network_node.labels.is_empty() {
+                    let labels: Vec<_> = network_node.labels.iter().map(|(key, value)| format!("{}={}", key, value)).collect();
+                    print!(" [{}]", labels.join(", "));
+                }
+
                 if network_node.uuid == node.uuid {
                     println!(" (current)");
                 } else {
@@ -141,25 +192,59 @@ impl Interactive
         }
     }
 
+    pub fn stats(&self) {
+        if let local::Node::Connected { ref node, .. } = self.0.node {
+            let drops = &node.drops;
+
+            println!("dropped {} packets", drops.total());
+            println!("  {} with no route to their destination", drops.unroutable);
+            println!("  {} passed on too many times", drops.hop_limit_exceeded);
+            println!("  {} malformed", drops.malformed);
+        } else {
+            println!("not yet connected to network");
+        }
+    }
+
+    pub fn merge(&mut self, address: &str) {
+        if let Err(e) = self.0.merge(address) {
+            println!("could not merge with the network at {}: {:?}", address, e);
+        }
+    }
+
     pub fn run_command(&mut self, executable: &str, arguments: &[String]) {
         if let local::Node::Connected { ref mut node, .. } = self.0.node {
             let job = ci::Job {
                 uuid: Uuid::new_v4(),
-                tasks: vec![ci::Task {
-                    uuid: Uuid::new_v4(),
-                    command: ci::Command {
-                        executable: executable.to_owned(),
-                        arguments: arguments.to_owned(),
-                    },
-                }].into_iter().collect(),
+                tasks: vec![ci::Task::new(ci::Command::new(executable, arguments.to_owned()))],
             };
 
-            node.dispatcher.enqueue(job);
+            if let Err(e) = node.dispatcher.enqueue(job) {
+                println!("could not run {}: {:?}", executable, e);
+            }
 
             // node.broadcast_packet(&PacketKind::WorkRequest(protocol::WorkRequest::from_work(&work))).unwrap();
         } else {
             println!("not yet connected to network");
         }
     }
-}
 
+    pub fn load(&mut self, path: &str) {
+        if let local::Node::Connected { ref mut node, .. } = self.0.node {
+            match ci::job_file::load(path) {
+                Ok(job) => {
+                    let task_count = job.tasks.len();
+
+                    match node.dispatcher.enqueue(job) {
+                        Ok(()) => println!("running {} tasks from {}", task_count, path),
+                        Err(e) => println!("could not run the job in {}: {:?}", path, e),
+                    }
+                },
+                Err(Error::InvalidJobFile { line: 0, reason }) => println!("invalid job file {}: {}", path, reason),
+                Err(Error::InvalidJobFile { line, reason }) => println!("invalid job file {}:{}: {}", path, line, reason),
+                Err(e) => println!("could not load job file {}: {:?}", path, e),
+            }
+        } else {
+            println!("not yet connected to network");
+        }
+    }
+}
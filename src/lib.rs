@@ -2,23 +2,42 @@ extern crate mio;
 extern crate slab;
 extern crate uuid;
 extern crate byteorder;
-extern crate graphsearch;
 extern crate twox_hash;
 extern crate walkdir;
 #[macro_use]
 extern crate protocol as proto;
 extern crate itertools;
+extern crate rand;
+extern crate hmac;
+extern crate sha2;
+extern crate x25519_dalek;
+extern crate chacha20poly1305;
+extern crate net2;
+extern crate mio_uds;
+extern crate libc;
+#[macro_use]
+extern crate serde_json;
 
 pub use self::parapet::Parapet;
 pub use self::interactive::Interactive;
+pub use self::control::{Control, Session};
 pub use self::network::Network;
 pub use self::error::Error;
+pub use self::config::Config;
+pub use self::identity::Identity;
+pub use self::clock::Clock;
+pub use self::random::Random;
 pub use self::protocol::{Packet, PacketKind};
 
 pub mod parapet;
 pub mod interactive;
+pub mod control;
 pub mod network;
 pub mod error;
+pub mod config;
+pub mod identity;
+pub mod clock;
+pub mod random;
 pub mod protocol;
 pub mod ci;
 
@@ -28,7 +47,8 @@ pub const SERVER_ADDRESS: (&'static str, u16) = ("127.0.0.1", SERVER_PORT);
 pub const CLIENT_NAME: &'static str = "vanilla";
 pub const CLIENT_VERSION: &'static str = env!("CARGO_PKG_VERSION");
 
-pub const PROTOCOL_MAJOR: u16 = 0;
+/// Any change to the layout of a packet must increment this.
+pub const PROTOCOL_MAJOR: u16 = 2;
 pub const PROTOCOL_REVISION: u16 = 0;
 
 pub fn user_agent() -> protocol::UserAgent {
@@ -0,0 +1,116 @@
+//! Challenge-response authentication using a pre-shared network key.
+//!
+//! When a network has a key, the accepting node sends a random challenge
+//! in its `Pong`. The joining node then proves it knows the key by
+//! including a keyed hash of the challenge in its `JoinRequest`.
+//!
+//! The accepting node proves it knows the key as well, by including a
+//! keyed hash of the data from the `Ping` in its `Pong`.
+//!
+//! Nodes also prove that they own the UUID they claim, by hashing it with
+//! a secret shared between their long-term identity key and the accepting
+//! node's ephemeral key. Only the owner of the identity key can work out
+//! that secret, and the ephemeral key is new for every connection, so
+//! proofs can't be replayed.
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use rand::{self, Rng};
+use uuid::Uuid;
+
+/// The number of random bytes in a challenge.
+const CHALLENGE_SIZE: usize = 32;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Mixed into identity proofs, so they can't be mistaken for anything else.
+const IDENTITY_LABEL: &[u8] = b"parapet identity";
+
+/// Generates a random challenge.
+pub fn challenge() -> Vec<u8> {
+    let mut challenge = vec![0; CHALLENGE_SIZE];
+    rand::thread_rng().fill_bytes(&mut challenge);
+    challenge
+}
+
+/// Proves knowledge of a key by hashing a challenge with it.
+pub fn prove(key: &str, challenge: &[u8]) -> Vec<u8> {
+    let mut mac = HmacSha256::new_from_slice(key.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(challenge);
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Checks that a proof was created with a key.
+pub fn verify(key: &str, challenge: &[u8], proof: &[u8]) -> bool {
+    let mut mac = HmacSha256::new_from_slice(key.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(challenge);
+    // Compare in constant time so the proof can't be guessed byte by byte.
+    mac.verify_slice(proof).is_ok()
+}
+
+/// Proves that we own a UUID, using the secret shared between our identity
+/// key and the other end's ephemeral key.
+pub fn prove_identity(shared_secret: &[u8], uuid: &Uuid) -> Vec<u8> {
+    identity_mac(shared_secret, uuid).finalize().into_bytes().to_vec()
+}
+
+/// Checks that a node proved it owns a UUID.
+pub fn verify_identity(shared_secret: &[u8], uuid: &Uuid, proof: &[u8]) -> bool {
+    identity_mac(shared_secret, uuid).verify_slice(proof).is_ok()
+}
+
+fn identity_mac(shared_secret: &[u8], uuid: &Uuid) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(shared_secret).expect("HMAC accepts keys of any size");
+    mac.update(IDENTITY_LABEL);
+    mac.update(uuid.as_bytes());
+    mac
+}
+
+#[cfg(test)]
+mod test
+{
+    pub use super::*;
+
+    #[test]
+    fn challenges_are_random() {
+        assert_eq!(challenge().len(), CHALLENGE_SIZE);
+        assert!(challenge() != challenge());
+    }
+
+    #[test]
+    fn verify_accepts_proof_made_with_the_same_key() {
+        let challenge = challenge();
+        let proof = prove("hunter2", &challenge);
+
+        assert!(verify("hunter2", &challenge, &proof));
+    }
+
+    #[test]
+    fn verify_rejects_proof_made_with_a_different_key() {
+        let challenge = challenge();
+        let proof = prove("hunter3", &challenge);
+
+        assert!(!verify("hunter2", &challenge, &proof));
+        assert!(!verify("hunter2", &challenge, &[]));
+    }
+
+    #[test]
+    fn identity_proofs_only_verify_for_the_same_uuid() {
+        let (ours, theirs) = (::identity::Keypair::generate(), ::network::encryption::KeyExchange::new());
+        let uuid = Uuid::new_v4();
+
+        let proof = prove_identity(&ours.shared_secret(&theirs.public_key()).unwrap(), &uuid);
+        let shared_secret = theirs.shared_secret(&ours.public_key()).unwrap();
+
+        assert!(verify_identity(&shared_secret, &uuid, &proof));
+        assert!(!verify_identity(&shared_secret, &Uuid::new_v4(), &proof));
+        assert!(!verify_identity(&shared_secret, &uuid, &[]));
+    }
+
+    #[test]
+    fn verify_rejects_proof_for_a_different_challenge() {
+        let proof = prove("hunter2", &challenge());
+
+        assert!(!verify("hunter2", &challenge(), &proof));
+    }
+}
@@ -0,0 +1,96 @@
+use {Clock, Random};
+
+use rand::Rng;
+
+use std::time::{SystemTime, Duration};
+
+/// Keeps track of when to retry something that keeps failing.
+///
+/// The delay doubles after every failure, up to a maximum.
+#[derive(Clone, Debug)]
+pub struct Backoff
+{
+    /// The delay after the first failure.
+    pub initial: Duration,
+    /// The longest we will ever wait.
+    pub maximum: Duration,
+
+    delay: Duration,
+    next_attempt_at: SystemTime,
+    clock: Clock,
+    random: Random,
+}
+
+impl Backoff
+{
+    /// Creates a new backoff, with the first attempt due immediately.
+    pub fn new(initial: Duration, maximum: Duration, clock: &Clock, random: &Random) -> Self {
+        Backoff {
+            initial,
+            maximum,
+            delay: initial,
+            next_attempt_at: clock.now(),
+            clock: clock.clone(),
+            random: random.clone(),
+        }
+    }
+
+    /// Checks if it is time for another attempt.
+    pub fn is_due(&self) -> bool {
+        self.clock.now() >= self.next_attempt_at
+    }
+
+    /// Records a failed attempt.
+    ///
+    /// Returns how long we will wait before the next attempt. A little
+    /// randomness is added so that nodes that failed together don't
+    /// retry together.
+    pub fn failed(&mut self) -> Duration {
+        let jitter_ms = self.random.gen_range(0, millis(self.delay) / 4 + 1);
+        let wait = self.delay + Duration::from_millis(jitter_ms);
+
+        self.next_attempt_at = self.clock.now() + wait;
+        self.delay = ::std::cmp::min(self.delay * 2, self.maximum);
+
+        wait
+    }
+
+    /// The delay that will be used after the next failure, without jitter.
+    pub fn delay(&self) -> Duration { self.delay }
+}
+
+fn millis(duration: Duration) -> u64 {
+    duration.as_secs() * 1000 + duration.subsec_millis() as u64
+}
+
+#[cfg(test)]
+mod test
+{
+    pub use super::*;
+
+    fn setup() -> Backoff {
+        Backoff::new(Duration::from_millis(100), Duration::from_millis(1000), &Clock::system(), &Random::system())
+    }
+
+    #[test]
+    fn the_first_attempt_is_due_immediately() {
+        assert!(setup().is_due());
+    }
+
+    #[test]
+    fn the_delay_doubles_up_to_the_maximum() {
+        let mut backoff = setup();
+
+        let delays: Vec<_> = (0..6).map(|_| { backoff.failed(); millis(backoff.delay()) }).collect();
+        assert_eq!(delays, vec![200, 400, 800, 1000, 1000, 1000]);
+    }
+
+    #[test]
+    fn attempts_are_not_due_after_a_failure() {
+        let mut backoff = setup();
+
+        let wait = backoff.failed();
+        assert!(wait >= Duration::from_millis(100) && wait <= Duration::from_millis(125));
+        assert!(!backoff.is_due());
+    }
+}
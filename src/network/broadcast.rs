@@ -0,0 +1,76 @@
+use uuid::Uuid;
+
+use std::collections::{HashSet, VecDeque};
+
+/// How many broadcasts to remember having seen.
+///
+/// Duplicates only arrive while nodes disagree about the shape of the
+/// network, so only recent broadcasts need remembering.
+const MAX_REMEMBERED_BROADCASTS: usize = 4096;
+
+/// Keeps track of the broadcasts we have seen, so that we handle and pass
+/// on each broadcast only once.
+#[derive(Clone, Debug)]
+pub struct Broadcasts
+{
+    seen: HashSet<Uuid>,
+    /// The broadcasts we have seen, oldest first.
+    order: VecDeque<Uuid>,
+}
+
+impl Broadcasts
+{
+    pub fn new() -> Self {
+        Broadcasts {
+            seen: HashSet::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    /// Remembers a broadcast, checking if this is the first time we have seen it.
+    pub fn first_sighting(&mut self, broadcast_id: &Uuid) -> bool {
+        if !self.seen.insert(*broadcast_id) { return false }
+
+        self.order.push_back(*broadcast_id);
+
+        if self.order.len() > MAX_REMEMBERED_BROADCASTS {
+            let oldest = self.order.pop_front().unwrap();
+            self.seen.remove(&oldest);
+        }
+
+        true
+    }
+}
+
+impl Default for Broadcasts
+{
+    fn default() -> Self { Broadcasts::new() }
+}
+
+#[cfg(test)]
+mod test
+{
+    pub use super::*;
+
+    #[test]
+    fn broadcasts_are_only_seen_for_the_first_time_once() {
+        let mut broadcasts = Broadcasts::new();
+        let broadcast_id = Uuid::new_v4();
+
+        assert!(broadcasts.first_sighting(&broadcast_id));
+        assert!(!broadcasts.first_sighting(&broadcast_id));
+    }
+
+    #[test]
+    fn old_broadcasts_are_forgotten() {
+        let mut broadcasts = Broadcasts::new();
+        let first_broadcast_id = Uuid::new_v4();
+
+        broadcasts.first_sighting(&first_broadcast_id);
+        for _ in 0..MAX_REMEMBERED_BROADCASTS {
+            broadcasts.first_sighting(&Uuid::new_v4());
+        }
+
+        assert!(broadcasts.first_sighting(&first_broadcast_id));
+    }
+}
@@ -1,25 +1,134 @@
 use {Packet, PacketKind, Error};
-use network;
+use network::encryption::{self, Encryption};
+use network::{self, Transport};
 use protocol;
 
 use proto;
-use mio::net::*;
+use mio;
+
+use std::net::Shutdown;
+use std::io::{self, Cursor};
+
+// The middleware that all packets pass through.
+define_middleware_pipeline!(Pipeline {
+    compression: proto::wire::middleware::Compression,
+    encryption: Encryption
+});
 
 #[derive(Debug)]
 pub struct Connection
 {
     pub token: ::mio::Token,
-    pub protocol: proto::wire::stream::Connection<Packet, TcpStream>,
+    pub protocol: proto::wire::stream::Connection<Packet, Box<dyn Transport>, Pipeline>,
+    /// The user agent of the remote end, once the handshake has told us.
+    pub user_agent: Option<protocol::UserAgent>,
+    /// The features the remote end supports, once the handshake has told us.
+    pub features: Vec<String>,
 }
 
 impl Connection
 {
+    pub fn new(token: ::mio::Token, stream: Box<dyn Transport>) -> Self {
+        Connection {
+            token,
+            protocol: proto::wire::stream::Connection::new(stream, Pipeline {
+                compression: proto::wire::middleware::Compression::Disabled,
+                encryption: Encryption::Disabled,
+            }),
+            user_agent: None,
+            features: Vec::new(),
+        }
+    }
+
+    /// Moves the connection over to a new token.
+    pub fn reregister(&mut self, poll: &mio::Poll, token: mio::Token) -> Result<(), Error> {
+        poll.reregister(&*self.protocol.stream, token, network::local::node::connection_interest(), mio::PollOpt::edge())?;
+        self.token = token;
+
+        Ok(())
+    }
+
+    /// The address of the other end, if it can be reached over the network.
+    pub fn peer_addr(&self) -> Option<::std::net::SocketAddr> {
+        self.protocol.stream.peer_addr()
+    }
+
+    /// Reads any data that has arrived.
+    ///
+    /// The transport panics if reading fails, so the socket is checked
+    /// for errors first.
     pub fn process_incoming_data(&mut self) -> Result<(), Error> {
-        Ok(self.protocol.process_incoming_data()?)
+        match self.protocol.stream.peek(&mut [0]) {
+            Ok(..) => Ok(self.protocol.process_incoming_data()?),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(()),
+            Err(e) => Err(e.into()),
+        }
     }
 
     pub fn receive_packet(&mut self) -> Result<Option<Packet>, Error> {
-        Ok(self.protocol.receive_packet()?)
+        self.process_incoming_data()?;
+        self.next_packet()
+    }
+
+    /// Receives every packet that has fully arrived.
+    ///
+    /// The socket is only read from once, as reading from a drained
+    /// non-blocking socket is an error.
+    pub fn receive_packets(&mut self) -> Result<Vec<Packet>, Error> {
+        self.process_incoming_data()?;
+
+        let mut packets = Vec::new();
+        while let Some(packet) = self.next_packet()? {
+            packets.push(packet);
+        }
+
+        Ok(packets)
+    }
+
+    /// Takes the next packet that has already been read, if any.
+    fn next_packet(&mut self) -> Result<Option<Packet>, Error> {
+        use proto::Parcel;
+        use proto::wire::stream::Transport;
+        use proto::wire::middleware::Pipeline;
+
+        match self.protocol.transport.receive_raw_packet()? {
+            Some(raw_packet) => {
+                let data = self.protocol.middleware.decode_data(raw_packet)?;
+
+                match Packet::read(&mut Cursor::new(&data)) {
+                    Ok(packet) => Ok(Some(packet)),
+                    Err(e) => Err(self.refuse_unreadable_handshake(&data).unwrap_or_else(|| e.into())),
+                }
+            },
+            None => Ok(None),
+        }
+    }
+
+    /// Tells a node that speaks an incompatible version of the protocol,
+    /// and so sent a handshake we can't read, why we are hanging up.
+    fn refuse_unreadable_handshake(&mut self, data: &[u8]) -> Option<Error> {
+        if self.is_encrypted() { return None }
+
+        let ours = ::user_agent();
+        let theirs = protocol::read_user_agent(data)?;
+        if theirs.is_compatible(&ours) { return None }
+
+        // The remote may have already hung up on us, which is fine.
+        self.terminate(format!("protocol version {} is not compatible with {}",
+                               theirs.protocol_major, ours.protocol_major)).ok();
+        Some(Error::IncompatibleProtocol { ours, theirs })
+    }
+
+    /// Checks if the remote has closed its end of the connection.
+    ///
+    /// A remote closing the connection only shows up as the socket
+    /// becoming readable with nothing left to read.
+    pub fn has_hung_up(&self) -> bool {
+        match self.protocol.stream.peek(&mut [0]) {
+            Ok(0) => true,
+            Ok(_) => false,
+            Err(e) => e.kind() != io::ErrorKind::WouldBlock,
+        }
     }
 
     pub fn send_packet(&mut self, packet: &Packet) -> Result<(), Error> {
@@ -27,8 +136,22 @@ impl Connection
         Ok(())
     }
 
+    /// Encrypts all packets from now on.
+    pub fn enable_encryption(&mut self, keys: encryption::Keys) {
+        self.protocol.middleware.encryption = Encryption::enabled(keys);
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.protocol.middleware.encryption.is_enabled()
+    }
+
+    /// Checks if both ends of the connection support a protocol feature.
+    pub fn supports(&self, feature: &str) -> bool {
+        protocol::feature::is_supported(feature) && self.features.iter().any(|f| f == feature)
+    }
+
     /// Terminate the connection with a reason.
-    pub fn terminate<S>(mut self, reason: S) -> Result<(), Error>
+    pub fn terminate<S>(&mut self, reason: S) -> Result<(), Error>
         where S: Into<String> {
         self.protocol.send_packet(&Packet {
             path: network::Path::empty(),
@@ -37,9 +160,8 @@ impl Connection
             }),
         })?;
 
-        // Connection is dropped when connection goes out of scope
+        self.protocol.stream.shutdown(Shutdown::Both)?;
 
         Ok(())
     }
 }
-
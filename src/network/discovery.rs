@@ -0,0 +1,262 @@
+//! Finding other nodes on the local network segment.
+//!
+//! A node looking for a network multicasts a `DiscoveryQuery` to the
+//! discovery group. Every node that is part of a network and listening on
+//! the group answers it directly with an `Announcement`, which tells the
+//! querying node where to connect.
+
+use Config;
+use network::local;
+use protocol::{self, Datagram};
+
+use net2::UdpBuilder;
+use net2::unix::UnixUdpBuilderExt;
+use proto::Parcel;
+
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How often to ask again while looking for a network.
+const QUERY_INTERVAL_MS: u64 = 500;
+/// How long to sleep between checks for announcements.
+const POLL_INTERVAL_MS: u64 = 10;
+/// The largest datagram we expect to receive.
+const MAX_DATAGRAM_SIZE: usize = 1024;
+
+/// The sockets used to find other nodes on the local network segment.
+#[derive(Debug)]
+pub struct Discovery
+{
+    /// The multicast group we listen for queries on.
+    group: SocketAddr,
+    /// Receives queries sent to the group, shared with every other node on the host.
+    listener: UdpSocket,
+    /// Sends queries to the group, and receives the announcements sent back.
+    sender: UdpSocket,
+}
+
+impl Discovery
+{
+    /// Joins the discovery group from the configuration.
+    ///
+    /// Queries are sent out of the interface we listen for nodes on, so
+    /// binding to a loopback address keeps discovery on the host.
+    pub fn new(group: SocketAddr, config: &Config) -> io::Result<Self> {
+        let group_ip = match group.ip() {
+            IpAddr::V4(ip) if ip.is_multicast() => ip,
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, "the discovery group must be an IPv4 multicast address")),
+        };
+
+        let interface = match config.bind_address {
+            IpAddr::V4(ip) => ip,
+            IpAddr::V6(..) => Ipv4Addr::new(0, 0, 0, 0),
+        };
+
+        // Every node on the host listens on the same port.
+        let listener = UdpBuilder::new_v4()?
+            .reuse_address(true)?
+            .reuse_port(true)?
+            .bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), group.port()))?;
+        listener.join_multicast_v4(&group_ip, &interface)?;
+        listener.set_nonblocking(true)?;
+
+        let sender = UdpSocket::bind(SocketAddr::new(IpAddr::V4(interface), 0))?;
+        sender.set_multicast_loop_v4(true)?;
+        sender.set_nonblocking(true)?;
+
+        Ok(Discovery {
+            group,
+            listener,
+            sender,
+        })
+    }
+
+    /// Joins the discovery group if discovery is enabled.
+    ///
+    /// Nodes still work without discovery, so failing to join is only a warning.
+    pub fn start(config: &Config) -> Option<Self> {
+        let group = config.discovery_group?;
+
+        match Discovery::new(group, config) {
+            Ok(discovery) => Some(discovery),
+            Err(e) => {
+                println!("warning: could not join the discovery group {}, other nodes won't be able to find us: {}", group, e);
+                None
+            },
+        }
+    }
+
+    /// Looks for a node that is part of a network, waiting up to some time
+    /// for one to answer.
+    ///
+    /// Returns the address to connect to the node on.
+    pub fn find_network(&self, timeout: Duration) -> io::Result<Option<SocketAddr>> {
+        let started_at = Instant::now();
+        let mut last_queried_at = None;
+
+        while started_at.elapsed() < timeout {
+            if last_queried_at.is_none_or(|at: Instant| at.elapsed() >= Duration::from_millis(QUERY_INTERVAL_MS)) {
+                let query = Datagram::DiscoveryQuery(protocol::DiscoveryQuery { user_agent: ::user_agent() });
+                send(&self.sender, &query, &self.group)?;
+                last_queried_at = Some(Instant::now());
+            }
+
+            while let Some((datagram, source)) = receive(&self.sender)? {
+                if let Datagram::Announcement(announcement) = datagram {
+                    if !announcement.user_agent.is_compatible(&::user_agent()) {
+                        println!("ignoring node {} at {} as it speaks an incompatible protocol", announcement.uuid, source);
+                        continue;
+                    }
+
+                    if let Some(address) = announced_address(&announcement, &source) {
+                        println!("found node {} at {}", announcement.name.as_ref().map_or(announcement.uuid.to_string(), |name| name.clone()), address);
+                        return Ok(Some(address));
+                    }
+                }
+            }
+
+            thread::sleep(Duration::from_millis(POLL_INTERVAL_MS));
+        }
+
+        Ok(None)
+    }
+
+    /// Tells every node that is looking for a network about us.
+    pub fn answer_queries(&self, node: &local::connected::Node) -> io::Result<()> {
+        while let Some((datagram, source)) = receive(&self.listener)? {
+            if let Datagram::DiscoveryQuery(..) = datagram {
+                // Nodes can only join us if we are listening.
+                let port = match node.listener.as_ref().and_then(|listener| listener.local_addr().ok()) {
+                    Some(address) => address.port(),
+                    None => continue,
+                };
+
+                let local_node = node.network.get(&node.uuid).unwrap();
+
+                let announcement = Datagram::Announcement(protocol::Announcement {
+                    user_agent: ::user_agent(),
+                    uuid: node.uuid,
+                    name: local_node.name.clone(),
+                    address: local_node.address.map(|address| address.to_string()),
+                    port,
+                });
+
+                // The querying node may have given up already.
+                if let Err(e) = send(&self.sender, &announcement, &source) {
+                    println!("could not answer discovery query from {}: {}", source, e);
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The address to connect to an announced node on.
+///
+/// A node that doesn't know its own address is reached on the address its
+/// announcement came from.
+pub fn announced_address(announcement: &protocol::Announcement, source: &SocketAddr) -> Option<SocketAddr> {
+    match announcement.address {
+        Some(ref address) => address.parse().ok(),
+        None => Some(SocketAddr::new(source.ip(), announcement.port)),
+    }
+}
+
+fn send(socket: &UdpSocket, datagram: &Datagram, to: &SocketAddr) -> io::Result<()> {
+    let bytes = datagram.raw_bytes().map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{:?}", e)))?;
+    socket.send_to(&bytes, to)?;
+    Ok(())
+}
+
+/// Receives the next datagram, skipping anything that isn't from a node.
+fn receive(socket: &UdpSocket) -> io::Result<Option<(Datagram, SocketAddr)>> {
+    let mut buffer = [0; MAX_DATAGRAM_SIZE];
+
+    loop {
+        match socket.recv_from(&mut buffer) {
+            Ok((size, source)) => match Datagram::from_raw_bytes(&buffer[..size]) {
+                Ok(datagram) => return Ok(Some((datagram, source))),
+                Err(..) => continue,
+            },
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(None),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test
+{
+    pub use super::*;
+    pub use uuid::Uuid;
+
+    fn announcement(address: Option<&str>) -> protocol::Announcement {
+        protocol::Announcement {
+            user_agent: ::user_agent(),
+            uuid: Uuid::parse_str("123e4567-e89b-12d3-a456-426655440000").unwrap(),
+            name: None,
+            address: address.map(|address| address.to_owned()),
+            port: 4000,
+        }
+    }
+
+    #[test]
+    fn the_announced_address_is_used_if_there_is_one() {
+        let source = "10.0.0.5:6000".parse().unwrap();
+
+        assert_eq!(announced_address(&announcement(Some("203.0.113.7:53371")), &source),
+                   Some("203.0.113.7:53371".parse().unwrap()));
+    }
+
+    #[test]
+    fn nodes_without_an_address_are_reached_where_they_announced_from() {
+        let source = "10.0.0.5:6000".parse().unwrap();
+
+        assert_eq!(announced_address(&announcement(None), &source), Some("10.0.0.5:4000".parse().unwrap()));
+    }
+
+    #[test]
+    fn nodes_that_are_part_of_a_network_answer_queries() {
+        // A port of our own, so other tests and nodes on the host don't answer.
+        let port = UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let group = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(239, 255, 53, 71)), port);
+
+        let mut config = Config::default();
+        config.bind_address = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        config.port = Some(0);
+
+        let listener = local::tcp::listen(&config).unwrap();
+        let listening_on = listener.local_addr().unwrap();
+
+        let uuid = Uuid::new_v4();
+        let node = local::connected::Node::new(uuid, Some(listener), ::network::Network::new(uuid, config.clock.now()), &config);
+
+        let answering = Discovery::new(group, &config).unwrap();
+        let querying = Discovery::new(group, &config).unwrap();
+
+        send(&querying.sender, &Datagram::DiscoveryQuery(protocol::DiscoveryQuery { user_agent: ::user_agent() }), &group).unwrap();
+
+        let started_at = Instant::now();
+        let announcement = loop {
+            answering.answer_queries(&node).unwrap();
+
+            if let Some((Datagram::Announcement(announcement), source)) = receive(&querying.sender).unwrap() {
+                break (announcement, source);
+            }
+
+            assert!(started_at.elapsed() < Duration::from_secs(5), "the query was never answered");
+            thread::sleep(Duration::from_millis(POLL_INTERVAL_MS));
+        };
+
+        assert_eq!(announcement.0.uuid, uuid);
+        assert_eq!(announced_address(&announcement.0, &announcement.1).map(|address| address.port()), Some(listening_on.port()));
+    }
+
+    #[test]
+    fn stray_datagrams_are_ignored() {
+        assert!(Datagram::from_raw_bytes(&[0xff, 0xff, 0xff, 0xff]).is_err());
+    }
+}
@@ -0,0 +1,18 @@
+/// Counts of the packets that we have had to drop, by why we dropped them.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Drops
+{
+    /// Packets that we couldn't pass on towards their destination.
+    pub unroutable: u64,
+    /// Packets that had already been passed on too many times.
+    pub hop_limit_exceeded: u64,
+    /// Packets that didn't make sense, such as ones with no sender.
+    pub malformed: u64,
+}
+
+impl Drops
+{
+    pub fn total(&self) -> u64 {
+        self.unroutable + self.hop_limit_exceeded + self.malformed
+    }
+}
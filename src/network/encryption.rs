@@ -0,0 +1,290 @@
+//! Encryption of node-to-node connections.
+//!
+//! Both ends of a connection send an ephemeral X25519 public key in the
+//! `Ping` and `Pong` handshake packets. The shared secret is then used to
+//! derive a separate key for each direction, and every packet afterwards
+//! is sealed with ChaCha20-Poly1305.
+//!
+//! If the network has a pre-shared key, it is mixed into the derived keys,
+//! so that a man in the middle without the key can't read the traffic.
+
+use proto;
+use proto::wire::Middleware;
+
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use chacha20poly1305::aead::{Aead, KeyInit};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use rand::{self, Rng};
+use x25519_dalek;
+use byteorder::{BigEndian, ByteOrder};
+
+use std::fmt;
+
+/// The size of a key, in bytes.
+const KEY_SIZE: usize = 32;
+
+/// Labels for deriving a key for each direction of the connection.
+const INITIATOR_LABEL: &[u8] = b"parapet initiator to responder";
+const RESPONDER_LABEL: &[u8] = b"parapet responder to initiator";
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Which end of the connection we are.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Role
+{
+    /// We opened the connection and sent the `Ping`.
+    Initiator,
+    /// We accepted the connection and sent the `Pong`.
+    Responder,
+}
+
+/// One half of an ephemeral key exchange.
+#[derive(Clone)]
+pub struct KeyExchange
+{
+    secret: [u8; KEY_SIZE],
+    public_key: [u8; KEY_SIZE],
+}
+
+/// The keys for both directions of a connection.
+#[derive(Clone)]
+pub struct Keys
+{
+    send: [u8; KEY_SIZE],
+    receive: [u8; KEY_SIZE],
+}
+
+/// A middleware that encrypts and authenticates packets.
+#[derive(Clone, Debug)]
+pub enum Encryption
+{
+    /// Packets are sent as plaintext.
+    Disabled,
+    /// Packets are encrypted.
+    Enabled {
+        keys: Keys,
+        /// The number of packets we have sent.
+        sent: u64,
+        /// The number of packets we have received.
+        received: u64,
+    },
+}
+
+impl KeyExchange
+{
+    /// Starts a new key exchange with a random secret.
+    pub fn new() -> Self {
+        let mut secret = [0; KEY_SIZE];
+        rand::thread_rng().fill_bytes(&mut secret);
+
+        KeyExchange {
+            secret,
+            public_key: x25519_dalek::x25519(secret, x25519_dalek::X25519_BASEPOINT_BYTES),
+        }
+    }
+
+    /// The public key to send to the other end.
+    pub fn public_key(&self) -> Vec<u8> {
+        self.public_key.to_vec()
+    }
+
+    /// Completes the exchange using the public key from the other end.
+    ///
+    /// Returns `None` if the public key is malformed.
+    pub fn complete(&self,
+                    their_public_key: &[u8],
+                    network_key: Option<&str>,
+                    role: Role) -> Option<Keys> {
+        let shared_secret = self.shared_secret(their_public_key)?;
+
+        let salt = network_key.unwrap_or("").as_bytes();
+        let initiator_key = derive_key(salt, &shared_secret, INITIATOR_LABEL);
+        let responder_key = derive_key(salt, &shared_secret, RESPONDER_LABEL);
+
+        Some(match role {
+            Role::Initiator => Keys { send: initiator_key, receive: responder_key },
+            Role::Responder => Keys { send: responder_key, receive: initiator_key },
+        })
+    }
+
+    /// Agrees on a secret with the owner of another public key.
+    ///
+    /// Returns `None` if the public key is malformed.
+    pub fn shared_secret(&self, their_public_key: &[u8]) -> Option<[u8; KEY_SIZE]> {
+        diffie_hellman(self.secret, their_public_key)
+    }
+}
+
+impl Default for KeyExchange
+{
+    fn default() -> Self { KeyExchange::new() }
+}
+
+impl Encryption
+{
+    pub fn enabled(keys: Keys) -> Self {
+        Encryption::Enabled { keys, sent: 0, received: 0 }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        matches!(*self, Encryption::Enabled { .. })
+    }
+}
+
+impl Middleware for Encryption
+{
+    fn encode_data(&mut self, data: Vec<u8>) -> Result<Vec<u8>, proto::Error> {
+        match *self {
+            Encryption::Disabled => Ok(data),
+            Encryption::Enabled { ref keys, ref mut sent, .. } => {
+                let cipher = ChaCha20Poly1305::new(Key::from_slice(&keys.send));
+                let ciphertext = cipher.encrypt(&nonce(*sent), data.as_slice())
+                    .map_err(|_| proto::Error::from("could not encrypt packet"))?;
+
+                *sent += 1;
+                Ok(ciphertext)
+            },
+        }
+    }
+
+    fn decode_data(&mut self, data: Vec<u8>) -> Result<Vec<u8>, proto::Error> {
+        match *self {
+            Encryption::Disabled => Ok(data),
+            Encryption::Enabled { ref keys, ref mut received, .. } => {
+                let cipher = ChaCha20Poly1305::new(Key::from_slice(&keys.receive));
+                let plaintext = cipher.decrypt(&nonce(*received), data.as_slice())
+                    .map_err(|_| proto::Error::from("could not decrypt packet"))?;
+
+                *received += 1;
+                Ok(plaintext)
+            },
+        }
+    }
+}
+
+impl fmt::Debug for KeyExchange
+{
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        // Never print the secret.
+        fmt.debug_struct("KeyExchange").field("public_key", &self.public_key).finish()
+    }
+}
+
+impl fmt::Debug for Keys
+{
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        // Never print the keys.
+        fmt.write_str("Keys { .. }")
+    }
+}
+
+/// Agrees on a secret between a secret key and somebody else's public key.
+///
+/// Returns `None` if the public key is malformed.
+pub fn diffie_hellman(secret: [u8; KEY_SIZE], their_public_key: &[u8]) -> Option<[u8; KEY_SIZE]> {
+    if their_public_key.len() != KEY_SIZE { return None }
+
+    let mut their_public_key_bytes = [0; KEY_SIZE];
+    their_public_key_bytes.copy_from_slice(their_public_key);
+
+    let shared_secret = x25519_dalek::x25519(secret, their_public_key_bytes);
+
+    // A low order point gives an all zero secret that an attacker could predict.
+    if shared_secret.iter().all(|&b| b == 0) { return None }
+
+    Some(shared_secret)
+}
+
+/// Derives a key from a shared secret.
+fn derive_key(salt: &[u8], shared_secret: &[u8], label: &[u8]) -> [u8; KEY_SIZE] {
+    let mut extract = <HmacSha256 as Mac>::new_from_slice(salt).expect("HMAC accepts keys of any size");
+    extract.update(shared_secret);
+    let pseudorandom_key = extract.finalize().into_bytes();
+
+    let mut expand = <HmacSha256 as Mac>::new_from_slice(&pseudorandom_key).expect("HMAC accepts keys of any size");
+    expand.update(label);
+
+    let mut key = [0; KEY_SIZE];
+    key.copy_from_slice(&expand.finalize().into_bytes());
+    key
+}
+
+/// Builds the nonce for a packet.
+///
+/// The stream is reliable and ordered, so both ends can count packets
+/// rather than sending the nonce along with each one.
+fn nonce(packet_number: u64) -> Nonce {
+    let mut nonce = [0; 12];
+    BigEndian::write_u64(&mut nonce[4..], packet_number);
+
+    *Nonce::from_slice(&nonce)
+}
+
+#[cfg(test)]
+mod test
+{
+    pub use super::*;
+    pub use proto::wire::Middleware;
+
+    fn setup(initiator_network_key: Option<&str>, responder_network_key: Option<&str>) -> (Encryption, Encryption) {
+        let initiator = KeyExchange::new();
+        let responder = KeyExchange::new();
+
+        let initiator_keys = initiator.complete(&responder.public_key(), initiator_network_key, Role::Initiator).unwrap();
+        let responder_keys = responder.complete(&initiator.public_key(), responder_network_key, Role::Responder).unwrap();
+
+        (Encryption::enabled(initiator_keys), Encryption::enabled(responder_keys))
+    }
+
+    #[test]
+    fn data_can_be_sent_in_both_directions() {
+        let (mut initiator, mut responder) = setup(None, None);
+
+        for _ in 0..3 {
+            let ciphertext = initiator.encode_data(b"hello".to_vec()).unwrap();
+            assert!(ciphertext != b"hello".to_vec());
+            assert_eq!(responder.decode_data(ciphertext).unwrap(), b"hello".to_vec());
+
+            let ciphertext = responder.encode_data(b"world".to_vec()).unwrap();
+            assert_eq!(initiator.decode_data(ciphertext).unwrap(), b"world".to_vec());
+        }
+    }
+
+    #[test]
+    fn tampered_data_is_rejected() {
+        let (mut initiator, mut responder) = setup(None, None);
+
+        let mut ciphertext = initiator.encode_data(b"hello".to_vec()).unwrap();
+        ciphertext[0] ^= 1;
+
+        assert!(responder.decode_data(ciphertext).is_err());
+    }
+
+    #[test]
+    fn replayed_data_is_rejected() {
+        let (mut initiator, mut responder) = setup(None, None);
+
+        let ciphertext = initiator.encode_data(b"hello".to_vec()).unwrap();
+        responder.decode_data(ciphertext.clone()).unwrap();
+
+        assert!(responder.decode_data(ciphertext).is_err());
+    }
+
+    #[test]
+    fn mismatched_network_keys_cannot_communicate() {
+        let (mut initiator, mut responder) = setup(Some("hunter2"), Some("hunter3"));
+
+        let ciphertext = initiator.encode_data(b"hello".to_vec()).unwrap();
+        assert!(responder.decode_data(ciphertext).is_err());
+    }
+
+    #[test]
+    fn malformed_public_keys_are_rejected() {
+        let key_exchange = KeyExchange::new();
+
+        assert!(key_exchange.complete(&[1, 2, 3], None, Role::Initiator).is_none());
+        assert!(key_exchange.complete(&[0; KEY_SIZE], None, Role::Initiator).is_none());
+    }
+}
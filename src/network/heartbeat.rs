@@ -0,0 +1,125 @@
+use {Config, Clock};
+
+use byteorder::{BigEndian, ByteOrder};
+
+use std::time::{SystemTime, Duration, UNIX_EPOCH};
+
+/// How much of each new latency measurement to mix into the smoothed latency.
+///
+/// This is the same as TCP uses for smoothing round-trip times.
+const LATENCY_SMOOTHING_FACTOR: f64 = 0.125;
+
+/// Keeps track of when to ping our directly connected nodes.
+#[derive(Clone, Debug)]
+pub struct Heartbeat
+{
+    /// How often heartbeats are sent.
+    pub interval: Duration,
+    /// How long a node can be silent before it is considered dead.
+    pub timeout: Duration,
+
+    last_sent_at: SystemTime,
+    clock: Clock,
+}
+
+impl Heartbeat
+{
+    pub fn new(config: &Config) -> Self {
+        Heartbeat {
+            interval: config.heartbeat_interval,
+            timeout: config.heartbeat_timeout,
+            last_sent_at: config.clock.now(),
+            clock: config.clock.clone(),
+        }
+    }
+
+    /// Checks if it is time to send another round of heartbeats.
+    ///
+    /// Resets the timer if so.
+    pub fn is_due(&mut self) -> bool {
+        let now = self.clock.now();
+
+        if elapsed_between(self.last_sent_at, now) >= self.interval {
+            self.last_sent_at = now;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Checks if a node that was last seen at some time is dead.
+    pub fn has_timed_out(&self, last_seen: SystemTime) -> bool {
+        elapsed_between(last_seen, self.clock.now()) >= self.timeout
+    }
+}
+
+/// Creates the data to send in a heartbeat ping, which is the time it was sent.
+///
+/// The data is echoed back in the pong, so we can tell how long the round trip took.
+pub fn ping_data(sent_at: SystemTime) -> Vec<u8> {
+    let mut data = vec![0; 8];
+    BigEndian::write_u64(&mut data, as_micros(elapsed_between(UNIX_EPOCH, sent_at)));
+    data
+}
+
+/// Gets the round-trip latency from the data echoed back in a heartbeat pong.
+///
+/// Returns `None` if the pong wasn't for a heartbeat.
+pub fn round_trip_latency(data: &[u8], received_at: SystemTime) -> Option<Duration> {
+    if data.len() != 8 { return None }
+
+    let sent_at = UNIX_EPOCH + Duration::from_micros(BigEndian::read_u64(data));
+    Some(elapsed_between(sent_at, received_at))
+}
+
+/// Mixes a new latency measurement into the smoothed latency.
+pub fn smooth_latency(smoothed: Option<Duration>, measured: Duration) -> Duration {
+    match smoothed {
+        Some(smoothed) => {
+            let smoothed_micros = as_micros(smoothed) as f64;
+            let measured_micros = as_micros(measured) as f64;
+
+            Duration::from_micros((smoothed_micros + (measured_micros - smoothed_micros) * LATENCY_SMOOTHING_FACTOR) as u64)
+        },
+        None => measured,
+    }
+}
+
+fn as_micros(duration: Duration) -> u64 {
+    duration.as_secs() * 1_000_000 + duration.subsec_micros() as u64
+}
+
+/// Gets the time between two instants, treating clock skew as no time at all.
+fn elapsed_between(earlier: SystemTime, later: SystemTime) -> Duration {
+    later.duration_since(earlier).unwrap_or(Duration::from_secs(0))
+}
+
+#[cfg(test)]
+mod test
+{
+    pub use super::*;
+
+    #[test]
+    fn latency_is_measured_from_the_echoed_ping_data() {
+        let sent_at = UNIX_EPOCH + Duration::from_secs(1_500_000_000);
+        let data = ping_data(sent_at);
+
+        assert_eq!(round_trip_latency(&data, sent_at + Duration::from_millis(12)), Some(Duration::from_millis(12)));
+    }
+
+    #[test]
+    fn pings_without_a_timestamp_are_not_measured() {
+        assert_eq!(round_trip_latency(&[], SystemTime::now()), None);
+    }
+
+    #[test]
+    fn the_first_measurement_is_taken_as_is() {
+        assert_eq!(smooth_latency(None, Duration::from_millis(40)), Duration::from_millis(40));
+    }
+
+    #[test]
+    fn spikes_in_latency_are_smoothed_out() {
+        let smoothed = smooth_latency(Some(Duration::from_millis(10)), Duration::from_millis(90));
+        assert_eq!(smoothed, Duration::from_millis(20));
+    }
+}
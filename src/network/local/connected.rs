@@ -1,61 +1,359 @@
-use {Network, Packet, PacketKind, Error};
-use network::{remote, PendingState, Notify};
+use {Network, Packet, PacketKind, Error, Config};
+use network::{local, remote, Connection, PendingState, Notify, Heartbeat, Broadcasts, Drops, Reliable};
 use {network, protocol, ci};
 
 use uuid::Uuid;
 use mio::net::*;
 
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+/// The most node addresses to remember for rejoining the network.
+const MAX_KNOWN_ADDRESSES: usize = 32;
+/// How much the latency of an edge must change by, as a fraction, before
+/// everybody is told about it.
+const LATENCY_CHANGE_THRESHOLD: f32 = 0.25;
+/// The smallest change in the latency of an edge that everybody is told about,
+/// in milliseconds, so that jitter on fast links doesn't flood the network.
+const MIN_LATENCY_CHANGE_MS: f32 = 0.5;
+
 /// A local node that is connected to the network.
 pub struct Node
 {
     pub uuid: Uuid,
     pub listener: Option<TcpListener>,
+    /// Where nodes and clients on the same host can connect to us.
+    pub unix_listener: Option<local::unix::Listener>,
 
     /// The network we are apart of.
     pub network: Network,
 
+    pub config: Config,
     pub notify: Notify,
+    pub heartbeat: Heartbeat,
     pub builder: ci::Builder,
     pub dispatcher: ci::Dispatcher,
+    /// Nodes that we couldn't open a direct connection to.
+    pub unreachable_nodes: HashSet<Uuid>,
+    /// The addresses of nodes that have been in the network, most recent last.
+    ///
+    /// If we lose our connection to the network, we try to rejoin through these.
+    pub known_addresses: Vec<SocketAddr>,
+    /// Our attempt to rejoin the network, if we have lost our connection to it.
+    pub rejoin: Option<local::rejoin::Rejoin>,
+    /// The broadcasts we have already handled.
+    pub broadcasts: Broadcasts,
+    /// The packets we have had to drop.
+    pub drops: Drops,
+    /// The packets we have sent and received reliably.
+    pub reliable: Reliable,
 }
 
 impl Node
 {
+    pub fn new(uuid: Uuid,
+               listener: Option<TcpListener>,
+               mut network: Network,
+               config: &Config) -> Self {
+        if let Some(local_node) = network.get_mut(&uuid) {
+            local_node.name = config.identity.name.clone();
+            local_node.labels = config.labels.clone();
+            local_node.address = listener.as_ref().and_then(|listener| local::tcp::advertised_address(config, listener));
+            local_node.public_key = config.identity.keypair.as_ref().map(|keypair| keypair.public_key());
+        }
+
+        Node {
+            uuid,
+            listener,
+            unix_listener: None,
+            network,
+            config: config.clone(),
+            notify: Notify::new(&config.clock),
+            heartbeat: Heartbeat::new(config),
+            builder: ci::Builder::new(),
+            dispatcher: ci::Dispatcher::new(&config.random),
+            unreachable_nodes: HashSet::new(),
+            known_addresses: Vec::new(),
+            rejoin: None,
+            broadcasts: Broadcasts::new(),
+            drops: Drops::default(),
+            reliable: Reliable::new(config),
+        }
+    }
+
     pub fn send_packet_to(&mut self, to: &Uuid, packet: &PacketKind) -> Result<(), Error> {
-        assert!(to != &self.uuid, "can't send a packet to yourself");
+        // There is no path from a node to itself.
+        if to == &self.uuid { return Err(Error::Unroutable { destination: *to }) }
+
+        let path = match self.network.route(&self.uuid, to) {
+            Some(path) => path,
+            None => return Err(Error::Unroutable { destination: *to }),
+        };
 
         let packet = Packet {
-            path: self.network.route(&self.uuid, to),
+            path,
             kind: packet.clone(),
         };
 
-        let first_hop_uuid = packet.path.next_hop(&self.uuid).expect("cannot find thing");
-        let first_hop = self.network.get_mut(&first_hop_uuid).expect("can't find first hop from uuid");
-        first_hop.connection.as_mut().unwrap().send_packet(&packet)
+        let first_hop_uuid = packet.path.next_hop(&self.uuid).unwrap();
+        match self.network.get_mut(&first_hop_uuid).and_then(|first_hop| first_hop.connection.as_mut()) {
+            Some(connection) => connection.send_packet(&packet),
+            None => Err(Error::Unroutable { destination: *to }),
+        }
     }
 
-    pub fn broadcast_packet(&mut self, packet: &PacketKind) -> Result<(), Error> {
-        let destination_uuids: Vec<_> = self.network.nodes()
-            .filter(|node| node.uuid != self.uuid)
-            .map(|node| node.uuid.clone())
-            .collect();
+    /// Sends a packet that mustn't be lost to another node.
+    ///
+    /// The packet is given the next sequence number, and is resent until
+    /// the node acknowledges it or leaves the network.
+    pub fn send_packet_reliably<F>(&mut self, to: &Uuid, build_packet: F) -> Result<(), Error>
+        where F: FnOnce(u64) -> PacketKind {
+        let sequence = self.reliable.next_sequence();
+        let packet = build_packet(sequence);
+
+        self.reliable.sent(sequence, *to, packet.clone());
 
-        for destination_uuid in destination_uuids {
-            self.send_packet_to(&destination_uuid, packet)?;
+        // It will be resent later if this fails.
+        if let Err(e) = self.send_packet_to(to, &packet) {
+            println!("could not send packet to node {}, will try again: {:?}", self.network.describe(to), e);
         }
 
         Ok(())
     }
 
+    /// Acknowledges a packet that was sent to us reliably.
+    ///
+    /// Returns `false` if we have already received the packet, in which
+    /// case it shouldn't be handled again.
+    pub fn acknowledge(&mut self, from: &Uuid, sequence: u64) -> Result<bool, Error> {
+        // Our last acknowledgement may have been lost, so always send another.
+        self.send_packet_to(from, &PacketKind::Acknowledgement(protocol::Acknowledgement {
+            sequence,
+        }))?;
+
+        Ok(self.reliable.first_receipt(from, sequence))
+    }
+
+    /// Sends a packet over our direct connection to a node, rather than
+    /// over the fastest path to it.
+    pub fn send_packet_directly(&mut self, to: &Uuid, packet: &PacketKind) -> Result<(), Error> {
+        let packet = Packet {
+            path: network::Path::from_to(self.uuid, *to),
+            kind: packet.clone(),
+        };
+
+        let node = self.network.get_mut(to).expect("can't find node from uuid");
+        node.connection.as_mut().expect("not directly connected to node").send_packet(&packet)
+    }
+
+    /// Sends a packet to every other node.
+    ///
+    /// Every node handles the packet once, however many times it arrives.
+    pub fn broadcast_packet(&mut self, packet: &PacketKind) -> Result<(), Error> {
+        let packet = Packet {
+            path: network::Path::broadcast(self.uuid, self.config.random.uuid()),
+            kind: packet.clone(),
+        };
+
+        self.broadcasts.first_sighting(packet.path.broadcast_id.as_ref().unwrap());
+        self.pass_on_broadcast(&packet, None);
+
+        Ok(())
+    }
+
+    /// Deals with a packet that we received from a directly connected node.
+    ///
+    /// A bad packet from one node shouldn't bring the rest of the network
+    /// down with it, so packets that can't be handled are only logged.
+    pub fn receive_packet(&mut self, from: &Uuid, packet: Packet) {
+        if packet.path.hops.is_empty() {
+            println!("dropping packet with no sender from node {}", self.network.describe(from));
+            self.drops.malformed += 1;
+            return;
+        }
+
+        if packet.origin() == self.uuid {
+            // Our own broadcasts find their way back to us around loops in the mesh.
+            let is_echo = packet.path.is_broadcast() &&
+                !self.broadcasts.first_sighting(packet.path.broadcast_id.as_ref().unwrap());
+
+            if !is_echo {
+                println!("dropping packet claiming to be from us from node {}", self.network.describe(from));
+                self.drops.malformed += 1;
+            }
+            return;
+        }
+
+        let result = if packet.path.is_broadcast() {
+            if self.receive_broadcast(from, &packet) {
+                local::handle::packet(self, &packet)
+            } else {
+                Ok(())
+            }
+        } else if packet.is_recipient(&self.uuid) {
+            local::handle::packet(self, &packet)
+        } else {
+            self.forward_packet(from, packet.clone())
+        };
+
+        if let Err(e) = result {
+            println!("could not handle packet from node {}: {:?}", self.network.describe(&packet.origin()), e);
+        }
+    }
+
+    /// Passes on a packet that is for another node to the next node on its path.
+    ///
+    /// Packets that can't be passed on are dropped, and their sender is told why.
+    pub fn forward_packet(&mut self, from: &Uuid, mut packet: Packet) -> Result<(), Error> {
+        if packet.path.hop_limit == 0 {
+            self.drops.hop_limit_exceeded += 1;
+            return self.report_unreachable(from, &packet, "the packet was passed on too many times");
+        }
+
+        packet.path.hop_limit -= 1;
+
+        let next_hop_uuid = match packet.path.next_hop(&self.uuid) {
+            Some(next_hop_uuid) => next_hop_uuid,
+            None => {
+                self.drops.unroutable += 1;
+                return self.report_unreachable(from, &packet, "the packet was sent to a node that isn't on its path");
+            },
+        };
+
+        match self.network.get_mut(&next_hop_uuid).and_then(|next_hop| next_hop.connection.as_mut()) {
+            Some(connection) => connection.send_packet(&packet),
+            None => {
+                self.drops.unroutable += 1;
+                let reason = format!("node {} isn't directly connected to node {}", self.uuid, next_hop_uuid);
+                self.report_unreachable(from, &packet, &reason)
+            },
+        }
+    }
+
+    /// Tells the sender of a packet that we dropped why it couldn't be delivered.
+    ///
+    /// The report goes back the way the packet came, as we may not know
+    /// about the sender ourselves.
+    fn report_unreachable(&mut self, from: &Uuid, packet: &Packet, reason: &str) -> Result<(), Error> {
+        println!("dropping packet from node {} to node {}: {}",
+                 self.network.describe(&packet.origin()), self.network.describe(packet.path.tail().unwrap()), reason);
+
+        // Reports about reports could go back and forth forever.
+        if let PacketKind::Unreachable(..) = packet.kind { return Ok(()) }
+
+        let mut path = match packet.path.back_from(from) {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        path.hops.push_back(self.uuid);
+
+        let report = Packet {
+            path,
+            kind: PacketKind::Unreachable(protocol::Unreachable {
+                destination: *packet.path.tail().unwrap(),
+                reason: reason.to_owned(),
+            }),
+        };
+
+        match self.network.get_mut(from).and_then(|node| node.connection.as_mut()) {
+            Some(connection) => connection.send_packet(&report),
+            None => Ok(()),
+        }
+    }
+
+    /// Checks if a broadcast we received from a directly connected node is
+    /// new to us, and passes it on if it is.
+    ///
+    /// Returns `false` if we have already seen the broadcast.
+    pub fn receive_broadcast(&mut self, from: &Uuid, packet: &Packet) -> bool {
+        if !self.broadcasts.first_sighting(packet.path.broadcast_id.as_ref().unwrap()) {
+            return false;
+        }
+
+        self.pass_on_broadcast(packet, Some(from));
+        true
+    }
+
+    /// Passes a broadcast on to the nodes that should receive it from us.
+    ///
+    /// Changes to the shape of the network are flooded to every direct
+    /// neighbour, as the spanning tree is only right if every node agrees
+    /// on that shape. Everything else is passed along the spanning tree, so
+    /// that each connection carries it only once.
+    fn pass_on_broadcast(&mut self, packet: &Packet, from: Option<&Uuid>) {
+        let mut packet = packet.clone();
+
+        if from.is_some() {
+            if packet.path.hop_limit == 0 {
+                self.drops.hop_limit_exceeded += 1;
+                return;
+            }
+
+            packet.path.hop_limit -= 1;
+        }
+
+        let local_uuid = self.uuid;
+        let in_tree = !changes_topology(&packet.kind) &&
+            self.network.spanning_tree(&packet.origin()).path_to(&local_uuid).is_some();
+
+        let children = if in_tree {
+            self.network.spanning_tree(&packet.origin()).children_of(&local_uuid).to_vec()
+        } else {
+            // Either every neighbour needs to hear about it, or we don't know how the
+            // sender is connected to us yet. Neighbours ignore any duplicates.
+            self.network.nodes()
+                .filter(|node| node.connection.is_some() && Some(&node.uuid) != from)
+                .map(|node| node.uuid)
+                .collect()
+        };
+
+        for child_uuid in children {
+            // A node we can't reach will be evicted once we notice its connection is gone.
+            let result = match self.network.get_mut(&child_uuid).and_then(|child| child.connection.as_mut()) {
+                Some(connection) => connection.send_packet(&packet),
+                None => Err(Error::ConnectRefused { reason: "we aren't directly connected to the node".to_owned() }),
+            };
+
+            if let Err(e) = result {
+                println!("could not pass broadcast on to node {}: {:?}", self.network.describe(&child_uuid), e);
+            }
+        }
+    }
+
     pub fn promote_pending_connection_to_node(&mut self, pending_connection: remote::pending::Node) -> Result<(), Error> {
-        if let PendingState::Complete { join_response } = pending_connection.state {
+        if let PendingState::PeerConnected { uuid } = pending_connection.state {
+            self.add_direct_connection(uuid, pending_connection.connection)?;
+        } else if let PendingState::Complete { join_response } = pending_connection.state {
+            let new_node_uuid = join_response.your_uuid;
+
             self.network.insert(network::Node {
-                uuid: join_response.your_uuid,
+                uuid: new_node_uuid,
+                name: pending_connection.name,
+                labels: pending_connection.labels,
+                address: pending_connection.address,
                 connection: Some(pending_connection.connection),
                 status: network::Status::Remote(network::remote::Status::default()),
+                last_seen: self.config.clock.now(),
+                latency: None,
+                public_key: pending_connection.public_key,
             });
 
-            self.network.connect(&self.uuid, &join_response.your_uuid);
+            self.network.connect(&self.uuid, &new_node_uuid);
+
+            // The new node already knows about everybody from the join response,
+            // but everybody else needs to hear about the new node.
+            let node_joined = PacketKind::NodeJoined(protocol::NodeJoined {
+                node: protocol::Node::from_node(self.network.get(&new_node_uuid).unwrap()),
+            });
+            let edge_added = PacketKind::EdgeAdded(protocol::EdgeAdded {
+                edge: protocol::Edge::from_edge(&network::Edge::new(self.uuid, new_node_uuid)),
+            });
+
+            self.broadcast_packet(&node_joined)?;
+            self.broadcast_packet(&edge_added)?;
+        } else if let PendingState::Merged { uuid, network } = pending_connection.state {
+            self.merge_network(uuid, pending_connection.connection, network)?;
         } else {
             unreachable!();
         }
@@ -63,39 +361,347 @@ impl Node
         Ok(())
     }
 
+    /// Starts using a direct connection to a node that is already in the network.
+    pub fn add_direct_connection(&mut self, uuid: Uuid, connection: Connection) -> Result<(), Error> {
+        if let Some(node) = self.network.get_mut(&uuid) {
+            node.connection = Some(connection);
+            node.last_seen = self.config.clock.now();
+        } else {
+            println!("node {} left the network before we could connect to it directly", uuid);
+            return Ok(());
+        }
+
+        println!("connected directly to node {}", self.network.describe(&uuid));
+        self.network.connect(&self.uuid, &uuid);
+
+        let edge_added = PacketKind::EdgeAdded(protocol::EdgeAdded {
+            edge: protocol::Edge::from_edge(&network::Edge::new(self.uuid, uuid)),
+        });
+        self.broadcast_packet(&edge_added)
+    }
+
+    /// Merges another network into ours, now that we are connected to one of its nodes.
+    ///
+    /// Both ends of the connection do this, each telling their own side of
+    /// the network about the other side.
+    pub fn merge_network(&mut self, uuid: Uuid, mut connection: Connection, other: protocol::Network) -> Result<(), Error> {
+        // Another merge may have brought the networks together in the meantime.
+        if let Err(e) = local::pending::refuse_overlap(&mut connection, self.network.overlap_with(&other)) {
+            println!("not merging with the network of node {}: {:?}", uuid, e);
+            return Ok(());
+        }
+
+        let mut other = other;
+        other.edges.push(protocol::Edge::from_edge(&network::Edge::new(self.uuid, uuid)));
+
+        // Only the nodes on our side need to hear about the merge, so tell them before merging.
+        self.broadcast_packet(&PacketKind::NetworkMerged(protocol::NetworkMerged {
+            network: other.clone(),
+        }))?;
+
+        let added_uuids = self.network.merge(other, self.config.clock.now());
+        self.network.set_connection(&uuid, connection);
+        self.network.get_mut(&uuid).unwrap().last_seen = self.config.clock.now();
+
+        println!("merged with the network of node {}, adding {} node(s)", self.network.describe(&uuid), added_uuids.len());
+        Ok(())
+    }
+
+    /// Records a measurement of the latency of our direct connection to a node.
+    ///
+    /// Everybody is told about the new weight of the edge if it has changed by much.
+    pub fn record_latency(&mut self, uuid: &Uuid, measured: Duration) -> Result<(), Error> {
+        let latency = match self.network.get_mut(uuid) {
+            Some(ref mut node) if node.connection.is_some() => {
+                let latency = network::heartbeat::smooth_latency(node.latency, measured);
+                node.latency = Some(latency);
+                latency
+            },
+            _ => return Ok(()),
+        };
+
+        let weight = network::Weight::from_latency(latency);
+        let has_changed = match self.network.edge(&self.uuid, uuid).and_then(|edge| edge.weight) {
+            Some(current) => (weight.0 - current.0).abs() > (current.0 * LATENCY_CHANGE_THRESHOLD).max(MIN_LATENCY_CHANGE_MS),
+            None => true,
+        };
+
+        if !has_changed { return Ok(()) }
+
+        let edge = network::Edge::new(self.uuid, *uuid).with_weight(weight);
+        self.network.add_edge(edge.clone());
+
+        self.broadcast_packet(&PacketKind::EdgeAdded(protocol::EdgeAdded {
+            edge: protocol::Edge::from_edge(&edge),
+        }))
+    }
+
+    /// Picks nodes to open direct connections to, along with their addresses.
+    ///
+    /// Only the node with the smaller UUID opens the connection, so that two
+    /// nodes never connect to each other at the same time.
+    pub fn direct_connection_candidates(&self, connecting_to: &[Uuid]) -> Vec<(Uuid, SocketAddr)> {
+        let direct_connection_count = self.network.nodes().filter(|node| node.connection.is_some()).count() +
+            connecting_to.len();
+
+        self.network.nodes()
+            .filter(|node| node.uuid > self.uuid && node.connection.is_none())
+            .filter(|node| !connecting_to.contains(&node.uuid) && !self.unreachable_nodes.contains(&node.uuid))
+            .filter_map(|node| node.address.map(|address| (node.uuid, address)))
+            .take(self.config.fan_out.saturating_sub(direct_connection_count))
+            .collect()
+    }
+
+    /// Remembers the address of a node, so we can rejoin through it later.
+    pub fn remember_address(&mut self, address: SocketAddr) {
+        self.known_addresses.retain(|&known_address| known_address != address);
+        self.known_addresses.push(address);
+
+        if self.known_addresses.len() > MAX_KNOWN_ADDRESSES {
+            self.known_addresses.remove(0);
+        }
+    }
+
+    /// Takes our place in the network again after rejoining it.
+    pub fn complete_rejoin(&mut self, join_response: protocol::JoinResponse, connection: Connection) {
+        let old_local_node = self.network.remove(&self.uuid).unwrap();
+        let mut network = local::pending::joined_network(&join_response, connection, self.config.clock.now());
+
+        if let Some(local_node) = network.get_mut(&join_response.your_uuid) {
+            local_node.name = old_local_node.name;
+            local_node.labels = old_local_node.labels;
+            local_node.address = old_local_node.address;
+            local_node.public_key = old_local_node.public_key;
+        }
+
+        if join_response.your_uuid != self.uuid {
+            println!("warning: our UUID {} was taken while we were away, so we rejoined as {}", self.uuid, join_response.your_uuid);
+        }
+
+        println!("rejoined the network via node {}", network.describe(&join_response.my_uuid));
+
+        self.uuid = join_response.your_uuid;
+        self.network = network;
+        self.rejoin = None;
+        self.unreachable_nodes.clear();
+
+        // Work that was handed out to nodes that aren't in the network anymore needs doing again.
+        let network = &self.network;
+        self.reliable.retain_recipients(|uuid| network.get(uuid).is_some());
+
+        for executor in self.dispatcher.executors() {
+            if self.network.get(&executor).is_none() {
+                let reassigned_task_count = self.dispatcher.reassign_work_from(&executor);
+                if reassigned_task_count > 0 {
+                    println!("reassigning {} task(s) from node {}", reassigned_task_count, executor);
+                }
+            }
+        }
+    }
+
+    /// Removes a node that has left the network and tells everybody else about it.
+    ///
+    /// Any nodes that can no longer be reached are removed as well.
+    pub fn evict_node(&mut self, uuid: &Uuid) -> Result<(), Error> {
+        for removed_node in self.forget_node(uuid) {
+            self.broadcast_packet(&PacketKind::NodeLeft(protocol::NodeLeft {
+                uuid: removed_node.uuid,
+            }))?;
+        }
+
+        Ok(())
+    }
+
+    /// Drops our direct connection to a node and tells everybody else that
+    /// the link between us is gone.
+    ///
+    /// Losing a link doesn't mean the node has left, so it stays in the
+    /// network for as long as it can be reached through other nodes.
+    pub fn drop_connection(&mut self, uuid: &Uuid) -> Result<(), Error> {
+        let local_uuid = self.uuid;
+        self.remove_edge(&local_uuid, uuid);
+
+        self.broadcast_packet(&PacketKind::EdgeRemoved(protocol::EdgeRemoved {
+            edge: protocol::Edge::from_edge(&network::Edge::new(local_uuid, *uuid)),
+        }))
+    }
+
+    /// Removes a node from our view of the network, along with any nodes
+    /// that can no longer be reached.
+    pub fn forget_node(&mut self, uuid: &Uuid) -> Vec<network::Node> {
+        let mut removed_nodes: Vec<_> = self.network.remove(uuid).into_iter().collect();
+        removed_nodes.extend(self.network.prune_unreachable(&self.uuid));
+
+        self.nodes_removed(&removed_nodes);
+        removed_nodes
+    }
+
+    /// Removes an edge from our view of the network, along with any nodes
+    /// that can no longer be reached.
+    pub fn remove_edge(&mut self, a: &Uuid, b: &Uuid) -> Vec<network::Node> {
+        let edge = network::Edge::new(*a, *b);
+
+        // If the edge is ours, the connection that made it is no use anymore.
+        if edge.connected_to(&self.uuid) {
+            let other_uuid = *edge.other_node(&self.uuid).unwrap();
+
+            if let Some(other) = self.network.get_mut(&other_uuid) {
+                other.connection = None;
+            }
+        }
+
+        self.network.disconnect(a, b);
+
+        let removed_nodes = self.network.prune_unreachable(&self.uuid);
+        self.nodes_removed(&removed_nodes);
+        removed_nodes
+    }
+
+    /// Cleans up after nodes that have been removed from the network.
+    fn nodes_removed(&mut self, removed_nodes: &[network::Node]) {
+        for removed_node in removed_nodes.iter() {
+            self.unreachable_nodes.remove(&removed_node.uuid);
+
+            if let Some(address) = removed_node.address {
+                self.remember_address(address);
+            }
+
+            println!("node {} has left the network", removed_node);
+
+            let abandoned_packet_count = self.reliable.forget(&removed_node.uuid);
+            if abandoned_packet_count > 0 {
+                println!("giving up on {} unacknowledged packet(s) to node {}", abandoned_packet_count, removed_node);
+            }
+
+            let reassigned_task_count = self.dispatcher.reassign_work_from(&removed_node.uuid);
+            if reassigned_task_count > 0 {
+                println!("reassigning {} task(s) from node {}", reassigned_task_count, removed_node);
+            }
+        }
+    }
+
     pub fn tick(&mut self) -> Result<(), Error> {
         self.builder.tick();
 
-        if self.dispatcher.has_work() { self.notify.work.available() } else { self.notify.work.complete() }
+        if self.dispatcher.has_work() { self.notify.work.available(self.config.clock.now()) } else { self.notify.work.complete() }
 
         for packet in self.notify.notify() {
             self.broadcast_packet(&packet)?;
         }
 
+        self.send_heartbeats()?;
+        self.evict_dead_nodes()?;
+
         self.ask_for_work()?;
 
         let completed_work: Vec<_> = self.builder.completed_work().collect();
         for work in completed_work {
-            let response = PacketKind::WorkFinished(protocol::WorkFinished {
-                uuid: work.output.work.uuid,
-                tasks: work.output.task_results.into_iter().map(|a| protocol::ci::TaskResult::from_task_result(&a)).collect(),
-            });
+            if self.network.get(&work.origin).is_none() {
+                println!("dropping results of work {} as node {} has left the network", work.output.work.uuid, work.origin);
+                continue;
+            }
+
+            let work_uuid = work.output.work.uuid;
+            let tasks: Vec<_> = work.output.task_results.into_iter().map(|a| protocol::ci::TaskResult::from_task_result(&a)).collect();
+
+            self.send_packet_reliably(&work.origin, |sequence| PacketKind::WorkFinished(protocol::WorkFinished {
+                sequence,
+                uuid: work_uuid,
+                tasks,
+            }))?;
+        }
+
+        self.resend_unacknowledged();
+
+        Ok(())
+    }
 
-            self.send_packet_to(&work.origin, &response)?;
+    /// Resends the packets that haven't been acknowledged in time.
+    fn resend_unacknowledged(&mut self) {
+        for (to, packet) in self.reliable.due() {
+            if let Err(e) = self.send_packet_to(&to, &packet) {
+                println!("could not resend packet to node {}, will try again: {:?}", self.network.describe(&to), e);
+            }
+        }
+    }
+
+    pub fn is_listening(&self) -> bool { self.listener.is_some() || self.unix_listener.is_some() }
+
+    /// Leaves the network, telling all directly connected nodes why.
+    pub fn terminate(&mut self, reason: &str) {
+        for neighbour in self.network.nodes.values_mut() {
+            if let Some(ref mut connection) = neighbour.connection {
+                // We're leaving anyway, so there's nothing to do if this fails.
+                connection.terminate(reason).ok();
+            }
+        }
+    }
+
+    /// Pings every node we are directly connected to, if it is time to.
+    fn send_heartbeats(&mut self) -> Result<(), Error> {
+        if !self.heartbeat.is_due() { return Ok(()) }
+
+        // The pong echoes the time we sent the ping, so we can measure the latency.
+        let ping = PacketKind::Ping(protocol::Ping {
+            user_agent: ::user_agent(),
+            data: network::heartbeat::ping_data(self.config.clock.now()),
+            public_key: Vec::new(),
+            features: protocol::feature::all(),
+        });
+
+        for neighbour_uuid in self.heartbeat_neighbours() {
+            // Losing an earlier neighbour may have taken this one with it.
+            if !self.is_directly_connected(&neighbour_uuid) { continue }
+
+            if let Err(e) = self.send_packet_directly(&neighbour_uuid, &ping) {
+                println!("lost connection to node {}: {:?}", self.network.describe(&neighbour_uuid), e);
+                self.drop_connection(&neighbour_uuid)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Evicts directly connected nodes that we haven't heard from in too long.
+    fn evict_dead_nodes(&mut self) -> Result<(), Error> {
+        let dead_node_uuids: Vec<_> = self.heartbeat_neighbours().into_iter()
+            .filter(|uuid| self.heartbeat.has_timed_out(self.network.get(uuid).unwrap().last_seen))
+            .collect();
+
+        for dead_node_uuid in dead_node_uuids {
+            if !self.is_directly_connected(&dead_node_uuid) { continue }
+
+            println!("node {} timed out", self.network.describe(&dead_node_uuid));
+            self.drop_connection(&dead_node_uuid)?;
         }
 
         Ok(())
     }
 
-    pub fn is_listening(&self) -> bool { self.listener.is_some() }
+    pub fn is_directly_connected(&self, uuid: &Uuid) -> bool {
+        self.network.get(uuid).is_some_and(|node| node.connection.is_some())
+    }
+
+    /// Gets the UUIDs of all directly connected nodes that support heartbeats.
+    fn heartbeat_neighbours(&self) -> Vec<Uuid> {
+        self.network.nodes()
+            .filter(|node| node.connection.as_ref().is_some_and(|c| c.supports(protocol::feature::HEARTBEAT)))
+            .map(|node| node.uuid)
+            .collect()
+    }
 
     fn ask_for_work(&mut self) -> Result<(), Error> {
         // If we're feeling up to it, grab some work from other nodes.
-        if self.builder.should_pickup_work() {
+        if self.config.accepts_work && self.builder.should_pickup_work() {
+            // Nodes are kept in order, so we always ask the same node first.
             let node_uuid = self.network.nodes().filter(|n| n.can_ask_for_work()).next().map(|n| n.uuid.clone());
 
             if let Some(node_uuid) = node_uuid {
-                self.send_packet_to(&node_uuid, &PacketKind::WorkRequest(protocol::WorkRequest))?;
+                // We may have heard about the node before learning how to reach it. Either
+                // way, we'll ask again the next time it tells us it has work.
+                if let Err(e) = self.send_packet_to(&node_uuid, &PacketKind::WorkRequest(protocol::WorkRequest)) {
+                    println!("could not ask node {} for work: {:?}", self.network.describe(&node_uuid), e);
+                }
 
                 if let remote::status::Work::Available { ref mut have_asked_for_work }
                     = self.network.get_mut(&node_uuid).unwrap().status.expect_remote_mut().work {
@@ -107,3 +713,102 @@ impl Node
         Ok(())
     }
 }
+
+/// Checks if a packet tells nodes about a change to the shape of the network.
+fn changes_topology(packet: &PacketKind) -> bool {
+    matches!(*packet, PacketKind::NodeJoined(..) | PacketKind::EdgeAdded(..) | PacketKind::EdgeRemoved(..) |
+                      PacketKind::NodeLeft(..) | PacketKind::NetworkMerged(..))
+}
+
+#[cfg(test)]
+mod test
+{
+    pub use super::*;
+    pub use std::collections::BTreeMap;
+    pub use std::time::UNIX_EPOCH;
+
+    fn setup(fan_out: usize) -> (Node, Vec<Uuid>) {
+        let uuids: Vec<_> = [
+            "123e4567-e89b-12d3-a456-426655440000",
+            "2c6bb858-72ea-4db6-a9ab-6ec0ca1f18ab",
+            "3042be80-4d2b-4cce-b30c-3142e3035720",
+            "4e1c2f5a-7b3d-4c8e-9a1f-2b3c4d5e6f70",
+        ].iter().map(|uuid| Uuid::parse_str(uuid).unwrap()).collect();
+
+        let mut network = Network::new(uuids[1], UNIX_EPOCH);
+
+        for (i, &uuid) in uuids.iter().enumerate().filter(|&(_, &uuid)| uuid != uuids[1]) {
+            network.insert(network::Node {
+                uuid: uuid,
+                name: None,
+                labels: BTreeMap::new(),
+                address: Some(format!("127.0.0.1:{}", 4000 + i).parse().unwrap()),
+                connection: None,
+                status: network::Status::Remote(Default::default()),
+                last_seen: UNIX_EPOCH,
+                latency: None,
+                public_key: None,
+            });
+        }
+
+        let mut config = Config::default();
+        config.fan_out = fan_out;
+
+        (Node::new(uuids[1], None, network, &config), uuids)
+    }
+
+    #[test]
+    fn only_nodes_with_bigger_uuids_are_connected_to() {
+        let (node, uuids) = setup(4);
+
+        let candidates: Vec<_> = node.direct_connection_candidates(&[]).into_iter().map(|(uuid, _)| uuid).collect();
+        assert_eq!(candidates.len(), 2);
+        assert!(candidates.contains(&uuids[2]) && candidates.contains(&uuids[3]));
+    }
+
+    #[test]
+    fn the_fan_out_limits_connections() {
+        let (node, uuids) = setup(2);
+
+        assert_eq!(node.direct_connection_candidates(&[]).len(), 2);
+        assert_eq!(node.direct_connection_candidates(&[uuids[2]]).len(), 1);
+        assert!(node.direct_connection_candidates(&[uuids[2], uuids[3]]).is_empty());
+    }
+
+    #[test]
+    fn packets_claiming_to_be_from_us_are_dropped() {
+        let (mut node, uuids) = setup(4);
+
+        node.receive_packet(&uuids[0], Packet {
+            path: network::Path::new(vec![node.uuid].into_iter().collect()),
+            kind: PacketKind::Ping(protocol::Ping {
+                user_agent: ::user_agent(),
+                data: Vec::new(),
+                public_key: Vec::new(),
+                features: protocol::feature::all(),
+            }),
+        });
+
+        assert_eq!(node.drops.malformed, 1);
+    }
+
+    #[test]
+    fn packets_are_not_sent_to_ourselves() {
+        let (mut node, _) = setup(4);
+        let uuid = node.uuid;
+
+        match node.send_packet_to(&uuid, &PacketKind::Terminate(protocol::Terminate { reason: String::new() })) {
+            Err(Error::Unroutable { destination }) => assert_eq!(destination, uuid),
+            result => panic!("expected the packet to be unroutable, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn unreachable_nodes_are_not_retried() {
+        let (mut node, uuids) = setup(4);
+        node.unreachable_nodes.insert(uuids[3]);
+
+        let candidates: Vec<_> = node.direct_connection_candidates(&[]).into_iter().map(|(uuid, _)| uuid).collect();
+        assert_eq!(candidates, vec![uuids[2]]);
+    }
+}
@@ -5,26 +5,60 @@ use network::{self, local, remote};
 pub fn packet(node: &mut local::connected::Node, packet: &Packet)
     -> Result<(), Error> {
     match packet.kind {
+        PacketKind::Ping(ref ping) => {
+            let pong = PacketKind::Pong(protocol::Pong {
+                user_agent: ::user_agent(),
+                data: ping.data.clone(),
+                challenge: Vec::new(),
+                proof: Vec::new(),
+                public_key: Vec::new(),
+                features: protocol::feature::all(),
+            });
+
+            // Heartbeats measure the latency of the connection they came over, so answer over it too.
+            if is_from_neighbour(node, packet) {
+                node.send_packet_directly(&packet.origin(), &pong)
+            } else {
+                node.send_packet_to(&packet.origin(), &pong)
+            }
+        },
+        PacketKind::Pong(ref pong) => {
+            // The last seen time has already been updated upon receipt.
+            if is_from_neighbour(node, packet) {
+                if let Some(latency) = network::heartbeat::round_trip_latency(&pong.data, node.config.clock.now()) {
+                    node.record_latency(&packet.origin(), latency)?;
+                }
+            }
+
+            Ok(())
+        },
         PacketKind::WorkRequest(..) => {
-            if let Some(work) = node.dispatcher.poll() {
+            if let Some(work) = node.dispatcher.poll(&packet.origin()) {
                 println!("giving data");
-                node.send_packet_to(&packet.origin(), &PacketKind::WorkResponse(protocol::WorkResponse::from_work(&work)))?
+                node.send_packet_reliably(&packet.origin(), |sequence| {
+                    PacketKind::WorkResponse(protocol::WorkResponse::from_work(&work, sequence))
+                })?
             }
 
             Ok(())
         },
         PacketKind::WorkResponse(ref work_response) => {
+            // We may have already been given this work, if our acknowledgement was lost.
+            if !node.acknowledge(&packet.origin(), work_response.sequence)? { return Ok(()) }
+
             let work: ci::build::Work = work_response.clone().into();
             node.builder.build(packet.origin(), work);
 
             Ok(())
         },
         PacketKind::WorkFinished(ref work_finished) => {
+            if !node.acknowledge(&packet.origin(), work_finished.sequence)? { return Ok(()) }
+
             println!("job completed on Node({})", packet.origin());
 
+            // Commands can print anything, so their output may not be UTF-8.
             for task in work_finished.tasks.iter() {
-                let output = String::from_utf8(task.output.clone()).unwrap();
-                println!("{}", output);
+                println!("{}", String::from_utf8_lossy(&task.output));
             }
 
             node.dispatcher.complete(ci::dispatcher::CompletedWork {
@@ -34,28 +68,84 @@ pub fn packet(node: &mut local::connected::Node, packet: &Packet)
 
             Ok(())
         },
+        PacketKind::Acknowledgement(ref acknowledgement) => {
+            node.reliable.acknowledged(&packet.origin(), acknowledgement.sequence);
+            Ok(())
+        },
         PacketKind::WorkAvailable(..) => {
-            let sender_uuid = packet.path.sender();
-            let sender = node.network.get_mut(&sender_uuid).unwrap();
-
-            println!("work available on {}", sender_uuid);
+            // We may not have heard about the sender yet.
+            if let Some(sender) = node.network.get_mut(packet.path.sender()) {
+                println!("work available on {}", sender);
 
-            if let network::Status::Remote(ref mut status) = sender.status {
-                status.work = remote::status::Work::Available { have_asked_for_work: false };
+                if let network::Status::Remote(ref mut status) = sender.status {
+                    status.work = remote::status::Work::Available { have_asked_for_work: false };
+                }
             }
 
             Ok(())
         },
         PacketKind::WorkComplete(..) => {
-            let sender_uuid = packet.path.sender();
-            let sender = node.network.get_mut(&sender_uuid).unwrap();
+            if let Some(sender) = node.network.get_mut(packet.path.sender()) {
+                if let network::Status::Remote(ref mut status) = sender.status {
+                    status.work = remote::status::Work::Unavailable;
+                }
+            }
 
-            if let network::Status::Remote(ref mut status) = sender.status {
-                status.work = remote::status::Work::Unavailable;
+            Ok(())
+        },
+        PacketKind::Unreachable(ref unreachable) => {
+            println!("node {} could not deliver our packet to node {}: {}",
+                     node.network.describe(&packet.origin()), node.network.describe(&unreachable.destination), unreachable.reason);
+
+            Ok(())
+        },
+        PacketKind::NodeJoined(ref node_joined) => {
+            let new_node = node_joined.node.clone();
+
+            if new_node.uuid != node.uuid && node.network.get(&new_node.uuid).is_none() {
+                node.network.insert(network::Node {
+                    uuid: new_node.uuid,
+                    name: new_node.name,
+                    labels: new_node.labels,
+                    address: new_node.address.and_then(|address| address.parse().ok()),
+                    connection: None,
+                    status: network::Status::Remote(remote::Status::default()),
+                    last_seen: node.config.clock.now(),
+                    latency: None,
+                    public_key: if new_node.public_key.is_empty() { None } else { Some(new_node.public_key) },
+                });
+
+                println!("node {} has joined the network", node.network.describe(&new_node.uuid));
+            }
+
+            Ok(())
+        },
+        PacketKind::EdgeAdded(ref edge_added) => {
+            let edge = &edge_added.edge;
+
+            if node.network.get(&edge.a).is_some() && node.network.get(&edge.b).is_some() {
+                node.network.add_edge(edge.clone().into());
+            }
+
+            Ok(())
+        },
+        PacketKind::NetworkMerged(ref network_merged) => {
+            let added_uuids = node.network.merge(network_merged.network.clone(), node.config.clock.now());
+
+            if !added_uuids.is_empty() {
+                println!("our network has merged with another, adding {} node(s)", added_uuids.len());
             }
 
             Ok(())
         },
+        PacketKind::EdgeRemoved(ref edge_removed) => {
+            node.remove_edge(&edge_removed.edge.a, &edge_removed.edge.b);
+            Ok(())
+        },
+        PacketKind::NodeLeft(ref node_left) => {
+            node.forget_node(&node_left.uuid);
+            Ok(())
+        },
         ref pkt => {
             println!("received packet: {:#?}", pkt);
             Ok(())
@@ -63,3 +153,9 @@ pub fn packet(node: &mut local::connected::Node, packet: &Packet)
     }
 }
 
+
+/// Checks if a packet came straight from a node we are directly connected to.
+fn is_from_neighbour(node: &local::connected::Node, packet: &Packet) -> bool {
+    packet.path.hops.len() == 2 &&
+        node.network.get(&packet.origin()).is_some_and(|n| n.connection.is_some())
+}
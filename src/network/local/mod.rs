@@ -3,7 +3,10 @@ pub use self::node::Node;
 pub mod node;
 pub mod pending;
 pub mod connected;
+pub mod outgoing;
+pub mod rejoin;
 
 pub mod tcp;
+pub mod unix;
 pub mod handle;
 
@@ -1,17 +1,31 @@
-use Error;
-use network::{PendingState, Connection, local, remote};
-use {network, ci};
+use {Error, PacketKind};
+use network::{PendingState, Connection, Transport, Address, local, remote};
 
 use slab::Slab;
-use proto;
 use mio;
 use mio::unix::UnixReady;
 use std;
 
+use std::collections::HashMap;
 use std::time::Duration;
 
-pub const SERVER_TOKEN: mio::Token = mio::Token(usize::max_value() - 10);
-pub const NEW_CONNECTION_TOKEN: mio::Token = mio::Token(usize::max_value() - 11);
+pub const SERVER_TOKEN: mio::Token = mio::Token(usize::MAX - 10);
+pub const NEW_CONNECTION_TOKEN: mio::Token = mio::Token(usize::MAX - 11);
+pub const UNIX_SERVER_TOKEN: mio::Token = mio::Token(usize::MAX - 12);
+
+/// The maximum number of connections that can be pending at once.
+///
+/// Pending connections use the tokens below this number, established
+/// connections use the tokens above it.
+pub const MAX_PENDING_CONNECTIONS: usize = 1024;
+
+/// The events we want to hear about on a connection.
+///
+/// We have to explicitly ask for hang ups, otherwise a remote closing
+/// the connection looks like nothing happened.
+pub fn connection_interest() -> mio::Ready {
+    mio::Ready::readable() | mio::Ready::writable() | UnixReady::hup()
+}
 
 pub enum Node
 {
@@ -22,6 +36,11 @@ pub enum Node
         node: local::connected::Node,
 
         pending_connections: Slab<remote::pending::Node, mio::Token>,
+        /// Direct connections we are opening to nodes already in the network,
+        /// or to nodes in networks we are merging with.
+        outgoing_connections: HashMap<mio::Token, local::outgoing::Node>,
+        /// The token to give to the next established connection.
+        next_node_token: mio::Token,
     },
 }
 
@@ -36,34 +55,48 @@ impl Node
 
         if let Node::Connected { ref mut node, .. } = *self {
             node.tick()?;
-
-            // If we've been orphaned, and we aren't listening locally.
-            if !node.is_listening() && node.network.node_count() == 1 {
-                return Err(Error::Stop { reason: "local node has stopped, and we aren't bound to local port, so we are orphaned" })
-            }
         }
 
+        self.try_rejoin(poll);
+        self.open_direct_connections(poll);
+
         for event in events.iter() {
             let readiness = UnixReady::from(event.readiness());
 
             match event.token() {
                 // A pending connection.
                 SERVER_TOKEN => {
-                    if let local::Node::Connected { ref mut node, ref mut pending_connections } = *self {
-                        let (socket, addr) = node.listener.as_mut().unwrap().accept()?;
-
-                        println!("accepted connection from {:?}", addr);
-
-                        let entry = pending_connections.vacant_entry().expect("ran out of connections");
-                        let token = entry.index();
+                    if let local::Node::Connected { ref mut node, ref mut pending_connections, .. } = *self {
+                        // We are only told about new connections once, so accept all of them.
+                        loop {
+                            let (socket, addr) = match node.listener.as_mut().unwrap().accept() {
+                                Ok(connection) => connection,
+                                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => break,
+                                Err(e) => return Err(e.into()),
+                            };
 
-                        poll.register(&socket, token, mio::Ready::readable() | mio::Ready::writable(),
-                            mio::PollOpt::edge())?;
+                            println!("accepted connection from {:?}", addr);
+                            accept_connection(poll, pending_connections, Box::new(socket))?;
+                        }
+                    } else {
+                        // We only start listening after we are successfully connected to the
+                        // network.
+                        unreachable!();
+                    }
+                },
+                // A pending connection from the same host.
+                UNIX_SERVER_TOKEN => {
+                    if let local::Node::Connected { ref mut node, ref mut pending_connections, .. } = *self {
+                        loop {
+                            let socket = match node.unix_listener.as_ref().unwrap().listener.accept() {
+                                Ok(Some((socket, _))) => socket,
+                                Ok(None) => break,
+                                Err(e) => return Err(e.into()),
+                            };
 
-                        entry.insert(remote::pending::Node::new(Connection {
-                            token: token,
-                            protocol: proto::wire::stream::Connection::new(socket, proto::wire::middleware::pipeline::default()),
-                        }));
+                            println!("accepted connection over the Unix socket");
+                            accept_connection(poll, pending_connections, Box::new(socket))?;
+                        }
                     } else {
                         // We only start listening after we are successfully connected to the
                         // network.
@@ -71,69 +104,105 @@ impl Node
                     }
                 },
                 token => {
-                    // TODO: check for `HUP` event.
-
                     match *self {
                         local::Node::Pending(ref mut pending_node) => {
                             assert_eq!(token, NEW_CONNECTION_TOKEN);
 
-                            if readiness.is_hup() {
-                                println!("remote node disconnected before we could join");
-                                return Ok(());
-                            } else if !readiness.is_readable() {
-                                continue;
+                            // Read anything the remote sent before hanging up, as it
+                            // may tell us why we were refused.
+                            if readiness.is_readable() {
+                                pending_node.process_incoming_data()?;
                             }
 
-                            pending_node.process_incoming_data()?;
+                            if readiness.is_hup() || pending_node.connection.has_hung_up() {
+                                // A remote that can't decrypt our packets hangs up without a reason.
+                                if pending_node.config.network_key.is_some() {
+                                    return Err(Error::Stop { reason: "remote node disconnected before we could join, the network key may be wrong" });
+                                }
+
+                                return Err(Error::Stop { reason: "remote node disconnected before we could join" });
+                            }
                         },
-                        local::Node::Connected { ref mut node, ref mut pending_connections } => {
+                        local::Node::Connected { ref mut node, ref mut pending_connections, ref mut outgoing_connections, ref mut next_node_token } => {
+                            // Once we have rejoined, the connection keeps its token, like when we first joined.
+                            if token == NEW_CONNECTION_TOKEN && node.rejoin.as_ref().is_some_and(|rejoin| rejoin.attempt.is_some()) {
+                                process_rejoin(node, readiness);
+                                continue;
+                            }
+
+                            if outgoing_connections.contains_key(&token) {
+                                process_outgoing_connection(node, outgoing_connections, token, readiness)?;
+                                continue;
+                            }
+
                             if !readiness.is_readable() {
                                 continue;
                             }
 
-                            let packet = if let Some(mut pending_connection) = pending_connections.entry(token) {
-                                if readiness.is_hup() {
+                            let (from_node_uuid, packets) = if let Some(mut pending_connection) = pending_connections.entry(token) {
+                                if readiness.is_hup() || pending_connection.get().connection.has_hung_up() {
                                     println!("pending remote connection disconnected");
                                     pending_connection.remove();
                                 } else if readiness.is_readable() {
-                                    pending_connection.get_mut().process_incoming_data(node)?;
-
-                                    if pending_connection.get().is_complete() {
-                                        node.promote_pending_connection_to_node(pending_connection.remove()).unwrap();
+                                    // A misbehaving remote shouldn't bring the whole node down.
+                                    if let Err(e) = pending_connection.get_mut().process_incoming_data(node) {
+                                        println!("dropping pending remote connection: {:?}", e);
+                                        pending_connection.remove();
+                                    } else if pending_connection.get().is_complete() {
+                                        let mut pending_connection = pending_connection.remove();
+
+                                        // Move the connection out of the pending token range so
+                                        // that the slot can be reused by another pending connection.
+                                        pending_connection.connection.reregister(poll, *next_node_token)?;
+                                        *next_node_token = mio::Token(next_node_token.0 + 1);
+
+                                        node.promote_pending_connection_to_node(pending_connection).unwrap();
                                     }
                                 }
 
                                 continue;
                             } else if let Some(mut from_node) = node.network.entry_by_token(token) {
-                                // we received a packet from an established node
-
-                                if readiness.is_hup() {
-                                    println!("node {} disconnected", from_node.get().uuid);
-                                    from_node.remove();
+                                // we received packets from an established node
+                                let from_node = from_node.get_mut();
 
-                                    continue;
-                                } else if readiness.is_readable() {
-                                    if let Some(packet) = from_node.get_mut().connection.as_mut().unwrap().receive_packet()? {
-                                        packet
-                                    } else {
-                                        continue;
-                                    }
-                                } else {
-                                    continue;
+                                // Read anything the node sent before checking if it hung up.
+                                let packets = from_node.connection.as_mut().unwrap().receive_packets();
+                                if packets.as_ref().is_ok_and(|packets| !packets.is_empty()) {
+                                    from_node.last_seen = node.config.clock.now();
                                 }
+
+                                (from_node.uuid, packets)
                             } else {
-                                unreachable!();
+                                // The connection was dropped while handling an earlier event.
+                                continue;
                             };
 
-                            // Check if the packet is for us.
-                            if packet.is_recipient(&node.uuid) {
-                                local::handle::packet(node, &packet)?;
-                            } else {
-                                // we need to forward this packet to the recipient
-                                let next_hop_uuid = packet.path.next_hop(&node.uuid).unwrap();
-                                let next_hop = node.network.get_mut(&next_hop_uuid).unwrap();
+                            let packets = match packets {
+                                Ok(packets) => packets,
+                                Err(e) => {
+                                    println!("lost connection to node {}: {:?}", node.network.describe(&from_node_uuid), e);
+                                    node.drop_connection(&from_node_uuid)?;
+                                    continue;
+                                },
+                            };
+
+                            for packet in packets {
+                                if let PacketKind::Terminate(ref terminate) = packet.kind {
+                                    println!("node {} terminated the connection: {}", node.network.describe(&from_node_uuid), terminate.reason);
+
+                                    node.evict_node(&from_node_uuid)?;
+                                    break;
+                                }
+
+                                node.receive_packet(&from_node_uuid, packet);
+                            }
+
+                            let hung_up = readiness.is_hup() ||
+                                node.network.get(&from_node_uuid).and_then(|n| n.connection.as_ref()).is_some_and(|c| c.has_hung_up());
 
-                                next_hop.connection.as_mut().unwrap().send_packet(&packet)?;
+                            if hung_up && node.is_directly_connected(&from_node_uuid) {
+                                println!("node {} disconnected", node.network.describe(&from_node_uuid));
+                                node.drop_connection(&from_node_uuid)?;
                             }
                         },
                         local::Node::Unconnected => unreachable!(),
@@ -145,6 +214,102 @@ impl Node
         Ok(())
     }
 
+    /// Disconnects from the network, telling the nodes we are connected to why.
+    pub fn terminate(&mut self, reason: &str) {
+        match *self {
+            local::Node::Pending(ref mut node) => { node.connection.terminate(reason).ok(); },
+            local::Node::Connected { ref mut node, .. } => node.terminate(reason),
+            local::Node::Unconnected => (),
+        }
+    }
+
+    /// Tries to join the network again if we have lost our connection to it.
+    fn try_rejoin(&mut self, poll: &mio::Poll) {
+        if let local::Node::Connected { ref mut node, .. } = *self {
+            if node.network.node_count() > 1 {
+                // Somebody else connected to us in the meantime, so we're back.
+                if let Some(rejoin) = node.rejoin.take() {
+                    if let Some(mut attempt) = rejoin.attempt {
+                        attempt.connection.terminate("we have already rejoined the network").ok();
+                    }
+                }
+
+                return;
+            }
+
+            if node.known_addresses.is_empty() { return }
+
+            if node.rejoin.is_none() {
+                println!("lost our connection to the network, trying to rejoin");
+                node.rejoin = Some(local::rejoin::Rejoin::new(&node.config));
+            }
+
+            let advertised_address = node.network.get(&node.uuid).and_then(|local_node| local_node.address);
+            let mut config = node.config.clone();
+            config.identity.uuid = node.uuid;
+
+            // A node that gave up is a network of its own until somebody joins it.
+            let rejoin = node.rejoin.as_mut().unwrap();
+            if rejoin.has_given_up() || rejoin.attempt.is_some() || !rejoin.backoff.is_due() { return }
+
+            let address = rejoin.next_address(&node.known_addresses).unwrap();
+
+            let result = Address::Tcp(address).connect().map_err(Error::from).and_then(|stream| {
+                poll.register(&*stream, NEW_CONNECTION_TOKEN, connection_interest(), mio::PollOpt::edge())?;
+                Ok(stream)
+            });
+
+            match result {
+                Ok(stream) => {
+                    println!("trying to rejoin the network via {}", address);
+
+                    let connection = Connection::new(NEW_CONNECTION_TOKEN, stream);
+                    rejoin.attempt = Some(local::pending::Node::rejoin(connection, advertised_address, config));
+                },
+                Err(e) => rejoin.failed(&e),
+            }
+        }
+    }
+
+    /// Opens direct connections to nodes we know the address of, up to the fan-out.
+    fn open_direct_connections(&mut self, poll: &mio::Poll) {
+        if let local::Node::Connected { ref mut node, ref mut outgoing_connections, ref mut next_node_token, .. } = *self {
+            let connecting_to: Vec<_> = outgoing_connections.values().filter_map(|outgoing| outgoing.uuid()).collect();
+
+            for (uuid, address) in node.direct_connection_candidates(&connecting_to) {
+                let token = *next_node_token;
+                *next_node_token = mio::Token(next_node_token.0 + 1);
+
+                match local::outgoing::Node::connect(local::outgoing::Intent::Connect { uuid }, &Address::Tcp(address), token, poll) {
+                    Ok(outgoing) => {
+                        println!("connecting directly to node {} at {}", node.network.describe(&uuid), address);
+                        outgoing_connections.insert(token, outgoing);
+                    },
+                    Err(e) => {
+                        println!("could not connect directly to node {}: {:?}", node.network.describe(&uuid), e);
+                        node.unreachable_nodes.insert(uuid);
+                    },
+                }
+            }
+        }
+    }
+
+    /// Starts merging our network with the network of the node at an address.
+    pub fn merge_with(&mut self, address: &Address, poll: &mio::Poll) -> Result<(), Error> {
+        if let local::Node::Connected { ref mut outgoing_connections, ref mut next_node_token, .. } = *self {
+            let token = *next_node_token;
+            *next_node_token = mio::Token(next_node_token.0 + 1);
+
+            let outgoing = local::outgoing::Node::connect(local::outgoing::Intent::Merge, address, token, poll)?;
+            println!("merging with the network of the node at {}", address);
+            outgoing_connections.insert(token, outgoing);
+
+            Ok(())
+        } else {
+            Err(Error::ConnectRefused { reason: "we can't merge networks until we are connected to one".to_owned() })
+        }
+    }
+
     /// Attempts to advance the current state if possible.
     pub fn try_complete_pending_connection(&mut self, poll: &mut mio::Poll) -> Result<(), Error> {
         let mut current_node = local::Node::Unconnected;
@@ -155,43 +320,36 @@ impl Node
                 node.advance_state()?;
 
                 if let PendingState::Complete { join_response } = node.state.clone() {
-                    let listener = match local::tcp::bind(poll, ::SERVER_ADDRESS) {
-                        Ok(listener) => Some(listener),
-                        Err(Error::Io(e)) => match e.kind() {
-                            std::io::ErrorKind::AddrInUse => {
-                                println!("there is already something listening on port {} - we're not going to listen", ::SERVER_PORT);
-                                None
-                            },
-                            _ => return Err(Error::Io(e)),
-                        },
-                        Err(e) => return Err(e),
-                    };
+                    // We only start accepting connections once we are part of the network.
+                    if let Some(ref listener) = node.listener {
+                        local::tcp::register(poll, listener)?;
+                        println!("listening on {}", listener.local_addr()?);
+                    }
+
+                    // Remember how we got in, in case we need to get back in later.
+                    let bootstrap_address = node.connection.peer_addr();
+
+                    let network = local::pending::joined_network(&join_response, node.connection, node.config.clock.now());
 
-                    let mut network: network::Network = join_response.network.into();
-                    network.set_connection(&join_response.my_uuid, node.connection);
+                    println!("connected to network with UUID {} via node {}", join_response.your_uuid, network.describe(&join_response.my_uuid));
 
-                    // Add ourselves to the network.
-                    network.insert(network::Node {
-                        uuid: join_response.your_uuid,
-                        connection: None,
-                        status: network::Status::Local,
-                    });
+                    if join_response.your_uuid != node.config.identity.uuid {
+                        println!("warning: our UUID {} was already taken, so we were given a new one for this session",
+                                 node.config.identity.uuid);
+                    }
 
-                    // Connect ourselves and the node we're communicating with.
-                    network.connect(&join_response.your_uuid, &join_response.my_uuid);
+                    let mut connected_node = local::connected::Node::new(join_response.your_uuid, node.listener, network, &node.config);
+                    if let Some(address) = bootstrap_address {
+                        connected_node.remember_address(address);
+                    }
 
-                    println!("connected to network with UUID {}", join_response.your_uuid);
+                    connected_node.unix_listener = local::unix::start(poll, &node.config);
 
                     local::Node::Connected {
-                        node: local::connected::Node {
-                            uuid: join_response.your_uuid,
-                            listener: listener,
-                            network: network,
-                            notify: network::Notify::new(),
-                            builder: ci::Builder::new(),
-                            dispatcher: ci::Dispatcher::new(),
-                        },
-                        pending_connections: Slab::with_capacity(1024),
+                        node: connected_node,
+                        pending_connections: Slab::with_capacity(MAX_PENDING_CONNECTIONS),
+                        outgoing_connections: HashMap::new(),
+                        next_node_token: mio::Token(MAX_PENDING_CONNECTIONS),
                     }
                 } else {
                     local::Node::Pending(node)
@@ -204,3 +362,110 @@ impl Node
     }
 }
 
+/// Starts the handshake with a node or client that has connected to us.
+fn accept_connection(poll: &mio::Poll,
+                     pending_connections: &mut Slab<remote::pending::Node, mio::Token>,
+                     socket: Box<dyn Transport>) -> Result<(), Error> {
+    let entry = pending_connections.vacant_entry().expect("ran out of connections");
+    let token = entry.index();
+
+    poll.register(&*socket, token, connection_interest(), mio::PollOpt::edge())?;
+
+    entry.insert(remote::pending::Node::new(Connection::new(token, socket)));
+    Ok(())
+}
+
+/// Advances our attempt to rejoin the network.
+fn process_rejoin(node: &mut local::connected::Node, readiness: UnixReady) {
+    let result = match node.rejoin.as_mut().and_then(|rejoin| rejoin.attempt.as_mut()) {
+        Some(attempt) => {
+            if readiness.is_error() || readiness.is_hup() {
+                Err(Error::ConnectRefused { reason: "the connection was closed".to_owned() })
+            } else {
+                // We only send our ping once the connection has opened.
+                let mut result = attempt.advance_state();
+
+                if result.is_ok() && readiness.is_readable() {
+                    result = attempt.process_incoming_data().and_then(|_| attempt.advance_state());
+
+                    if result.is_ok() && !attempt.state.is_complete() && attempt.connection.has_hung_up() {
+                        result = Err(Error::ConnectRefused { reason: "the connection was closed".to_owned() });
+                    }
+                }
+
+                result
+            }
+        },
+        None => return,
+    };
+
+    if let Err(e) = result {
+        node.rejoin.as_mut().unwrap().failed(&e);
+        return;
+    }
+
+    let attempt = node.rejoin.as_mut().unwrap().attempt.take().unwrap();
+
+    if let PendingState::Complete { join_response } = attempt.state.clone() {
+        node.complete_rejoin(join_response, attempt.connection);
+    } else {
+        node.rejoin.as_mut().unwrap().attempt = Some(attempt);
+    }
+}
+
+/// Advances the handshake of a direct connection we are opening.
+fn process_outgoing_connection(node: &mut local::connected::Node,
+                               outgoing_connections: &mut HashMap<mio::Token, local::outgoing::Node>,
+                               token: mio::Token,
+                               readiness: UnixReady) -> Result<(), Error> {
+    let result = {
+        let outgoing = outgoing_connections.get_mut(&token).unwrap();
+
+        if readiness.is_error() || readiness.is_hup() {
+            Err(Error::ConnectRefused { reason: "the connection was closed".to_owned() })
+        } else {
+            let mut result = Ok(());
+
+            if readiness.is_writable() {
+                result = outgoing.process_writable();
+            }
+
+            if result.is_ok() && readiness.is_readable() && !outgoing.is_connecting() {
+                result = outgoing.process_incoming_data(node);
+
+                if result.is_ok() && !outgoing.is_complete() && outgoing.connection.has_hung_up() {
+                    result = Err(Error::ConnectRefused { reason: "the connection was closed".to_owned() });
+                }
+            }
+
+            result
+        }
+    };
+
+    match result {
+        Ok(()) => {
+            if outgoing_connections[&token].is_complete() {
+                let outgoing = outgoing_connections.remove(&token).unwrap();
+
+                match outgoing.state {
+                    local::outgoing::State::Merged { uuid, network } => node.merge_network(uuid, outgoing.connection, network)?,
+                    _ => node.add_direct_connection(outgoing.uuid().unwrap(), outgoing.connection)?,
+                }
+            }
+        },
+        Err(e) => {
+            let outgoing = outgoing_connections.remove(&token).unwrap();
+
+            match outgoing.uuid() {
+                // The node is still reachable through the rest of the network.
+                Some(uuid) => {
+                    println!("could not connect directly to node {}: {:?}", node.network.describe(&uuid), e);
+                    node.unreachable_nodes.insert(uuid);
+                },
+                None => println!("could not merge networks: {:?}", e),
+            }
+        },
+    }
+
+    Ok(())
+}
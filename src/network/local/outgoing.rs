@@ -0,0 +1,222 @@
+use {Packet, PacketKind, Error};
+use network::{local, Address, Connection};
+use network::encryption::KeyExchange;
+use {network, protocol};
+
+use mio;
+use uuid::Uuid;
+
+/// A direct connection we are opening to a node that is either already
+/// part of our network, or part of a network we want to merge with.
+#[derive(Debug)]
+pub struct Node
+{
+    pub intent: Intent,
+    pub state: State,
+    pub connection: Connection,
+    /// Our half of the key exchange for encrypting the connection.
+    key_exchange: KeyExchange,
+}
+
+/// Why we are opening a connection.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Intent
+{
+    /// Connecting directly to a node that is already in our network.
+    Connect {
+        uuid: Uuid,
+    },
+    /// Merging our network with the network of whatever node is at the address.
+    Merge,
+}
+
+/// The state of a direct connection that we are opening.
+#[derive(Clone, Debug)]
+pub enum State
+{
+    /// We are waiting for the connection to open before sending a `Ping`.
+    Connecting,
+    /// We sent a `Ping` and are awaiting a `Pong`.
+    PendingPong {
+        /// The original ping that we sent.
+        original_ping: protocol::Ping,
+    },
+    /// We sent a `ConnectRequest` and are awaiting a response.
+    PendingConnectResponse,
+    /// We sent a `MergeRequest` and are awaiting a response.
+    PendingMergeResponse,
+    Complete,
+    /// The remote agreed to merge our networks.
+    Merged {
+        uuid: Uuid,
+        /// The network the remote is a part of.
+        network: protocol::Network,
+    },
+}
+
+impl Node
+{
+    /// Starts connecting to a node.
+    pub fn connect(intent: Intent,
+                   address: &Address,
+                   token: mio::Token,
+                   poll: &mio::Poll) -> Result<Self, Error> {
+        let stream = address.connect()?;
+        poll.register(&*stream, token, local::node::connection_interest(), mio::PollOpt::edge())?;
+
+        Ok(Node {
+            intent,
+            state: State::Connecting,
+            connection: Connection::new(token, stream),
+            key_exchange: KeyExchange::new(),
+        })
+    }
+
+    /// The node we are connecting to, if it is already in our network.
+    pub fn uuid(&self) -> Option<Uuid> {
+        match self.intent {
+            Intent::Connect { uuid } => Some(uuid),
+            Intent::Merge => None,
+        }
+    }
+
+    pub fn is_complete(&self) -> bool {
+        matches!(self.state, State::Complete | State::Merged { .. })
+    }
+
+    /// Checks if the connection has opened yet.
+    pub fn is_connecting(&self) -> bool {
+        matches!(self.state, State::Connecting)
+    }
+
+    /// Starts the handshake once the connection has opened.
+    pub fn process_writable(&mut self) -> Result<(), Error> {
+        if self.is_connecting() {
+            let ping = local::pending::send_ping(&mut self.connection, &self.key_exchange)?;
+            self.state = State::PendingPong { original_ping: ping };
+        }
+
+        Ok(())
+    }
+
+    pub fn process_incoming_data(&mut self, connected_node: &local::connected::Node) -> Result<(), Error> {
+        match self.state.clone() {
+            State::PendingPong { original_ping } => {
+                if let Some(packet) = self.connection.receive_packet()? {
+                    if let PacketKind::Pong(pong) = packet.kind {
+                        let their_public_key = pong.public_key.clone();
+                        let challenge = local::pending::accept_pong(&mut self.connection, &connected_node.config,
+                                                                    original_ping, &self.key_exchange, pong)?;
+
+                        let proof = local::pending::prove(&connected_node.config, &challenge);
+
+                        match self.intent {
+                            Intent::Connect { .. } => {
+                                if !self.connection.supports(protocol::feature::MESH) {
+                                    self.connection.terminate("direct connections are not supported").ok();
+                                    return Err(Error::ConnectRefused { reason: "the node does not support direct connections".to_owned() });
+                                }
+
+                                self.connection.send_packet(&Packet {
+                                    path: network::Path::empty(),
+                                    kind: PacketKind::ConnectRequest(protocol::ConnectRequest {
+                                        uuid: connected_node.uuid,
+                                        proof,
+                                        identity_proof: local::pending::prove_identity(&connected_node.config,
+                                                                                       &connected_node.uuid,
+                                                                                       &their_public_key),
+                                    }),
+                                })?;
+
+                                self.state = State::PendingConnectResponse;
+                            },
+                            Intent::Merge => {
+                                if !self.connection.supports(protocol::feature::MERGE) {
+                                    self.connection.terminate("merging networks is not supported").ok();
+                                    return Err(Error::ConnectRefused { reason: "the node does not support merging networks".to_owned() });
+                                }
+
+                                self.connection.send_packet(&Packet {
+                                    path: network::Path::empty(),
+                                    kind: PacketKind::MergeRequest(protocol::MergeRequest {
+                                        uuid: connected_node.uuid,
+                                        proof,
+                                        identity_proof: local::pending::prove_identity(&connected_node.config,
+                                                                                       &connected_node.uuid,
+                                                                                       &their_public_key),
+                                        network: protocol::Network::from_network(&connected_node.network),
+                                    }),
+                                })?;
+
+                                self.state = State::PendingMergeResponse;
+                            },
+                        }
+                    } else {
+                        return Err(Error::unexpected_packet("pong", packet));
+                    }
+                }
+            },
+            State::PendingConnectResponse => {
+                if let Some(packet) = self.connection.receive_packet()? {
+                    if let PacketKind::ConnectResponse(connect_response) = packet.kind {
+                        // The address may now belong to somebody else.
+                        let expected_uuid = self.uuid().expect("only direct connections send connect requests");
+                        if connect_response.uuid != expected_uuid {
+                            self.connection.terminate("we were expecting a different node").ok();
+                            return Err(Error::ConnectRefused {
+                                reason: format!("expected node {} but found node {}", expected_uuid, connect_response.uuid),
+                            });
+                        }
+
+                        let public_key = connected_node.network.get(&expected_uuid).and_then(|node| node.public_key.clone());
+                        if !self.proves_identity(&expected_uuid, public_key.as_deref(),
+                                                 &connect_response.identity_proof) {
+                            self.connection.terminate("you could not prove who you are").ok();
+                            return Err(Error::IdentityNotProven { uuid: expected_uuid });
+                        }
+
+                        self.state = State::Complete;
+                    } else {
+                        return Err(Error::unexpected_packet("connect response", packet));
+                    }
+                }
+            },
+            State::PendingMergeResponse => {
+                if let Some(packet) = self.connection.receive_packet()? {
+                    if let PacketKind::MergeResponse(merge_response) = packet.kind {
+                        if !local::pending::is_local_node_of(&merge_response.uuid, &merge_response.network) {
+                            let reason = "your network doesn't include you";
+                            self.connection.terminate(reason).ok();
+                            return Err(Error::ConnectRefused { reason: reason.to_owned() });
+                        }
+
+                        let public_key = merge_response.network.nodes.iter()
+                            .find(|node| node.uuid == merge_response.uuid)
+                            .map(|node| node.public_key.as_slice());
+                        if !self.proves_identity(&merge_response.uuid, public_key, &merge_response.identity_proof) {
+                            self.connection.terminate("you could not prove who you are").ok();
+                            return Err(Error::IdentityNotProven { uuid: merge_response.uuid });
+                        }
+
+                        local::pending::refuse_overlap(&mut self.connection,
+                                                       connected_node.network.overlap_with(&merge_response.network))?;
+
+                        self.state = State::Merged { uuid: merge_response.uuid, network: merge_response.network };
+                    } else {
+                        return Err(Error::unexpected_packet("merge response", packet));
+                    }
+                }
+            },
+            State::Connecting | State::Complete | State::Merged { .. } => (),
+        }
+
+        Ok(())
+    }
+
+    /// Checks that the remote owns the identity key of the node it claims to be.
+    fn proves_identity(&self, uuid: &Uuid, public_key: Option<&[u8]>, identity_proof: &[u8]) -> bool {
+        public_key.is_some_and(|public_key| {
+            local::pending::verify_identity(&self.key_exchange, uuid, public_key, identity_proof)
+        })
+    }
+}
@@ -1,46 +1,79 @@
-use {Packet, PacketKind, Error};
+use {Packet, PacketKind, Error, Config};
 use network::{PendingState, Connection};
+use network::encryption::{KeyExchange, Role};
+use network::local;
 use {network, protocol};
 
+use mio::net::TcpListener;
+
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::time::SystemTime;
+
 #[derive(Debug)]
 pub struct Node
 {
     pub state: PendingState,
     pub connection: Connection,
+    /// The listener we will accept other nodes on once we have joined.
+    ///
+    /// This is empty if we are rejoining, as we are already listening.
+    pub listener: Option<TcpListener>,
+    /// The address other nodes can connect to us on.
+    pub advertised_address: Option<SocketAddr>,
+    /// The configuration to use once we have joined.
+    pub config: Config,
+    /// Our half of the key exchange for encrypting the connection.
+    key_exchange: KeyExchange,
 }
 
 impl Node
 {
-    pub fn new(connection: Connection) -> Self {
+    pub fn new(connection: Connection, listener: TcpListener, config: Config) -> Self {
         Node {
             state: PendingState::PendingPing,
             connection: connection,
+            advertised_address: local::tcp::advertised_address(&config, &listener),
+            listener: Some(listener),
+            config,
+            key_exchange: KeyExchange::new(),
+        }
+    }
+
+    /// Starts joining the network again after losing our connection to it.
+    pub fn rejoin(connection: Connection, advertised_address: Option<SocketAddr>, config: Config) -> Self {
+        Node {
+            state: PendingState::PendingPing,
+            connection,
+            listener: None,
+            advertised_address,
+            config,
+            key_exchange: KeyExchange::new(),
         }
     }
 
     pub fn advance_state(&mut self) -> Result<(), Error> {
         match self.state.clone() {
             PendingState::PendingPing => {
-                let ping = protocol::Ping {
-                    user_agent: ::user_agent(),
-                    // TODO: randomise this data
-                    data: vec![6, 2, 6, 1, 8, 8],
-                };
-
-                println!("sending ping");
-
-                self.connection.send_packet(&Packet {
-                    // FIXME: come up with a proper path
-                    path: network::Path::empty(),
-                    kind: PacketKind::Ping(ping.clone()),
-                })?;
+                let ping = send_ping(&mut self.connection, &self.key_exchange)?;
 
                 self.state = PendingState::PendingPong { original_ping: ping };
             },
-            PendingState::PendingJoinRequest => {
+            PendingState::PendingJoinRequest { challenge, their_public_key } => {
+                let proof = prove(&self.config, &challenge);
+                let identity_proof = prove_identity(&self.config, &self.config.identity.uuid, &their_public_key);
+
                 self.connection.send_packet(&Packet {
                     path: network::Path::empty(),
-                    kind: PacketKind::JoinRequest(protocol::JoinRequest),
+                    kind: PacketKind::JoinRequest(protocol::JoinRequest {
+                        proof,
+                        uuid: Some(self.config.identity.uuid),
+                        identity_proof,
+                        name: self.config.identity.name.clone(),
+                        labels: self.config.labels.clone(),
+                        address: self.advertised_address.map(|address| address.to_string()),
+                        public_key: self.config.identity.keypair.as_ref().map_or(Vec::new(), |keypair| keypair.public_key()),
+                    }),
                 })?;
                 println!("advancing from pending join request");
 
@@ -56,45 +89,29 @@ impl Node
         match self.state.clone() {
             PendingState::PendingPing => (),
             PendingState::PendingPong { original_ping } => {
-                if let Some(packet) = self.connection.receive_packet().unwrap() {
+                if let Some(packet) = self.connection.receive_packet()? {
                     if let PacketKind::Pong(pong) = packet.kind {
-                        println!("received pong");
-
-                        // Check if the echoed data is correct.
-                        if pong.data != original_ping.data {
-                            return Err(Error::InvalidPong{
-                                expected: original_ping.data.clone(),
-                                received: pong.data,
-                            });
-                        }
-
-                        // Ensure the protocol versions are compatible.
-                        if !pong.user_agent.is_compatible(&original_ping.user_agent) {
-                            // self.connection.terminate("protocol versions are not compatible")?;
-
-                            // FIXME: Remove the connection.
-                            unimplemented!();
-                        }
-
-                        self.state = PendingState::PendingJoinRequest;
+                        let their_public_key = pong.public_key.clone();
+                        let challenge = accept_pong(&mut self.connection, &self.config, original_ping, &self.key_exchange, pong)?;
+                        self.state = PendingState::PendingJoinRequest { challenge, their_public_key };
                     } else {
-                        return Err(Error::UnexpectedPacket { expected: "pong", received: packet })
+                        return Err(Error::unexpected_packet("pong", packet))
                     }
                 } else {
                     // we haven't received a full packet yet.
                 }
             },
-            PendingState::PendingJoinRequest  => (),
+            PendingState::PendingJoinRequest { .. } => (),
             PendingState::PendingJoinResponse => {
                 if let Some(packet) = self.connection.receive_packet()? {
                     if let PacketKind::JoinResponse(join_response) = packet.kind {
                         self.state = PendingState::Complete { join_response: join_response };
                     } else {
-                        return Err(Error::UnexpectedPacket { expected: "join response", received: packet })
+                        return Err(Error::unexpected_packet("join response", packet))
                     }
                 }
             },
-            PendingState::Complete { .. } => {
+            PendingState::Complete { .. } | PendingState::PeerConnected { .. } | PendingState::Merged { .. } => {
                 // nothing to do
             },
         }
@@ -103,3 +120,270 @@ impl Node
     }
 }
 
+/// Sends the `Ping` that starts a handshake with a node we connected to.
+pub fn send_ping(connection: &mut Connection, key_exchange: &KeyExchange) -> Result<protocol::Ping, Error> {
+    let ping = protocol::Ping {
+        user_agent: ::user_agent(),
+        data: network::auth::challenge(),
+        public_key: key_exchange.public_key(),
+        features: protocol::feature::all(),
+    };
+
+    println!("sending ping");
+
+    connection.send_packet(&Packet {
+        // FIXME: come up with a proper path
+        path: network::Path::empty(),
+        kind: PacketKind::Ping(ping.clone()),
+    })?;
+
+    Ok(ping)
+}
+
+/// Checks the `Pong` from a node we connected to, and encrypts the
+/// connection.
+///
+/// Returns the challenge to prove knowledge of the network key with.
+pub fn accept_pong(connection: &mut Connection,
+                   config: &Config,
+                   original_ping: protocol::Ping,
+                   key_exchange: &KeyExchange,
+                   pong: protocol::Pong) -> Result<Vec<u8>, Error> {
+    println!("received pong");
+
+    // Check if the echoed data is correct.
+    if pong.data != original_ping.data {
+        return Err(Error::InvalidPong{
+            expected: original_ping.data.clone(),
+            received: pong.data,
+        });
+    }
+
+    // Ensure the protocol versions are compatible.
+    if !pong.user_agent.is_compatible(&original_ping.user_agent) {
+        // The remote may have already hung up on us, which is fine.
+        connection.terminate("protocol versions are not compatible").ok();
+
+        return Err(Error::IncompatibleProtocol {
+            ours: original_ping.user_agent,
+            theirs: pong.user_agent,
+        });
+    }
+
+    println!("negotiated protocol features: {:?}", protocol::feature::common(&original_ping.features, &pong.features));
+
+    match config.network_key {
+        None if !pong.challenge.is_empty() => {
+            connection.terminate("we don't know the network key").ok();
+            return Err(Error::NetworkKeyRequired);
+        },
+        // A node that doesn't ask us to prove we know the key doesn't know it either.
+        Some(ref network_key) if pong.challenge.is_empty() || !network::auth::verify(network_key, &original_ping.data, &pong.proof) => {
+            connection.terminate("authentication failed").ok();
+            return Err(Error::AuthenticationFailed);
+        },
+        _ => (),
+    }
+
+    // Everything after the pong is encrypted.
+    if pong.public_key.is_empty() {
+        connection.terminate("connections must be encrypted").ok();
+        return Err(Error::EncryptionRequired);
+    }
+
+    let network_key = config.network_key.as_deref();
+    let keys = match key_exchange.complete(&pong.public_key, network_key, Role::Initiator) {
+        Some(keys) => keys,
+        None => {
+            connection.terminate("invalid public key").ok();
+            return Err(Error::InvalidPublicKey);
+        },
+    };
+
+    connection.enable_encryption(keys);
+
+    connection.user_agent = Some(pong.user_agent);
+    connection.features = pong.features;
+    Ok(pong.challenge)
+}
+
+/// Proves that we know the network key, if there is one.
+pub fn prove(config: &Config, challenge: &[u8]) -> Vec<u8> {
+    match config.network_key {
+        Some(ref network_key) if !challenge.is_empty() => network::auth::prove(network_key, challenge),
+        _ => Vec::new(),
+    }
+}
+
+/// Proves that we own a UUID to the node at the other end of a connection,
+/// if we have an identity key.
+///
+/// The public key is the ephemeral key the node sent in its `Ping` or `Pong`.
+pub fn prove_identity(config: &Config, uuid: &::uuid::Uuid, their_public_key: &[u8]) -> Vec<u8> {
+    config.identity.keypair.as_ref()
+        .and_then(|keypair| keypair.shared_secret(their_public_key))
+        .map_or(Vec::new(), |shared_secret| network::auth::prove_identity(&shared_secret, uuid))
+}
+
+/// Checks that the node at the other end of a connection owns the identity
+/// key with the given public key, and that it claimed the UUID with it.
+pub fn verify_identity(key_exchange: &KeyExchange,
+                       uuid: &::uuid::Uuid,
+                       public_key: &[u8],
+                       identity_proof: &[u8]) -> bool {
+    match key_exchange.shared_secret(public_key) {
+        Some(shared_secret) => network::auth::verify_identity(&shared_secret, uuid, identity_proof),
+        None => false,
+    }
+}
+
+/// Refuses to merge with a network that has nodes in common with ours,
+/// telling the other end why.
+pub fn refuse_overlap(connection: &mut Connection, overlap: network::Overlap) -> Result<(), Error> {
+    match overlap {
+        network::Overlap::None => Ok(()),
+        network::Overlap::Same => {
+            let reason = "we are already part of the same network";
+            connection.terminate(reason).ok();
+            Err(Error::ConnectRefused { reason: reason.to_owned() })
+        },
+        network::Overlap::Collision { uuids } => {
+            let uuid_list: Vec<_> = uuids.iter().map(|uuid| uuid.to_string()).collect();
+            connection.terminate(format!("our networks are independent but both have node(s) {}", uuid_list.join(", "))).ok();
+            Err(Error::UuidCollision { uuids })
+        },
+    }
+}
+
+/// Checks that a node's own UUID is the local node of the network it sent us.
+pub fn is_local_node_of(uuid: &::uuid::Uuid, network: &protocol::Network) -> bool {
+    network.nodes.iter().any(|node| &node.uuid == uuid && node.status.is_none())
+}
+
+/// Builds our view of the network from the response to our join request.
+pub fn joined_network(join_response: &protocol::JoinResponse, connection: Connection, now: SystemTime) -> network::Network {
+    let mut network = join_response.network.clone().into_network(now);
+    network.set_connection(&join_response.my_uuid, connection);
+
+    // The node that answered sees itself as local, but it's remote to us.
+    if let Some(responder) = network.get_mut(&join_response.my_uuid) {
+        responder.status = network::Status::Remote(Default::default());
+    }
+
+    // Add ourselves to the network.
+    network.insert(network::Node {
+        uuid: join_response.your_uuid,
+        name: None,
+        labels: BTreeMap::new(),
+        address: None,
+        connection: None,
+        status: network::Status::Local,
+        last_seen: now,
+        latency: None,
+        public_key: None,
+    });
+
+    // Connect ourselves and the node we're communicating with.
+    network.connect(&join_response.your_uuid, &join_response.my_uuid);
+
+    network
+}
+
+#[cfg(test)]
+mod test
+{
+    pub use super::*;
+    pub use network::memory::MemoryStream;
+    pub use std::time::UNIX_EPOCH;
+    use mio;
+
+    /// Sends a ping, answers it with a pong and checks that the pong is refused.
+    fn refuse_pong<F>(config: Config, build_pong: F) -> (Error, String)
+        where F: FnOnce(&protocol::Ping) -> protocol::Pong {
+        let (ours, theirs) = MemoryStream::pair();
+        let mut connection = Connection::new(mio::Token(0), Box::new(ours));
+        let mut remote = Connection::new(mio::Token(0), Box::new(theirs));
+
+        let key_exchange = KeyExchange::new();
+        let ping = send_ping(&mut connection, &key_exchange).unwrap();
+        let pong = build_pong(&ping);
+
+        let error = match accept_pong(&mut connection, &config, ping, &key_exchange, pong) {
+            Err(e) => e,
+            Ok(..) => panic!("expected the pong to be refused"),
+        };
+
+        let packets = remote.receive_packets().unwrap();
+        match packets.last().map(|packet| &packet.kind) {
+            Some(&PacketKind::Terminate(ref terminate)) => (error, terminate.reason.clone()),
+            kind => panic!("expected the connection to be terminated, got {:?}", kind),
+        }
+    }
+
+    fn pong(ping: &protocol::Ping, challenge: Vec<u8>, proof: Vec<u8>) -> protocol::Pong {
+        protocol::Pong {
+            user_agent: ::user_agent(),
+            data: ping.data.clone(),
+            challenge: challenge,
+            proof: proof,
+            public_key: KeyExchange::new().public_key(),
+            features: protocol::feature::all(),
+        }
+    }
+
+    fn config_with_key(network_key: &str) -> Config {
+        let mut config = Config::default();
+        config.network_key = Some(network_key.to_owned());
+        config
+    }
+
+    #[test]
+    fn plaintext_pongs_are_refused() {
+        let (error, reason) = refuse_pong(Config::default(), |ping| {
+            protocol::Pong { public_key: Vec::new(), ..pong(ping, Vec::new(), Vec::new()) }
+        });
+
+        match error { Error::EncryptionRequired => (), e => panic!("unexpected error {:?}", e) }
+        assert_eq!(reason, "connections must be encrypted");
+    }
+
+    #[test]
+    fn nodes_that_do_not_ask_for_the_network_key_are_refused() {
+        let (error, reason) = refuse_pong(config_with_key("hunter2"), |ping| pong(ping, Vec::new(), Vec::new()));
+
+        match error { Error::AuthenticationFailed => (), e => panic!("unexpected error {:?}", e) }
+        assert_eq!(reason, "authentication failed");
+    }
+
+    #[test]
+    fn nodes_that_do_not_know_the_network_key_are_refused() {
+        let (error, _) = refuse_pong(config_with_key("hunter2"), |ping| {
+            pong(ping, network::auth::challenge(), network::auth::prove("hunter3", &ping.data))
+        });
+
+        match error { Error::AuthenticationFailed => (), e => panic!("unexpected error {:?}", e) }
+    }
+
+    #[test]
+    fn the_node_we_joined_through_is_remote() {
+        let (ours, _theirs) = MemoryStream::pair();
+        let responder = ::uuid::Uuid::new_v4();
+        let joiner = ::uuid::Uuid::new_v4();
+
+        let join_response = protocol::JoinResponse {
+            your_uuid: joiner,
+            my_uuid: responder,
+            // The responder describes itself as local.
+            network: protocol::Network::from_network(&network::Network::new(responder, UNIX_EPOCH)),
+        };
+
+        let network = joined_network(&join_response, Connection::new(mio::Token(0), Box::new(ours)), UNIX_EPOCH);
+
+        match network.get(&responder).unwrap().status {
+            network::Status::Remote(..) => (),
+            network::Status::Local => panic!("the node we joined through should be remote"),
+        }
+        assert!(network.get(&responder).unwrap().connection.is_some());
+        assert_eq!(network.get(&joiner).unwrap().status, network::Status::Local);
+    }
+}
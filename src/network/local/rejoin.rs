@@ -0,0 +1,82 @@
+use {Config, Error};
+use network::{local, Backoff};
+
+use std::net::SocketAddr;
+
+/// Joining the network again after we lost our connection to it.
+#[derive(Debug)]
+pub struct Rejoin
+{
+    pub backoff: Backoff,
+    /// The join that is in progress, if any.
+    pub attempt: Option<local::pending::Node>,
+    /// How many attempts we have made.
+    attempt_count: usize,
+    /// How many attempts we make before giving up.
+    max_attempts: usize,
+}
+
+impl Rejoin
+{
+    pub fn new(config: &Config) -> Self {
+        Rejoin {
+            backoff: Backoff::new(config.reconnect_delay, config.max_reconnect_delay, &config.clock, &config.random),
+            attempt: None,
+            attempt_count: 0,
+            max_attempts: config.max_rejoin_attempts,
+        }
+    }
+
+    /// Picks the address to try next, going through each in turn.
+    pub fn next_address(&mut self, addresses: &[SocketAddr]) -> Option<SocketAddr> {
+        if addresses.is_empty() { return None }
+
+        let address = addresses[self.attempt_count % addresses.len()];
+        self.attempt_count += 1;
+
+        Some(address)
+    }
+
+    /// Gives up on the current attempt and waits before trying again.
+    pub fn failed(&mut self, error: &Error) {
+        self.attempt = None;
+
+        if self.has_given_up() {
+            println!("could not rejoin the network ({:?}), giving up after {} attempts and carrying on as a network of our own",
+                     error, self.attempt_count);
+            return;
+        }
+
+        let wait = self.backoff.failed();
+        println!("could not rejoin the network ({:?}), trying again in {}ms",
+                 error, wait.as_secs() * 1000 + wait.subsec_millis() as u64);
+    }
+
+    /// Checks if every attempt we are allowed has failed.
+    pub fn has_given_up(&self) -> bool {
+        self.attempt.is_none() && self.attempt_count >= self.max_attempts
+    }
+}
+
+#[cfg(test)]
+mod test
+{
+    pub use super::*;
+
+    #[test]
+    fn rejoining_stops_after_the_maximum_number_of_attempts() {
+        let mut config = Config::default();
+        config.max_rejoin_attempts = 2;
+
+        let addresses = ["127.0.0.1:4000".parse().unwrap()];
+        let mut rejoin = Rejoin::new(&config);
+
+        for _ in 0..2 {
+            assert!(!rejoin.has_given_up());
+            assert!(rejoin.next_address(&addresses).is_some());
+            rejoin.failed(&Error::Stop { reason: "the connection was refused" });
+        }
+
+        assert!(rejoin.has_given_up());
+    }
+}
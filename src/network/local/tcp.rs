@@ -1,21 +1,40 @@
-use Error;
+use {Error, Config};
 use network::local;
 
 use mio;
 use mio::net::*;
 use std;
 
-/// Create a new tcp listener locally.
-pub fn bind<A>(poll: &mio::Poll, addr: A) -> Result<TcpListener, Error>
-    where A: std::net::ToSocketAddrs {
-    let mut addresses = addr.to_socket_addrs()?;
-    let address = addresses.next().expect("could not resolve address");
+use std::net::SocketAddr;
 
-    let listener = TcpListener::bind(&address)?;
+/// Create a new tcp listener on the address from the configuration.
+///
+/// If no port was configured and the default port is taken, we listen
+/// on a random port instead.
+pub fn listen(config: &Config) -> Result<TcpListener, std::io::Error> {
+    match TcpListener::bind(&config.listen_address()) {
+        Err(ref e) if e.kind() == std::io::ErrorKind::AddrInUse && config.port.is_none() => {
+            println!("there is already something listening on port {} - listening on a random port instead", ::SERVER_PORT);
+            TcpListener::bind(&SocketAddr::new(config.bind_address, 0))
+        },
+        result => result,
+    }
+}
 
-    poll.register(&listener, local::node::SERVER_TOKEN, mio::Ready::readable(),
+/// Start receiving connections from a listener.
+pub fn register(poll: &mio::Poll, listener: &TcpListener) -> Result<(), Error> {
+    poll.register(listener, local::node::SERVER_TOKEN, mio::Ready::readable(),
         mio::PollOpt::edge())?;
 
-    Ok(listener)
+    Ok(())
 }
 
+/// The address that other nodes can reach us on.
+///
+/// We can't know this if we are listening on every interface, unless
+/// it is configured.
+pub fn advertised_address(config: &Config, listener: &TcpListener) -> Option<SocketAddr> {
+    config.advertised_address.or_else(|| {
+        listener.local_addr().ok().filter(|address| !address.ip().is_unspecified())
+    })
+}
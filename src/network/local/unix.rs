@@ -0,0 +1,128 @@
+use {Error, Config};
+use network::local;
+
+use mio;
+use mio_uds;
+
+use std::fs;
+use std::io;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+
+/// A Unix domain socket that nodes and clients on the same host can connect to.
+#[derive(Debug)]
+pub struct Listener
+{
+    pub listener: mio_uds::UnixListener,
+    /// Where the socket lives, so it can be cleaned up when we stop.
+    pub path: PathBuf,
+}
+
+impl Listener
+{
+    /// Listens on the socket at a path.
+    ///
+    /// A socket left behind by a node that didn't stop cleanly is replaced,
+    /// but one that another node is still listening on isn't. Anything at
+    /// the path that isn't a socket is left alone.
+    pub fn bind<P>(path: P) -> io::Result<Self>
+        where P: AsRef<Path> {
+        let path = path.as_ref();
+
+        if let Ok(metadata) = fs::symlink_metadata(path) {
+            if !metadata.file_type().is_socket() {
+                return Err(io::Error::new(io::ErrorKind::AlreadyExists,
+                                          format!("{} already exists and is not a socket", path.display())));
+            }
+
+            if UnixStream::connect(path).is_ok() {
+                return Err(io::Error::new(io::ErrorKind::AddrInUse,
+                                          format!("another node is already listening on {}", path.display())));
+            }
+
+            fs::remove_file(path)?;
+        }
+
+        Ok(Listener {
+            listener: mio_uds::UnixListener::bind(path)?,
+            path: path.to_owned(),
+        })
+    }
+}
+
+impl Drop for Listener
+{
+    fn drop(&mut self) {
+        // There's nothing to do if this fails, the next node to bind here will replace it.
+        fs::remove_file(&self.path).ok();
+    }
+}
+
+/// Listens on the Unix domain socket from the configuration, if there is one.
+///
+/// Nodes still work without it, so failing to listen is only a warning.
+pub fn start(poll: &mio::Poll, config: &Config) -> Option<Listener> {
+    let path = config.unix_socket.as_ref()?;
+
+    match Listener::bind(path).map_err(Error::from).and_then(|listener| register(poll, &listener).map(|_| listener)) {
+        Ok(listener) => {
+            println!("listening on {}{}", ::network::transport::UNIX_PREFIX, path.display());
+            Some(listener)
+        },
+        Err(e) => {
+            println!("warning: could not listen on {}, local clients won't be able to connect to us: {:?}", path.display(), e);
+            None
+        },
+    }
+}
+
+/// Start receiving connections from a listener.
+pub fn register(poll: &mio::Poll, listener: &Listener) -> Result<(), Error> {
+    poll.register(&listener.listener, local::node::UNIX_SERVER_TOKEN, mio::Ready::readable(),
+        mio::PollOpt::edge())?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test
+{
+    pub use super::*;
+    pub use std::env;
+
+    fn socket_path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("parapet-test-{}-{}.sock", name, ::std::process::id()))
+    }
+
+    #[test]
+    fn stale_sockets_are_replaced() {
+        let path = socket_path("stale");
+
+        drop(::std::os::unix::net::UnixListener::bind(&path).unwrap());
+        assert!(path.exists());
+
+        let listener = Listener::bind(&path).unwrap();
+        drop(listener);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn sockets_in_use_are_not_replaced() {
+        let path = socket_path("in-use");
+
+        let _listener = Listener::bind(&path).unwrap();
+        assert_eq!(Listener::bind(&path).unwrap_err().kind(), io::ErrorKind::AddrInUse);
+    }
+
+    #[test]
+    fn files_that_are_not_sockets_are_not_replaced() {
+        let path = socket_path("not-a-socket");
+
+        fs::write(&path, "important").unwrap();
+        assert_eq!(Listener::bind(&path).unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "important");
+
+        fs::remove_file(&path).unwrap();
+    }
+}
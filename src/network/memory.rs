@@ -0,0 +1,210 @@
+//! A transport that never leaves the process.
+//!
+//! Both ends of a memory stream live in the same process, which lets tests
+//! connect nodes together without opening any sockets.
+
+use network::Transport;
+
+use mio::{Evented, Poll, PollOpt, Ready, Registration, SetReadiness, Token};
+
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr};
+use std::sync::{Arc, Mutex};
+
+/// One end of an in-process stream.
+#[derive(Debug)]
+pub struct MemoryStream
+{
+    /// The data sent to us by the other end.
+    incoming: Arc<Mutex<Pipe>>,
+    /// The data we have sent to the other end.
+    outgoing: Arc<Mutex<Pipe>>,
+
+    registration: Registration,
+    /// Tells the other end that it has data to read.
+    remote_readiness: SetReadiness,
+}
+
+/// Data travelling in one direction.
+#[derive(Debug, Default)]
+struct Pipe
+{
+    data: VecDeque<u8>,
+    /// Whether either end has hung up.
+    closed: bool,
+}
+
+impl MemoryStream
+{
+    /// Creates both ends of a stream.
+    pub fn pair() -> (Self, Self) {
+        let a_to_b = Arc::new(Mutex::new(Pipe::default()));
+        let b_to_a = Arc::new(Mutex::new(Pipe::default()));
+
+        let (a_registration, a_readiness) = Registration::new2();
+        let (b_registration, b_readiness) = Registration::new2();
+
+        // There is always room to write.
+        a_readiness.set_readiness(Ready::writable()).unwrap();
+        b_readiness.set_readiness(Ready::writable()).unwrap();
+
+        let a = MemoryStream {
+            incoming: b_to_a.clone(),
+            outgoing: a_to_b.clone(),
+            registration: a_registration,
+            remote_readiness: b_readiness,
+        };
+
+        let b = MemoryStream {
+            incoming: a_to_b,
+            outgoing: b_to_a,
+            registration: b_registration,
+            remote_readiness: a_readiness,
+        };
+
+        (a, b)
+    }
+
+    /// Tells the other end something has happened.
+    fn wake_remote(&self) {
+        // The other end may not be registered with a poll, in which case
+        // it will find out next time it reads.
+        self.remote_readiness.set_readiness(Ready::readable() | Ready::writable()).ok();
+    }
+}
+
+impl Read for MemoryStream
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut incoming = self.incoming.lock().unwrap();
+
+        // The protocol transport panics on `WouldBlock`, so running out of
+        // data looks like the end of the stream. Hang ups are noticed by peeking.
+        if incoming.data.is_empty() { return Ok(0) }
+
+        let size = ::std::cmp::min(buf.len(), incoming.data.len());
+        for (byte, data) in buf.iter_mut().zip(incoming.data.drain(..size)) {
+            *byte = data;
+        }
+
+        Ok(size)
+    }
+}
+
+impl Write for MemoryStream
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        {
+            let mut outgoing = self.outgoing.lock().unwrap();
+            if outgoing.closed { return Err(io::ErrorKind::BrokenPipe.into()) }
+
+            outgoing.data.extend(buf);
+        }
+
+        self.wake_remote();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> { Ok(()) }
+}
+
+impl Transport for MemoryStream
+{
+    fn peek(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let incoming = self.incoming.lock().unwrap();
+
+        if incoming.data.is_empty() {
+            return if incoming.closed { Ok(0) } else { Err(io::ErrorKind::WouldBlock.into()) };
+        }
+
+        let size = ::std::cmp::min(buf.len(), incoming.data.len());
+        for (byte, &data) in buf.iter_mut().zip(incoming.data.iter()) {
+            *byte = data;
+        }
+
+        Ok(size)
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        if how != Shutdown::Read {
+            self.outgoing.lock().unwrap().closed = true;
+        }
+
+        if how != Shutdown::Write {
+            self.incoming.lock().unwrap().closed = true;
+        }
+
+        self.wake_remote();
+        Ok(())
+    }
+
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+}
+
+impl Drop for MemoryStream
+{
+    fn drop(&mut self) {
+        self.shutdown(Shutdown::Both).ok();
+    }
+}
+
+impl Evented for MemoryStream
+{
+    fn register(&self, poll: &Poll, token: Token, interest: Ready, opts: PollOpt) -> io::Result<()> {
+        self.registration.register(poll, token, interest, opts)
+    }
+
+    fn reregister(&self, poll: &Poll, token: Token, interest: Ready, opts: PollOpt) -> io::Result<()> {
+        self.registration.reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        <Registration as Evented>::deregister(&self.registration, poll)
+    }
+}
+
+#[cfg(test)]
+mod test
+{
+    pub use super::*;
+    pub use std::time::Duration;
+
+    #[test]
+    fn data_written_to_one_end_is_read_from_the_other() {
+        let (mut a, mut b) = MemoryStream::pair();
+        let mut buf = [0; 4];
+
+        assert_eq!(b.peek(&mut buf).unwrap_err().kind(), io::ErrorKind::WouldBlock);
+
+        a.write_all(&[1, 2, 3]).unwrap();
+        assert_eq!(b.peek(&mut buf).unwrap(), 3);
+        assert_eq!(b.read(&mut buf).unwrap(), 3);
+        assert_eq!(&buf[..3], &[1, 2, 3]);
+        assert_eq!(b.peek(&mut buf).unwrap_err().kind(), io::ErrorKind::WouldBlock);
+    }
+
+    #[test]
+    fn dropping_one_end_hangs_up_on_the_other() {
+        let (mut a, b) = MemoryStream::pair();
+        drop(b);
+
+        assert_eq!(a.peek(&mut [0]).unwrap(), 0);
+        assert_eq!(a.write(&[1]).unwrap_err().kind(), io::ErrorKind::BrokenPipe);
+    }
+
+    #[test]
+    fn writing_wakes_up_the_other_end() {
+        let (mut a, b) = MemoryStream::pair();
+        let poll = Poll::new().unwrap();
+        let mut events = ::mio::Events::with_capacity(4);
+
+        poll.register(&b, Token(7), Ready::readable(), PollOpt::edge()).unwrap();
+        a.write_all(&[1]).unwrap();
+
+        poll.poll(&mut events, Some(Duration::from_millis(100))).unwrap();
+        assert!(events.iter().any(|event| event.token() == Token(7) && event.readiness().is_readable()));
+    }
+}
@@ -4,7 +4,16 @@ pub use self::path::Path;
 pub use self::network::*;
 pub use self::node::Node;
 pub use self::notify::Notify;
+pub use self::heartbeat::Heartbeat;
+pub use self::backoff::Backoff;
 pub use self::status::Status;
+pub use self::discovery::Discovery;
+pub use self::routing::RoutingTable;
+pub use self::broadcast::Broadcasts;
+pub use self::drops::Drops;
+pub use self::reliable::Reliable;
+pub use self::transport::{Transport, Address};
+pub use self::memory::MemoryStream;
 
 pub mod local;
 pub mod remote;
@@ -14,5 +23,18 @@ pub mod path;
 pub mod network;
 pub mod node;
 pub mod notify;
+pub mod heartbeat;
+pub mod backoff;
 pub mod status;
+pub mod auth;
+pub mod encryption;
+pub mod discovery;
+pub mod routing;
+pub mod broadcast;
+pub mod drops;
+pub mod reliable;
+pub mod transport;
+pub mod memory;
+#[cfg(test)]
+pub mod simulation;
 
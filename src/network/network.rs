@@ -1,16 +1,64 @@
-use network::{Node, Connection, Path, Status};
-use std::collections::{HashMap, VecDeque};
+use network::{Node, Connection, Path, Status, RoutingTable};
+use protocol;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 
 use uuid::Uuid;
-use graphsearch;
 
-pub struct Weight(f32);
+use std::time::{Duration, SystemTime};
 
+/// The weight of an edge that hasn't been measured yet.
+///
+/// This is about what a round trip on a local network takes.
+const UNMEASURED_WEIGHT: Weight = Weight(1.0);
+
+/// The cost of sending packets over an edge, which is the round-trip
+/// latency of the edge in milliseconds.
+#[derive(Copy, Clone, Debug, PartialEq, PartialOrd)]
+pub struct Weight(pub f32);
+
+impl Weight
+{
+    pub fn from_latency(latency: Duration) -> Self {
+        Weight(latency.as_secs() as f32 * 1000.0 + latency.subsec_nanos() as f32 / 1_000_000.0)
+    }
+
+    /// The cost of the edge in a graph search, in whole microseconds.
+    ///
+    /// Every edge costs something, so that free edges don't make paths
+    /// with more hops look as good as paths with fewer.
+    pub fn cost(&self) -> i32 {
+        ::std::cmp::max(1, (self.0 * 1000.0) as i32)
+    }
+}
+
+/// Our view of the network.
+///
+/// Nodes and edges should be added and removed through the methods here
+/// rather than directly, so that the routing table is rebuilt.
 #[derive(Debug)]
 pub struct Network
 {
-    pub nodes: HashMap<Uuid, Node>,
+    /// Kept in order of UUID, so that every node goes through them in the same order.
+    pub nodes: BTreeMap<Uuid, Node>,
     pub edges: Vec<Edge>,
+    /// The paths from us to every other node, if they have been found
+    /// since the network last changed.
+    routing_table: Option<RoutingTable>,
+    /// The trees that broadcasts from each node are passed along, for
+    /// the nodes that have broadcast since the network last changed.
+    spanning_trees: HashMap<Uuid, RoutingTable>,
+}
+
+/// How another network relates to ours.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Overlap
+{
+    /// The networks have no nodes in common.
+    None,
+    /// The other network is our own network.
+    Same,
+    /// The networks are independent, but have nodes with the same UUIDs.
+    Collision { uuids: Vec<Uuid> },
 }
 
 pub struct Entry<'a>
@@ -22,11 +70,7 @@ pub struct Entry<'a>
 impl<'a> Entry<'a>
 {
     pub fn remove(self) -> Node {
-        self.network.edges = self.network.edges.iter().cloned().filter(|edge| {
-            !edge.connected_to(&self.uuid)
-        }).collect();
-
-        self.network.nodes.remove(&self.uuid).unwrap()
+        self.network.remove(&self.uuid).unwrap()
     }
 
     pub fn get(&self) -> &Node {
@@ -42,11 +86,13 @@ impl<'a> Entry<'a>
 ///
 /// Node `a` is always the smaller UUID, and node `b` is
 /// always the bigger one.
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug)]
 pub struct Edge
 {
     pub a: Uuid,
     pub b: Uuid,
+    /// The measured latency of the edge, if it has been measured.
+    pub weight: Option<Weight>,
 }
 
 impl Edge
@@ -58,9 +104,20 @@ impl Edge
         Edge {
             a: sorted[0],
             b: sorted[1],
+            weight: None,
         }
     }
 
+    pub fn with_weight(mut self, weight: Weight) -> Edge {
+        self.weight = Some(weight);
+        self
+    }
+
+    /// The weight to use for the edge, whether it has been measured or not.
+    pub fn effective_weight(&self) -> Weight {
+        self.weight.unwrap_or(UNMEASURED_WEIGHT)
+    }
+
     pub fn connected_to(&self, uuid: &Uuid) -> bool {
         [&self.a, &self.b].iter().any(|adjacent| &uuid == adjacent)
     }
@@ -70,21 +127,37 @@ impl Edge
     }
 }
 
+/// Edges are the same if they join the same nodes, whatever their weight.
+impl PartialEq for Edge
+{
+    fn eq(&self, other: &Edge) -> bool {
+        self.a == other.a && self.b == other.b
+    }
+}
+
 impl Network
 {
     pub fn empty() -> Self {
         Network {
-            nodes: HashMap::new(),
+            nodes: BTreeMap::new(),
             edges: Vec::new(),
+            routing_table: None,
+            spanning_trees: HashMap::new(),
         }
     }
 
-    pub fn new(your_uuid: Uuid) -> Self {
+    pub fn new(your_uuid: Uuid, now: SystemTime) -> Self {
         let mut network = Network::empty();
         network.insert(Node {
             uuid: your_uuid,
+            name: None,
+            labels: BTreeMap::new(),
+            address: None,
             connection: None,
             status: Status::Local,
+            last_seen: now,
+            latency: None,
+            public_key: None,
         });
 
         network
@@ -92,6 +165,104 @@ impl Network
 
     pub fn insert(&mut self, node: Node) {
         self.nodes.insert(node.uuid.clone(), node);
+        self.topology_changed();
+    }
+
+    /// Removes a node and all of its edges from the network.
+    pub fn remove(&mut self, uuid: &Uuid) -> Option<Node> {
+        self.edges.retain(|edge| !edge.connected_to(uuid));
+        self.topology_changed();
+        self.nodes.remove(uuid)
+    }
+
+    /// Removes all nodes that can no longer be reached from a node.
+    ///
+    /// Returns the nodes that were removed.
+    pub fn prune_unreachable(&mut self, from: &Uuid) -> Vec<Node> {
+        let reachable = self.reachable_from(from);
+
+        let unreachable_uuids: Vec<_> = self.nodes.keys()
+            .filter(|uuid| !reachable.contains(uuid))
+            .cloned()
+            .collect();
+
+        unreachable_uuids.iter().filter_map(|uuid| self.remove(uuid)).collect()
+    }
+
+    /// Works out how another network relates to ours.
+    ///
+    /// Independent networks never share nodes, so a network with nodes in
+    /// common is either our own network or has nodes whose UUIDs clash with
+    /// ours. It is only our own network if it knows about our local node,
+    /// with the same identity key.
+    pub fn overlap_with(&self, other: &protocol::Network) -> Overlap {
+        let shared_uuids: Vec<_> = other.nodes.iter()
+            .filter(|node| self.nodes.contains_key(&node.uuid))
+            .map(|node| node.uuid)
+            .collect();
+
+        if shared_uuids.is_empty() { return Overlap::None }
+
+        let knows_us = self.nodes.values().filter(|node| node.status == Status::Local).any(|local_node| {
+            other.nodes.iter().any(|node| {
+                // Nodes without identity keys can't be told apart.
+                node.uuid == local_node.uuid &&
+                    local_node.public_key.as_ref().is_none_or(|key| node.public_key.is_empty() || key == &node.public_key)
+            })
+        });
+
+        if knows_us { Overlap::Same } else { Overlap::Collision { uuids: shared_uuids } }
+    }
+
+    /// Adds the nodes and edges of another network to ours.
+    ///
+    /// Nodes we already know about are left alone. Returns the UUIDs of
+    /// the nodes that were added.
+    pub fn merge(&mut self, other: protocol::Network, now: SystemTime) -> Vec<Uuid> {
+        let mut added_uuids = Vec::new();
+
+        for node in other.nodes {
+            if self.nodes.contains_key(&node.uuid) { continue }
+
+            let mut node = node.into_node(now);
+
+            // The node that sent the network sees itself as local, but it's remote to us.
+            if node.status == Status::Local {
+                node.status = Status::Remote(Default::default());
+            }
+
+            added_uuids.push(node.uuid);
+            self.insert(node);
+        }
+
+        for edge in other.edges {
+            if self.nodes.contains_key(&edge.a) && self.nodes.contains_key(&edge.b) {
+                self.add_edge(edge.into());
+            }
+        }
+
+        added_uuids
+    }
+
+    /// Gets the set of nodes that can be reached from a node.
+    pub fn reachable_from(&self, from: &Uuid) -> HashSet<Uuid> {
+        let mut reachable = HashSet::new();
+        let mut queue = VecDeque::new();
+
+        if self.nodes.contains_key(from) {
+            reachable.insert(*from);
+            queue.push_back(*from);
+        }
+
+        while let Some(uuid) = queue.pop_front() {
+            for &sibling in self.siblings(&uuid) {
+                if reachable.insert(sibling) {
+                    queue.push_back(sibling);
+                }
+            }
+        }
+
+        reachable
     }
 
     pub fn get(&self, uuid: &Uuid) -> Option<&Node> {
@@ -102,6 +273,14 @@ impl Network
         self.nodes.get_mut(uuid)
     }
 
+    /// Describes a node for log messages, by name if it has one.
+    pub fn describe(&self, uuid: &Uuid) -> String {
+        match self.get(uuid) {
+            Some(node) => node.to_string(),
+            None => uuid.to_string(),
+        }
+    }
+
     pub fn node_count(&self) -> usize {
         self.nodes.len()
     }
@@ -125,11 +304,33 @@ impl Network
     }
 
     pub fn connect(&mut self, a: &Uuid, b: &Uuid) {
-        let edge = Edge::new(a.clone(), b.clone());
+        self.add_edge(Edge::new(*a, *b));
+    }
+
+    /// Adds an edge, or updates the weight of the edge if we already have it.
+    pub fn add_edge(&mut self, edge: Edge) {
+        self.topology_changed();
+
+        if let Some(existing_edge) = self.edges.iter_mut().find(|e| *e == &edge) {
+            if edge.weight.is_some() {
+                existing_edge.weight = edge.weight;
+            }
 
-        if self.edges.iter().find(|&e| e == &edge).is_none() {
-            self.edges.push(edge);
+            return;
         }
+
+        self.edges.push(edge);
+    }
+
+    pub fn edge(&self, a: &Uuid, b: &Uuid) -> Option<&Edge> {
+        let edge = Edge::new(*a, *b);
+        self.edges.iter().find(|&e| e == &edge)
+    }
+
+    pub fn disconnect(&mut self, a: &Uuid, b: &Uuid) {
+        let edge = Edge::new(*a, *b);
+        self.edges.retain(|e| e != &edge);
+        self.topology_changed();
     }
 
     pub fn siblings(&self, node: &Uuid) -> Vec<&Uuid> {
@@ -146,34 +347,245 @@ impl Network
         self.nodes.get_mut(uuid).unwrap().connection = Some(connection);
     }
 
-    pub fn route(&self, from: &Uuid, to: &Uuid) -> Path {
-        Path::new(self.shortest_path(from, to))
+    /// Gets the lowest-latency path between two nodes.
+    ///
+    /// The paths from a node are found all at once and kept until the
+    /// network changes, so this is cheap to call for every packet.
+    pub fn route(&mut self, from: &Uuid, to: &Uuid) -> Option<Path> {
+        self.routing_table(from).path_to(to)
     }
 
-    pub fn build_graph(&self) -> graphsearch::Graph<Uuid> {
-        let raw_graph: Vec<_> = self.nodes.values().map(|node| {
-            graphsearch::Node {
-                content: node.uuid.clone(),
-                adjacent: self.siblings(&node.uuid).into_iter().map(|&sibling| {
-                    let sibling_idx = self.nodes.values().position(|n| n.uuid == sibling).unwrap();
-
-                    graphsearch::Vertex { cost: 1, node: sibling_idx }
-                }).collect(),
-            }
-        }).collect();
+    /// Gets the paths from a node to every other node.
+    pub fn routing_table(&mut self, from: &Uuid) -> &RoutingTable {
+        if self.routing_table.as_ref().is_none_or(|table| &table.from != from) {
+            self.routing_table = Some(RoutingTable::build(self, from));
+        }
 
-        graphsearch::Graph::new(raw_graph)
+        self.routing_table.as_ref().unwrap()
     }
 
-    pub fn shortest_path(&self, from: &Uuid, to: &Uuid) -> VecDeque<Uuid> {
-        println!("route from {} to {}", from, to);
-        let graph = self.build_graph();
+    /// Gets the tree that broadcasts from a node are passed along.
+    pub fn spanning_tree(&mut self, root: &Uuid) -> &RoutingTable {
+        if !self.spanning_trees.contains_key(root) {
+            let tree = RoutingTable::spanning_tree(self, root);
+            self.spanning_trees.insert(*root, tree);
+        }
 
-        let from_idx = self.nodes.values().position(|node| &node.uuid == from).unwrap();
+        &self.spanning_trees[root]
+    }
 
-        graph.search(from_idx, to.clone()).expect("no route exists").into_iter().map(|idx| {
-            self.nodes.values().nth(idx).unwrap().uuid
-        }).rev().collect()
+    /// Forgets all of the paths we have found, as they may have changed.
+    fn topology_changed(&mut self) {
+        self.routing_table = None;
+        self.spanning_trees.clear();
     }
 }
 
+
+#[cfg(test)]
+mod test
+{
+    pub use network::{Network, Node, Status, Edge, Weight, Overlap};
+    pub use protocol;
+    pub use uuid::Uuid;
+    pub use std::collections::{BTreeMap, VecDeque};
+    pub use std::time::UNIX_EPOCH;
+
+    fn setup() -> (Network, Uuid, Uuid, Uuid) {
+        let node1 = Uuid::parse_str("123e4567-e89b-12d3-a456-426655440000").unwrap();
+        let node2 = Uuid::parse_str("2c6bb858-72ea-4db6-a9ab-6ec0ca1f18ab").unwrap();
+        let node3 = Uuid::parse_str("3042be80-4d2b-4cce-b30c-3142e3035720").unwrap();
+
+        let mut network = Network::new(node1, UNIX_EPOCH);
+
+        for &uuid in [node2, node3].iter() {
+            network.insert(Node {
+                uuid: uuid,
+                name: None,
+                labels: BTreeMap::new(),
+                address: None,
+                connection: None,
+                status: Status::Remote(Default::default()),
+                last_seen: UNIX_EPOCH,
+                latency: None,
+                public_key: None,
+            });
+        }
+
+        network.connect(&node1, &node2);
+        network.connect(&node2, &node3);
+
+        (network, node1, node2, node3)
+    }
+
+    #[test]
+    fn remove_deletes_edges_of_the_node() {
+        let (mut network, node1, node2, _) = setup();
+
+        network.remove(&node2).unwrap();
+        assert!(network.siblings(&node1).is_empty());
+        assert!(network.edges.is_empty());
+    }
+
+    #[test]
+    fn prune_unreachable_removes_nodes_behind_a_removed_node() {
+        let (mut network, node1, node2, node3) = setup();
+
+        network.remove(&node2).unwrap();
+        let pruned: Vec<_> = network.prune_unreachable(&node1).into_iter().map(|n| n.uuid).collect();
+
+        assert_eq!(pruned, vec![node3]);
+        assert_eq!(network.node_count(), 1);
+    }
+
+    #[test]
+    fn prune_unreachable_keeps_connected_nodes() {
+        let (mut network, node1, _, _) = setup();
+
+        assert!(network.prune_unreachable(&node1).is_empty());
+        assert_eq!(network.node_count(), 3);
+    }
+
+    #[test]
+    fn nodes_are_described_by_name_if_they_have_one() {
+        let (mut network, node1, node2, _) = setup();
+        network.get_mut(&node2).unwrap().name = Some("build-box-2".to_owned());
+
+        assert_eq!(network.describe(&node1), node1.to_string());
+        assert_eq!(network.describe(&node2), format!("build-box-2 ({})", node2));
+    }
+
+    #[test]
+    fn merge_adds_the_nodes_and_edges_of_the_other_network() {
+        let (mut network, node1, _, node3) = setup();
+        let other_node1 = Uuid::parse_str("4e1c2f5a-7b3d-4c8e-9a1f-2b3c4d5e6f70").unwrap();
+        let other_node2 = Uuid::parse_str("5d2e8a41-93c6-4f0b-8e27-6a1b9c3d4e5f").unwrap();
+
+        let mut other = Network::new(other_node1, UNIX_EPOCH);
+        other.insert(Node {
+            uuid: other_node2,
+            name: None,
+            labels: BTreeMap::new(),
+            address: None,
+            connection: None,
+            status: Status::Remote(Default::default()),
+            last_seen: UNIX_EPOCH,
+            latency: None,
+            public_key: None,
+        });
+        other.get_mut(&other_node2).unwrap().status.expect_remote_mut().work =
+            ::network::remote::status::Work::Available { have_asked_for_work: false };
+        other.connect(&other_node1, &other_node2);
+
+        let mut snapshot = protocol::Network::from_network(&other);
+        snapshot.edges.push(protocol::Edge::from_edge(&Edge::new(node3, other_node1)));
+
+        assert_eq!(network.overlap_with(&snapshot), Overlap::None);
+
+        let mut added = network.merge(snapshot, UNIX_EPOCH);
+        added.sort();
+        assert_eq!(added, vec![other_node1, other_node2]);
+
+        assert_eq!(network.node_count(), 5);
+        assert!(network.reachable_from(&node1).contains(&other_node2));
+        assert_eq!(network.get(&other_node1).unwrap().status, Status::Remote(Default::default()));
+        assert!(network.get(&other_node2).unwrap().can_ask_for_work());
+        assert_eq!(network.get(&node1).unwrap().status, Status::Local);
+    }
+
+    #[test]
+    fn networks_that_know_about_us_are_our_own_network() {
+        let (network, _, _, _) = setup();
+        let snapshot = protocol::Network::from_network(&network);
+
+        assert_eq!(network.overlap_with(&snapshot), Overlap::Same);
+    }
+
+    #[test]
+    fn independent_networks_with_the_same_uuids_collide() {
+        let (network, node1, node2, _) = setup();
+
+        let mut other = Network::new(Uuid::parse_str("4e1c2f5a-7b3d-4c8e-9a1f-2b3c4d5e6f70").unwrap(), UNIX_EPOCH);
+        other.insert(Node { status: Status::Remote(Default::default()), ..Network::new(node2, UNIX_EPOCH).remove(&node2).unwrap() });
+        assert_eq!(network.overlap_with(&protocol::Network::from_network(&other)), Overlap::Collision { uuids: vec![node2] });
+
+        // A node with our UUID but a different identity key isn't us.
+        let mut network = network;
+        network.get_mut(&node1).unwrap().public_key = Some(vec![1; 32]);
+        let mut impostor = Network::new(node1, UNIX_EPOCH);
+        impostor.get_mut(&node1).unwrap().public_key = Some(vec![2; 32]);
+        assert_eq!(network.overlap_with(&protocol::Network::from_network(&impostor)), Overlap::Collision { uuids: vec![node1] });
+    }
+
+    #[test]
+    fn routes_take_the_fewest_hops_when_nothing_has_been_measured() {
+        let (mut network, node1, _, node3) = setup();
+        network.connect(&node1, &node3);
+
+        assert_eq!(network.route(&node1, &node3).unwrap().hops, vec![node3, node1].into_iter().collect::<VecDeque<_>>());
+    }
+
+    #[test]
+    fn routes_take_the_lowest_latency_path() {
+        let (mut network, node1, node2, node3) = setup();
+        network.add_edge(Edge::new(node1, node2).with_weight(Weight(2.0)));
+        network.add_edge(Edge::new(node2, node3).with_weight(Weight(3.0)));
+        network.add_edge(Edge::new(node1, node3).with_weight(Weight(40.0)));
+
+        assert_eq!(network.route(&node1, &node3).unwrap().hops, vec![node3, node2, node1].into_iter().collect::<VecDeque<_>>());
+    }
+
+    #[test]
+    fn measuring_an_edge_again_updates_its_weight() {
+        let (mut network, node1, node2, _) = setup();
+        network.add_edge(Edge::new(node1, node2).with_weight(Weight(2.0)));
+        network.add_edge(Edge::new(node2, node1).with_weight(Weight(7.5)));
+
+        assert_eq!(network.edges.len(), 2);
+        assert_eq!(network.edge(&node1, &node2).unwrap().weight, Some(Weight(7.5)));
+    }
+
+    #[test]
+    fn routes_are_found_again_when_the_network_changes() {
+        let (mut network, node1, node2, node3) = setup();
+        assert_eq!(network.routing_table(&node1).next_hop(&node3), Some(&node2));
+
+        network.connect(&node1, &node3);
+        assert_eq!(network.routing_table(&node1).next_hop(&node3), Some(&node3));
+
+        network.remove(&node2);
+        assert_eq!(network.route(&node1, &node2), None);
+    }
+
+    #[test]
+    fn there_is_no_route_to_unreachable_nodes() {
+        let (mut network, node1, node2, node3) = setup();
+        network.disconnect(&node2, &node3);
+
+        assert_eq!(network.route(&node1, &node3), None);
+    }
+
+    #[test]
+    fn every_node_is_in_the_spanning_tree_once() {
+        let (mut network, node1, node2, node3) = setup();
+        network.connect(&node1, &node3);
+
+        // Node 3 is reached straight from node 1, so node 2 doesn't pass broadcasts on to it.
+        let tree = network.spanning_tree(&node1).clone();
+        let mut children = tree.children_of(&node1).to_vec();
+        children.sort();
+
+        assert_eq!(children, vec![node2, node3]);
+        assert!(tree.children_of(&node2).is_empty());
+        assert!(tree.children_of(&node3).is_empty());
+    }
+
+    #[test]
+    fn spanning_trees_ignore_latency() {
+        let (mut network, node1, node2, node3) = setup();
+        network.add_edge(Edge::new(node1, node3).with_weight(Weight(40.0)));
+
+        assert_eq!(network.spanning_tree(&node1).children_of(&node1).len(), 2);
+        assert_eq!(network.routing_table(&node1).next_hop(&node3), Some(&node2));
+    }
+}
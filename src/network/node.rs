@@ -1,12 +1,32 @@
 use network::{remote, Connection, Status};
 use uuid::Uuid;
 
+use std::collections::BTreeMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::time::{Duration, SystemTime};
+
 #[derive(Debug)]
 pub struct Node
 {
     pub uuid: Uuid,
+    /// A human-readable name for the node.
+    pub name: Option<String>,
+    /// Free-form information about the node, such as its hostname.
+    pub labels: BTreeMap<String, String>,
+    /// The address the node can be connected to on, if it is listening.
+    pub address: Option<SocketAddr>,
     pub connection: Option<Connection>,
     pub status: Status,
+    /// The last time we heard from the node directly.
+    pub last_seen: SystemTime,
+    /// The smoothed round-trip latency of our direct connection to the
+    /// node, if we have measured it.
+    pub latency: Option<Duration>,
+    /// The public key of the node's long-term identity, if it has one.
+    ///
+    /// Nodes prove who they are with it when connecting to each other directly.
+    pub public_key: Option<Vec<u8>>,
 }
 
 impl Node
@@ -20,3 +40,12 @@ impl Node
     }
 }
 
+impl fmt::Display for Node
+{
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self.name {
+            Some(ref name) => write!(fmt, "{} ({})", name, self.uuid),
+            None => write!(fmt, "{}", self.uuid),
+        }
+    }
+}
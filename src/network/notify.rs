@@ -1,4 +1,4 @@
-use protocol;
+use {protocol, Clock};
 
 use std::time::{SystemTime, Duration};
 use std::collections::VecDeque;
@@ -11,6 +11,7 @@ const WORK_NOTIFY_FREQUENCY_MS: u64 = 1000;
 pub struct Notify
 {
     pub work: Work,
+    clock: Clock,
 }
 
 #[derive(Clone, Debug)]
@@ -28,16 +29,17 @@ pub enum Work
 
 impl Notify
 {
-    pub fn new() -> Self {
+    pub fn new(clock: &Clock) -> Self {
         Notify {
             work: Work::Unavailable,
+            clock: clock.clone(),
         }
     }
 
     pub fn notify(&mut self) -> VecDeque<protocol::PacketKind> {
         let mut packets = VecDeque::new();
 
-        packets.extend(self.work.notify());
+        packets.extend(self.work.notify(self.clock.now()));
 
         packets
     }
@@ -45,10 +47,10 @@ impl Notify
 
 impl Work
 {
-    pub fn available(&mut self) {
+    pub fn available(&mut self, now: SystemTime) {
         match *self {
             Work::Unavailable | Work::Complete => {
-                *self = Work::Available { last_sent_at: SystemTime::now() - Duration::from_secs(1000)};
+                *self = Work::Available { last_sent_at: now - Duration::from_secs(1000)};
             },
             Work::Available { .. } => (),
         }
@@ -63,12 +65,10 @@ impl Work
         }
     }
 
-    fn notify(&mut self) -> Option<protocol::PacketKind> {
+    fn notify(&mut self, now: SystemTime) -> Option<protocol::PacketKind> {
         match *self {
             Work::Unavailable => None,
             Work::Available { ref mut last_sent_at } => {
-                let now = SystemTime::now();
-
                 if now.duration_since(*last_sent_at).unwrap() >= Duration::from_millis(WORK_NOTIFY_FREQUENCY_MS) {
                     *last_sent_at = now;
 
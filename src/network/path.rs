@@ -3,23 +3,48 @@ use uuid::Uuid;
 
 use std::fmt;
 
+/// How many times a packet can be passed on before it is dropped.
+pub const DEFAULT_HOP_LIMIT: u8 = 64;
+
 /// A path through the network.
 #[derive(Clone, PartialEq)]
 pub struct Path
 {
     pub hops: VecDeque<Uuid>,
+    /// How many more times the packet can be passed on before it is dropped.
+    ///
+    /// This stops a malformed path from sending a packet around in circles.
+    pub hop_limit: u8,
+    /// Identifies a packet that is being broadcast to every node, so that
+    /// nodes can tell if they have already seen it.
+    ///
+    /// The only hop of a broadcast path is the node that sent it.
+    pub broadcast_id: Option<Uuid>,
 }
 
-implement_composite_type!(Path { hops });
-
 impl Path
 {
     pub fn empty() -> Self {
-        Path { hops: VecDeque::new() }
+        Path::new(VecDeque::new())
     }
 
     pub fn new(hops: VecDeque<Uuid>) -> Self {
-        Path { hops: hops }
+        Path { hops, hop_limit: DEFAULT_HOP_LIMIT, broadcast_id: None }
+    }
+
+    /// Creates a path for broadcasting a packet from a node to every other node.
+    ///
+    /// The broadcast ID must be unique to the broadcast.
+    pub fn broadcast(from: Uuid, broadcast_id: Uuid) -> Self {
+        Path {
+            hops: vec![from].into_iter().collect(),
+            hop_limit: DEFAULT_HOP_LIMIT,
+            broadcast_id: Some(broadcast_id),
+        }
+    }
+
+    pub fn is_broadcast(&self) -> bool {
+        self.broadcast_id.is_some()
     }
 
     pub fn from_to(from: Uuid, to: Uuid) -> Self {
@@ -48,7 +73,20 @@ impl Path
         self.hops.iter().rev().skip_while(|node| node != &from).skip(1).next().cloned()
     }
 
+    /// Gets the path back to the sender from a node on the path.
+    ///
+    /// Returns `None` if the node isn't on the path.
+    pub fn back_from(&self, node: &Uuid) -> Option<Path> {
+        if !self.hops.contains(node) || self.is_broadcast() { return None }
+
+        // The sender is at the back, so it becomes the destination at the front.
+        let hops = self.hops.iter().rev().position(|hop| hop == node).unwrap();
+        Some(Path::new(self.hops.iter().rev().take(hops + 1).cloned().collect()))
+    }
+
     pub fn ends_at(&self, node: &Uuid) -> bool {
+        if self.is_broadcast() { return true }
+
         // If there are no hops, the path can end at all nodes.
         self.hops.front().map(|uuid| uuid == node).unwrap_or(true)
     }
@@ -58,7 +96,12 @@ impl fmt::Debug for Path
 {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
         let hops: Vec<_> = self.hops.iter().rev().map(|uuid| format!("{}", uuid)).collect();
-        hops.join(" -> ").fmt(fmt)
+
+        if self.is_broadcast() {
+            format!("{} -> *", hops.join(" -> ")).fmt(fmt)
+        } else {
+            hops.join(" -> ").fmt(fmt)
+        }
     }
 }
 
@@ -102,5 +145,31 @@ mod test
 
         assert_eq!(path.next_hop(&invalid_uuid), None);
     }
-}
 
+    #[test]
+    fn broadcast_paths_end_at_every_node() {
+        let (_, node1, node2, node3) = setup();
+        let path = Path::broadcast(node1, Uuid::new_v4());
+
+        assert!(path.ends_at(&node2) && path.ends_at(&node3));
+        assert_eq!(path.sender(), &node1);
+    }
+
+    #[test]
+    fn the_path_back_leads_to_the_sender() {
+        let (path, node1, node2, _) = setup();
+        let path_back = path.back_from(&node2).unwrap();
+
+        assert_eq!(path_back.sender(), &node2);
+        assert!(path_back.ends_at(&node1));
+        assert_eq!(path_back.next_hop(&node2), Some(node1));
+    }
+
+    #[test]
+    fn there_is_no_path_back_from_nodes_off_the_path() {
+        let (path, _, _, _) = setup();
+        let invalid_uuid = Uuid::parse_str("883c3194-6750-4bbb-8f38-b2b05fc8a40e").unwrap();
+
+        assert_eq!(path.back_from(&invalid_uuid), None);
+    }
+}
@@ -1,5 +1,7 @@
 use protocol;
 
+use uuid::Uuid;
+
 /// The state of some connection that hasn't been promoted to a node.
 #[derive(Clone, Debug)]
 pub enum PendingState
@@ -14,19 +16,36 @@ pub enum PendingState
     },
 
     /// Pong matched original data, we now need to send a `JoinRequest`.
-    PendingJoinRequest,
+    PendingJoinRequest {
+        /// The challenge that the joining node must prove knowledge of
+        /// the network key with. Empty if there is no key.
+        challenge: Vec<u8>,
+        /// The ephemeral public key from the other end, which we prove
+        /// our identity with.
+        their_public_key: Vec<u8>,
+    },
     /// We sent a `JoinRequest` and are awaiting a response.
     PendingJoinResponse,
 
     Complete {
         join_response: protocol::JoinResponse,
     },
+    /// A node that is already in the network connected to us directly.
+    PeerConnected {
+        uuid: Uuid,
+    },
+    /// A node from another network connected to us to merge the two networks.
+    Merged {
+        uuid: Uuid,
+        /// The network the node is a part of.
+        network: protocol::Network,
+    },
 }
 
 impl PendingState
 {
     pub fn is_complete(&self) -> bool {
-        if let PendingState::Complete { .. } = *self { true } else { false }
+        matches!(*self, PendingState::Complete { .. } | PendingState::PeerConnected { .. } | PendingState::Merged { .. })
     }
 }
 
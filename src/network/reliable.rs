@@ -0,0 +1,223 @@
+//! Delivering packets that mustn't be lost.
+//!
+//! A packet sent reliably carries a sequence number. Its recipient
+//! acknowledges it, and the sender keeps resending it until it does.
+//! Resent packets can arrive more than once, so recipients remember the
+//! sequence numbers they have seen and only handle each packet once.
+
+use {Config, PacketKind};
+use network::Backoff;
+use uuid::Uuid;
+use rand::Rng;
+
+use std::collections::{HashMap, HashSet, VecDeque};
+
+/// How many received packets to remember having seen.
+const MAX_REMEMBERED_PACKETS: usize = 4096;
+/// The longest to wait before resending an unacknowledged packet, as a
+/// multiple of the retransmit interval.
+const MAX_RETRANSMIT_BACKOFF: u32 = 16;
+
+/// Keeps track of the packets we have sent reliably and received reliably.
+#[derive(Clone, Debug)]
+pub struct Reliable
+{
+    next_sequence: u64,
+    /// The packets we have sent that haven't been acknowledged yet, by sequence number.
+    unacknowledged: HashMap<u64, Unacknowledged>,
+    /// The packets we have received, as their sender and sequence number.
+    received: HashSet<(Uuid, u64)>,
+    /// The packets we have received, oldest first.
+    received_order: VecDeque<(Uuid, u64)>,
+    config: Config,
+}
+
+/// A packet that we are waiting on an acknowledgement for.
+#[derive(Clone, Debug)]
+pub struct Unacknowledged
+{
+    /// The node the packet is for.
+    pub to: Uuid,
+    pub packet: PacketKind,
+    backoff: Backoff,
+}
+
+impl Reliable
+{
+    pub fn new(config: &Config) -> Self {
+        Reliable {
+            // Starting somewhere random means a node that restarts doesn't
+            // reuse sequence numbers that others still remember.
+            next_sequence: config.random.clone().gen(),
+            unacknowledged: HashMap::new(),
+            received: HashSet::new(),
+            received_order: VecDeque::new(),
+            config: config.clone(),
+        }
+    }
+
+    /// Takes the sequence number for the next packet we send reliably.
+    pub fn next_sequence(&mut self) -> u64 {
+        let sequence = self.next_sequence;
+        self.next_sequence = self.next_sequence.wrapping_add(1);
+        sequence
+    }
+
+    /// Remembers a packet we have sent, until it is acknowledged.
+    pub fn sent(&mut self, sequence: u64, to: Uuid, packet: PacketKind) {
+        let mut backoff = Backoff::new(self.config.retransmit_interval,
+                                       self.config.retransmit_interval * MAX_RETRANSMIT_BACKOFF,
+                                       &self.config.clock,
+                                       &self.config.random);
+        backoff.failed();
+
+        self.unacknowledged.insert(sequence, Unacknowledged {
+            to,
+            packet,
+            backoff,
+        });
+    }
+
+    /// Records that a node has acknowledged a packet.
+    ///
+    /// Returns `false` if we weren't waiting on the node to acknowledge it.
+    pub fn acknowledged(&mut self, from: &Uuid, sequence: u64) -> bool {
+        match self.unacknowledged.get(&sequence) {
+            Some(unacknowledged) if &unacknowledged.to == from => (),
+            _ => return false,
+        }
+
+        self.unacknowledged.remove(&sequence);
+        true
+    }
+
+    /// Gets the packets that haven't been acknowledged in time and need resending.
+    ///
+    /// Each packet waits longer before it is resent again.
+    pub fn due(&mut self) -> Vec<(Uuid, PacketKind)> {
+        self.unacknowledged.values_mut()
+            .filter(|unacknowledged| unacknowledged.backoff.is_due())
+            .map(|unacknowledged| {
+                unacknowledged.backoff.failed();
+                (unacknowledged.to, unacknowledged.packet.clone())
+            })
+            .collect()
+    }
+
+    /// Stops waiting on acknowledgements from a node, such as one that has left the network.
+    ///
+    /// Returns the number of packets that will never be acknowledged.
+    pub fn forget(&mut self, to: &Uuid) -> usize {
+        let count_before = self.unacknowledged.len();
+        self.unacknowledged.retain(|_, unacknowledged| &unacknowledged.to != to);
+        count_before - self.unacknowledged.len()
+    }
+
+    /// Stops waiting on acknowledgements from every node that doesn't match a predicate.
+    pub fn retain_recipients<F>(&mut self, mut predicate: F)
+        where F: FnMut(&Uuid) -> bool {
+        self.unacknowledged.retain(|_, unacknowledged| predicate(&unacknowledged.to));
+    }
+
+    /// Remembers a packet we have received, checking if this is the first time we have seen it.
+    pub fn first_receipt(&mut self, from: &Uuid, sequence: u64) -> bool {
+        let key = (*from, sequence);
+        if !self.received.insert(key) { return false }
+
+        self.received_order.push_back(key);
+
+        if self.received_order.len() > MAX_REMEMBERED_PACKETS {
+            let oldest = self.received_order.pop_front().unwrap();
+            self.received.remove(&oldest);
+        }
+
+        true
+    }
+
+    /// The number of packets we are waiting on acknowledgements for.
+    pub fn unacknowledged_count(&self) -> usize { self.unacknowledged.len() }
+}
+
+#[cfg(test)]
+mod test
+{
+    pub use super::*;
+    pub use protocol;
+
+    use std::time::Duration;
+
+    fn setup(retransmit_interval_ms: u64) -> (Reliable, Uuid) {
+        let mut config = Config::default();
+        config.retransmit_interval = Duration::from_millis(retransmit_interval_ms);
+
+        (Reliable::new(&config), Uuid::parse_str("2c6bb858-72ea-4db6-a9ab-6ec0ca1f18ab").unwrap())
+    }
+
+    fn packet() -> PacketKind {
+        PacketKind::WorkRequest(protocol::WorkRequest)
+    }
+
+    #[test]
+    fn packets_are_not_resent_straight_away() {
+        let (mut reliable, to) = setup(60_000);
+        let sequence = reliable.next_sequence();
+
+        reliable.sent(sequence, to, packet());
+        assert!(reliable.due().is_empty());
+        assert_eq!(reliable.unacknowledged_count(), 1);
+    }
+
+    #[test]
+    fn unacknowledged_packets_are_resent() {
+        let (mut reliable, to) = setup(0);
+        let sequence = reliable.next_sequence();
+
+        reliable.sent(sequence, to, packet());
+        assert_eq!(reliable.due().len(), 1);
+        assert_eq!(reliable.due().len(), 1);
+    }
+
+    #[test]
+    fn acknowledged_packets_are_not_resent() {
+        let (mut reliable, to) = setup(0);
+        let sequence = reliable.next_sequence();
+
+        reliable.sent(sequence, to, packet());
+        assert!(reliable.acknowledged(&to, sequence));
+        assert!(!reliable.acknowledged(&to, sequence));
+        assert!(reliable.due().is_empty());
+    }
+
+    #[test]
+    fn only_the_recipient_can_acknowledge_a_packet() {
+        let (mut reliable, to) = setup(0);
+        let sequence = reliable.next_sequence();
+
+        reliable.sent(sequence, to, packet());
+        assert!(!reliable.acknowledged(&Uuid::new_v4(), sequence));
+        assert_eq!(reliable.unacknowledged_count(), 1);
+    }
+
+    #[test]
+    fn packets_to_forgotten_nodes_are_not_resent() {
+        let (mut reliable, to) = setup(0);
+
+        let sequence = reliable.next_sequence();
+        reliable.sent(sequence, to, packet());
+        let sequence = reliable.next_sequence();
+        reliable.sent(sequence, Uuid::new_v4(), packet());
+
+        assert_eq!(reliable.forget(&to), 1);
+        assert_eq!(reliable.unacknowledged_count(), 1);
+    }
+
+    #[test]
+    fn duplicate_packets_are_only_received_once() {
+        let (mut reliable, from) = setup(0);
+
+        assert!(reliable.first_receipt(&from, 7));
+        assert!(!reliable.first_receipt(&from, 7));
+        assert!(reliable.first_receipt(&from, 8));
+        assert!(reliable.first_receipt(&Uuid::new_v4(), 7));
+    }
+}
@@ -1,10 +1,12 @@
 use {Packet, PacketKind, Error};
 use network::{Connection, PendingState};
+use network::encryption::{KeyExchange, Role};
 use network;
 use network::local;
 use protocol;
 
-use uuid::Uuid;
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
 
 
 #[derive(Debug)]
@@ -12,6 +14,16 @@ pub struct Node
 {
     pub state: PendingState,
     pub connection: Connection,
+    /// The name the node asked to join with.
+    pub name: Option<String>,
+    /// The labels the node asked to join with.
+    pub labels: BTreeMap<String, String>,
+    /// The address the node said it can be connected to on.
+    pub address: Option<SocketAddr>,
+    /// The public key of the identity the node asked to join with.
+    pub public_key: Option<Vec<u8>>,
+    /// Our half of the key exchange for encrypting the connection.
+    key_exchange: KeyExchange,
 }
 
 impl Node
@@ -20,11 +32,16 @@ impl Node
         Node {
             state: PendingState::PendingPing,
             connection: connection,
+            name: None,
+            labels: BTreeMap::new(),
+            address: None,
+            public_key: None,
+            key_exchange: KeyExchange::new(),
         }
     }
 
     pub fn is_complete(&self) -> bool {
-        if let PendingState::Complete { .. } = self.state { true } else { false }
+        self.state.is_complete()
     }
 
     pub fn process_incoming_data(&mut self, connected_node: &mut local::connected::Node) -> Result<(), Error> {
@@ -34,28 +51,114 @@ impl Node
                     if let PacketKind::Ping(ping) = packet.kind {
                         println!("received ping, responding with pong");
 
+                        // Nodes must prove they know the network key, if there is one.
+                        let challenge = if connected_node.config.network_key.is_some() {
+                            network::auth::challenge()
+                        } else {
+                            Vec::new()
+                        };
+
+                        // Only the version check below can be answered without encryption.
+                        let keys = if ping.public_key.is_empty() {
+                            None
+                        } else {
+                            let network_key = connected_node.config.network_key.as_deref();
+
+                            match self.key_exchange.complete(&ping.public_key, network_key, Role::Responder) {
+                                Some(keys) => Some(keys),
+                                None => {
+                                    self.connection.terminate("invalid public key")?;
+                                    return Err(Error::InvalidPublicKey);
+                                },
+                            }
+                        };
+
                         let pong = protocol::Pong {
                             user_agent: ::user_agent(),
                             data: ping.data.clone(),
+                            challenge: challenge.clone(),
+                            // Prove that we know the key too, so nodes can't be lured into a fake network.
+                            proof: local::pending::prove(&connected_node.config, &ping.data),
+                            public_key: if keys.is_some() { self.key_exchange.public_key() } else { Vec::new() },
+                            features: protocol::feature::all(),
                         };
 
+                        // Always respond so the remote can tell which version we speak.
                         self.connection.send_packet(&Packet {
                             path: network::Path::empty(),
                             kind: PacketKind::Pong(pong.clone()),
                         })?;
 
-                        self.state = PendingState::PendingJoinRequest;
+                        if !ping.user_agent.is_compatible(&pong.user_agent) {
+                            self.connection.terminate(format!(
+                                "protocol version {} is not compatible with {}",
+                                ping.user_agent.protocol_major, pong.user_agent.protocol_major))?;
+
+                            return Err(Error::IncompatibleProtocol {
+                                ours: pong.user_agent,
+                                theirs: ping.user_agent,
+                            });
+                        }
+
+                        println!("negotiated protocol features: {:?}", protocol::feature::common(&pong.features, &ping.features));
+
+                        // Everything after the pong is encrypted.
+                        match keys {
+                            Some(keys) => self.connection.enable_encryption(keys),
+                            None => {
+                                self.connection.terminate("connections must be encrypted")?;
+                                return Err(Error::EncryptionRequired);
+                            },
+                        }
+
+                        self.connection.user_agent = Some(ping.user_agent);
+                        self.connection.features = ping.features;
+                        self.state = PendingState::PendingJoinRequest { challenge, their_public_key: ping.public_key };
                     } else {
-                        return Err(Error::UnexpectedPacket { expected: "ping", received: packet });
+                        return Err(Error::unexpected_packet("ping", packet));
                     }
                 }
             },
-            PendingState::PendingJoinRequest => {
+            PendingState::PendingJoinRequest { challenge, their_public_key } => {
                 if let Some(packet) = self.connection.receive_packet()? {
-                    if let PacketKind::JoinRequest(..) = packet.kind {
-                        let new_node_uuid = Uuid::new_v4();
+                    if let PacketKind::JoinRequest(join_request) = packet.kind {
+                        self.verify_proof(connected_node, &challenge, &join_request.proof)?;
+
+                        // A node that sends its identity key must own it.
+                        let public_key = match join_request.uuid {
+                            Some(uuid) if !join_request.public_key.is_empty() => {
+                                if !self.proves_identity(&uuid, Some(&join_request.public_key), &join_request.identity_proof) {
+                                    self.connection.terminate("you could not prove who you are")?;
+                                    return Err(Error::IdentityNotProven { uuid });
+                                }
+
+                                Some(join_request.public_key.clone())
+                            },
+                            _ => None,
+                        };
+
+                        // Let the node keep its identity, unless somebody else already has it
+                        // or it can't prove the identity is its own.
+                        let new_node_uuid = match join_request.uuid {
+                            Some(uuid) if connected_node.network.get(&uuid).is_some() => {
+                                println!("node requested UUID {} but it is already taken", uuid);
+                                connected_node.config.random.uuid()
+                            },
+                            Some(uuid) if public_key.is_none() => {
+                                println!("node requested UUID {} without an identity key", uuid);
+                                connected_node.config.random.uuid()
+                            },
+                            Some(uuid) => uuid,
+                            None => connected_node.config.random.uuid(),
+                        };
+
+                        println!("received join request from {}, responding (assigning UUID {})",
+                                 join_request.name.as_ref().map_or("unnamed node", |name| name.as_str()), new_node_uuid);
 
-                        println!("received join request, responding (assigning UUID {})", new_node_uuid);
+                        self.name = join_request.name.clone();
+                        self.labels = join_request.labels.clone();
+                        self.address = join_request.address.as_ref().and_then(|address| address.parse().ok());
+                        self.public_key = public_key;
 
                         let network = protocol::Network::from_network(&connected_node.network);
 
@@ -71,8 +174,75 @@ impl Node
                         })?;
 
                         self.state = PendingState::Complete { join_response: join_response };
+                    } else if let PacketKind::ConnectRequest(connect_request) = packet.kind {
+                        self.verify_proof(connected_node, &challenge, &connect_request.proof)?;
+
+                        let refusal = match connected_node.network.get(&connect_request.uuid) {
+                            None => Some("we don't know about you"),
+                            Some(node) if node.connection.is_some() => Some("we are already connected to you"),
+                            // Anybody could claim to be a node we know, so make sure they are.
+                            Some(node) if !self.proves_identity(&node.uuid, node.public_key.as_ref(), &connect_request.identity_proof) => {
+                                Some("you could not prove who you are")
+                            },
+                            Some(..) => None,
+                        };
+
+                        if let Some(reason) = refusal {
+                            self.connection.terminate(reason)?;
+                            return Err(Error::ConnectRefused { reason: reason.to_owned() });
+                        }
+
+                        println!("received connect request from node {}", connected_node.network.describe(&connect_request.uuid));
+
+                        self.connection.send_packet(&Packet {
+                            path: network::Path::empty(),
+                            kind: PacketKind::ConnectResponse(protocol::ConnectResponse {
+                                uuid: connected_node.uuid,
+                                identity_proof: local::pending::prove_identity(&connected_node.config,
+                                                                               &connected_node.uuid,
+                                                                               &their_public_key),
+                            }),
+                        })?;
+
+                        self.state = PendingState::PeerConnected { uuid: connect_request.uuid };
+                    } else if let PacketKind::MergeRequest(merge_request) = packet.kind {
+                        self.verify_proof(connected_node, &challenge, &merge_request.proof)?;
+
+                        let requester = merge_request.network.nodes.iter()
+                            .find(|node| node.uuid == merge_request.uuid && node.status.is_none());
+                        let refusal = match requester {
+                            None => Some("your network doesn't include you"),
+                            Some(node) if !self.proves_identity(&node.uuid, Some(&node.public_key), &merge_request.identity_proof) => {
+                                Some("you could not prove who you are")
+                            },
+                            Some(..) => None,
+                        };
+
+                        if let Some(reason) = refusal {
+                            self.connection.terminate(reason)?;
+                            return Err(Error::ConnectRefused { reason: reason.to_owned() });
+                        }
+
+                        local::pending::refuse_overlap(&mut self.connection,
+                                                       connected_node.network.overlap_with(&merge_request.network))?;
+
+                        println!("received merge request from node {}, merging with its network of {} node(s)",
+                                 merge_request.uuid, merge_request.network.nodes.len());
+
+                        self.connection.send_packet(&Packet {
+                            path: network::Path::empty(),
+                            kind: PacketKind::MergeResponse(protocol::MergeResponse {
+                                uuid: connected_node.uuid,
+                                identity_proof: local::pending::prove_identity(&connected_node.config,
+                                                                               &connected_node.uuid,
+                                                                               &their_public_key),
+                                network: protocol::Network::from_network(&connected_node.network),
+                            }),
+                        })?;
+
+                        self.state = PendingState::Merged { uuid: merge_request.uuid, network: merge_request.network };
                     } else {
-                        return Err(Error::UnexpectedPacket { expected: "join request", received: packet });
+                        return Err(Error::unexpected_packet("join request", packet));
                     }
                 }
             },
@@ -81,5 +251,319 @@ impl Node
 
         Ok(())
     }
+
+    /// Checks that the remote owns the identity key of a node.
+    ///
+    /// Nodes without an identity key can't prove who they are.
+    fn proves_identity(&self, uuid: &::uuid::Uuid, public_key: Option<&Vec<u8>>, identity_proof: &[u8]) -> bool {
+        public_key.is_some_and(|public_key| {
+            local::pending::verify_identity(&self.key_exchange, uuid, public_key, identity_proof)
+        })
+    }
+
+    /// Checks that the remote knows the network key, if there is one.
+    fn verify_proof(&mut self,
+                    connected_node: &local::connected::Node,
+                    challenge: &[u8],
+                    proof: &[u8]) -> Result<(), Error> {
+        if let Some(ref network_key) = connected_node.config.network_key {
+            if !network::auth::verify(network_key, challenge, proof) {
+                self.connection.terminate("authentication failed")?;
+                return Err(Error::AuthenticationFailed);
+            }
+        }
+
+        Ok(())
+    }
 }
 
+#[cfg(test)]
+mod test
+{
+    pub use super::*;
+    pub use network::memory::MemoryStream;
+    pub use Config;
+    pub use uuid::Uuid;
+    use mio;
+
+    /// Starts accepting a connection, returning the other end of it.
+    fn accept(config: Config) -> (Node, local::connected::Node, Connection) {
+        let (ours, theirs) = MemoryStream::pair();
+        let uuid = Uuid::new_v4();
+        let connected_node = local::connected::Node::new(uuid, None, network::Network::new(uuid, config.clock.now()), &config);
+
+        (Node::new(Connection::new(mio::Token(0), Box::new(theirs))), connected_node,
+         Connection::new(mio::Token(0), Box::new(ours)))
+    }
+
+    #[test]
+    fn plaintext_connections_are_refused() {
+        let (mut pending, mut connected_node, mut connection) = accept(Config::default());
+
+        connection.send_packet(&Packet {
+            path: network::Path::empty(),
+            kind: PacketKind::Ping(protocol::Ping {
+                user_agent: ::user_agent(),
+                data: network::auth::challenge(),
+                public_key: Vec::new(),
+                features: protocol::feature::all(),
+            }),
+        }).unwrap();
+
+        match pending.process_incoming_data(&mut connected_node) {
+            Err(Error::EncryptionRequired) => (),
+            result => panic!("expected the connection to be refused, got {:?}", result),
+        }
+
+        let packets = connection.receive_packets().unwrap();
+        match packets.last().map(|packet| &packet.kind) {
+            Some(&PacketKind::Terminate(ref terminate)) => assert_eq!(terminate.reason, "connections must be encrypted"),
+            kind => panic!("expected the connection to be terminated, got {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn nodes_speaking_an_older_protocol_are_told_why_they_are_refused() {
+        use proto::Parcel;
+        use proto::wire::stream::Transport;
+        use std::collections::VecDeque;
+
+        let (mut pending, mut connected_node, mut connection) = accept(Config::default());
+
+        // A ping from the first version of the protocol, which can't be read as a whole.
+        let old_user_agent = protocol::UserAgent { client: "vanilla v0.1.0".to_owned(), protocol_major: 0, protocol_revision: 0 };
+        let mut ping = Vec::new();
+        VecDeque::<Uuid>::new().write(&mut ping).unwrap();
+        0u32.write(&mut ping).unwrap();
+        old_user_agent.write(&mut ping).unwrap();
+        vec![1u8, 2, 3].write(&mut ping).unwrap();
+        connection.protocol.transport.send_raw_packet(&mut connection.protocol.stream, &ping).unwrap();
+
+        match pending.process_incoming_data(&mut connected_node) {
+            Err(Error::IncompatibleProtocol { theirs, .. }) => assert_eq!(theirs, old_user_agent),
+            result => panic!("expected the connection to be refused, got {:?}", result),
+        }
+
+        // The first version can read the terminate, as it only adds to the end.
+        connection.process_incoming_data().unwrap();
+        let terminate = connection.protocol.transport.receive_raw_packet().unwrap().unwrap();
+        let mut read = ::std::io::Cursor::new(terminate);
+        let hops: VecDeque<Uuid> = Parcel::read(&mut read).unwrap();
+        let kind: u32 = Parcel::read(&mut read).unwrap();
+        let reason: String = Parcel::read(&mut read).unwrap();
+
+        assert!(hops.is_empty());
+        assert_eq!(kind, 0x05);
+        assert_eq!(reason, format!("protocol version 0 is not compatible with {}", ::PROTOCOL_MAJOR));
+    }
+
+    /// Gets through the ping and pong, returning our half of the key exchange
+    /// and the public key from the pong.
+    fn handshake(pending: &mut Node,
+                 connected_node: &mut local::connected::Node,
+                 connection: &mut Connection,
+                 requester: &Config) -> (KeyExchange, Vec<u8>) {
+        let key_exchange = KeyExchange::new();
+        let ping = local::pending::send_ping(connection, &key_exchange).unwrap();
+        pending.process_incoming_data(connected_node).unwrap();
+
+        let pong = match connection.receive_packet().unwrap().map(|packet| packet.kind) {
+            Some(PacketKind::Pong(pong)) => pong,
+            kind => panic!("expected a pong, got {:?}", kind),
+        };
+        let their_public_key = pong.public_key.clone();
+        local::pending::accept_pong(connection, requester, ping, &key_exchange, pong).unwrap();
+
+        (key_exchange, their_public_key)
+    }
+
+    /// Builds a network with a single node, as the node would see it.
+    fn network_of(config: &Config) -> network::Network {
+        let uuid = config.identity.uuid;
+        let mut network = network::Network::new(uuid, config.clock.now());
+        network.get_mut(&uuid).unwrap().public_key = config.identity.keypair.as_ref().map(|keypair| keypair.public_key());
+        network
+    }
+
+    /// Asks to connect directly as a node that the accepting node knows about.
+    fn connect_as(known_node: &Config, requester: &Config) -> (Result<(), Error>, Node) {
+        let (mut pending, mut connected_node, mut connection) = accept(Config::default());
+        let uuid = known_node.identity.uuid;
+        connected_node.network.merge(protocol::Network::from_network(&network_of(known_node)), connected_node.config.clock.now());
+
+        let (_, their_public_key) = handshake(&mut pending, &mut connected_node, &mut connection, requester);
+
+        connection.send_packet(&Packet {
+            path: network::Path::empty(),
+            kind: PacketKind::ConnectRequest(protocol::ConnectRequest {
+                uuid: uuid,
+                proof: Vec::new(),
+                identity_proof: local::pending::prove_identity(requester, &uuid, &their_public_key),
+            }),
+        }).unwrap();
+
+        (pending.process_incoming_data(&mut connected_node), pending)
+    }
+
+    /// Asks to merge with the accepting node, claiming to be a node in a network.
+    fn merge_as(uuid: Uuid, network: &network::Network, requester: &Config, responder_network: &network::Network) -> Result<(), Error> {
+        let (mut pending, mut connected_node, mut connection) = accept(Config::default());
+        connected_node.network.merge(protocol::Network::from_network(responder_network), connected_node.config.clock.now());
+
+        let (_, their_public_key) = handshake(&mut pending, &mut connected_node, &mut connection, requester);
+
+        connection.send_packet(&Packet {
+            path: network::Path::empty(),
+            kind: PacketKind::MergeRequest(protocol::MergeRequest {
+                uuid: uuid,
+                proof: Vec::new(),
+                identity_proof: local::pending::prove_identity(requester, &uuid, &their_public_key),
+                network: protocol::Network::from_network(network),
+            }),
+        }).unwrap();
+
+        pending.process_incoming_data(&mut connected_node)
+    }
+
+    /// Asks to join as the node with the given identity, proving it with another.
+    fn join_as(identity: &Config, prover: &Config) -> (Result<(), Error>, Node) {
+        let (mut pending, mut connected_node, mut connection) = accept(Config::default());
+        let (_, their_public_key) = handshake(&mut pending, &mut connected_node, &mut connection, prover);
+
+        connection.send_packet(&Packet {
+            path: network::Path::empty(),
+            kind: PacketKind::JoinRequest(protocol::JoinRequest {
+                proof: Vec::new(),
+                uuid: Some(identity.identity.uuid),
+                identity_proof: local::pending::prove_identity(prover, &identity.identity.uuid, &their_public_key),
+                name: None,
+                labels: BTreeMap::new(),
+                address: None,
+                public_key: identity.identity.keypair.as_ref().map_or(Vec::new(), |keypair| keypair.public_key()),
+            }),
+        }).unwrap();
+
+        (pending.process_incoming_data(&mut connected_node), pending)
+    }
+
+    #[test]
+    fn nodes_that_prove_their_uuid_keep_it() {
+        let config = Config::default();
+        let (result, pending) = join_as(&config, &config);
+
+        result.unwrap();
+        match pending.state {
+            PendingState::Complete { join_response } => assert_eq!(join_response.your_uuid, config.identity.uuid),
+            state => panic!("expected the node to join, got {:?}", state),
+        }
+        assert_eq!(pending.public_key, config.identity.keypair.as_ref().map(|keypair| keypair.public_key()));
+    }
+
+    #[test]
+    fn nodes_that_join_with_somebody_elses_uuid_are_refused() {
+        let config = Config::default();
+        let (result, _) = join_as(&config, &Config::default());
+
+        match result {
+            Err(Error::IdentityNotProven { uuid }) => assert_eq!(uuid, config.identity.uuid),
+            result => panic!("expected the join to be refused, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn nodes_without_an_identity_key_get_a_new_uuid() {
+        let mut config = Config::default();
+        config.identity.keypair = None;
+        let (result, pending) = join_as(&config, &config);
+
+        result.unwrap();
+        match pending.state {
+            PendingState::Complete { join_response } => assert!(join_response.your_uuid != config.identity.uuid),
+            state => panic!("expected the node to join, got {:?}", state),
+        }
+        assert_eq!(pending.public_key, None);
+    }
+
+    #[test]
+    fn accepted_connections_prove_who_we_are() {
+        let config = Config::default();
+        let (mut pending, mut connected_node, mut connection) = accept(Config::default());
+        connected_node.network.merge(protocol::Network::from_network(&network_of(&config)), connected_node.config.clock.now());
+
+        let (key_exchange, their_public_key) = handshake(&mut pending, &mut connected_node, &mut connection, &config);
+
+        connection.send_packet(&Packet {
+            path: network::Path::empty(),
+            kind: PacketKind::ConnectRequest(protocol::ConnectRequest {
+                uuid: config.identity.uuid,
+                proof: Vec::new(),
+                identity_proof: local::pending::prove_identity(&config, &config.identity.uuid, &their_public_key),
+            }),
+        }).unwrap();
+        pending.process_incoming_data(&mut connected_node).unwrap();
+
+        let connect_response = match connection.receive_packet().unwrap().map(|packet| packet.kind) {
+            Some(PacketKind::ConnectResponse(connect_response)) => connect_response,
+            kind => panic!("expected a connect response, got {:?}", kind),
+        };
+        let public_key = connected_node.config.identity.keypair.as_ref().unwrap().public_key();
+
+        assert!(local::pending::verify_identity(&key_exchange, &connected_node.uuid, &public_key,
+                                                &connect_response.identity_proof));
+        assert!(!local::pending::verify_identity(&key_exchange, &config.identity.uuid, &public_key,
+                                                 &connect_response.identity_proof));
+    }
+
+    #[test]
+    fn nodes_that_prove_who_they_are_can_connect() {
+        let config = Config::default();
+        let (result, pending) = connect_as(&config, &config);
+
+        result.unwrap();
+        match pending.state {
+            PendingState::PeerConnected { uuid } => assert_eq!(uuid, config.identity.uuid),
+            state => panic!("expected the connection to be accepted, got {:?}", state),
+        }
+    }
+
+    #[test]
+    fn nodes_that_claim_to_be_somebody_else_are_refused() {
+        let (result, _) = connect_as(&Config::default(), &Config::default());
+
+        match result {
+            Err(Error::ConnectRefused { reason }) => assert_eq!(reason, "you could not prove who you are"),
+            result => panic!("expected the connection to be refused, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn networks_with_colliding_uuids_are_not_merged() {
+        let (requester, stranger) = (Config::default(), Config::default());
+
+        // Both networks have a node with the same UUID, but are otherwise independent.
+        let mut network = network_of(&requester);
+        let mut impostor = network_of(&stranger);
+        impostor.get_mut(&stranger.identity.uuid).unwrap().status = network::Status::Remote(Default::default());
+        network.merge(protocol::Network::from_network(&impostor), requester.clock.now());
+
+        match merge_as(requester.identity.uuid, &network, &requester, &network_of(&stranger)) {
+            Err(Error::UuidCollision { uuids }) => assert_eq!(uuids, vec![stranger.identity.uuid]),
+            result => panic!("expected the merge to be refused, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn merge_requests_must_come_from_the_local_node_of_the_network() {
+        let (requester, other) = (Config::default(), Config::default());
+
+        match merge_as(other.identity.uuid, &network_of(&requester), &requester, &network::Network::empty()) {
+            Err(Error::ConnectRefused { reason }) => assert_eq!(reason, "your network doesn't include you"),
+            result => panic!("expected the merge to be refused, got {:?}", result),
+        }
+
+        match merge_as(other.identity.uuid, &network_of(&other), &requester, &network::Network::empty()) {
+            Err(Error::ConnectRefused { reason }) => assert_eq!(reason, "you could not prove who you are"),
+            result => panic!("expected the merge to be refused, got {:?}", result),
+        }
+    }
+}
@@ -0,0 +1,127 @@
+use network::{Network, Edge, Path};
+use uuid::Uuid;
+
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, BTreeMap, HashMap, VecDeque};
+
+/// The lowest-latency paths from one node to every other node it can reach.
+///
+/// Building the table searches the whole network once, so it is kept
+/// until the topology of the network changes.
+#[derive(Clone, Debug)]
+pub struct RoutingTable
+{
+    /// The node that the paths start at.
+    pub from: Uuid,
+    /// The node before each node on its path.
+    ///
+    /// Kept in order of UUID, so that the children of each node are too.
+    previous: BTreeMap<Uuid, Uuid>,
+    /// The first node on the path to each node.
+    next_hops: HashMap<Uuid, Uuid>,
+    /// The nodes that come straight after each node on their paths.
+    children: HashMap<Uuid, Vec<Uuid>>,
+}
+
+impl RoutingTable
+{
+    /// Finds the lowest-latency path from a node to every other node.
+    pub fn build(network: &Network, from: &Uuid) -> Self {
+        RoutingTable::search(network, from, |edge| edge.effective_weight().cost() as i64)
+    }
+
+    /// Finds the path with the fewest hops from a node to every other node.
+    ///
+    /// The latencies of edges can differ slightly between nodes, but the edges
+    /// themselves don't, so every node finds the same tree for broadcasts.
+    pub fn spanning_tree(network: &Network, root: &Uuid) -> Self {
+        RoutingTable::search(network, root, |_| 1)
+    }
+
+    fn search<F>(network: &Network, from: &Uuid, edge_cost: F) -> Self
+        where F: Fn(&Edge) -> i64 {
+        let mut adjacent: HashMap<Uuid, Vec<(Uuid, i64)>> = HashMap::new();
+        for edge in network.edges.iter() {
+            let cost = edge_cost(edge);
+
+            adjacent.entry(edge.a).or_default().push((edge.b, cost));
+            adjacent.entry(edge.b).or_default().push((edge.a, cost));
+        }
+
+        let mut costs: HashMap<Uuid, i64> = HashMap::new();
+        let mut previous = BTreeMap::new();
+        // Ties are broken by UUID, so the same network always gives the same paths.
+        let mut queue = BinaryHeap::new();
+
+        costs.insert(*from, 0);
+        queue.push(Reverse((0, *from)));
+
+        while let Some(Reverse((cost, uuid))) = queue.pop() {
+            if costs.get(&uuid).is_some_and(|&best| cost > best) { continue }
+
+            for &(sibling, edge_cost) in adjacent.get(&uuid).map(|a| a.as_slice()).unwrap_or(&[]) {
+                let sibling_cost = cost + edge_cost;
+
+                if costs.get(&sibling).is_none_or(|&best| sibling_cost < best) {
+                    costs.insert(sibling, sibling_cost);
+                    previous.insert(sibling, uuid);
+                    queue.push(Reverse((sibling_cost, sibling)));
+                }
+            }
+        }
+
+        let mut children = HashMap::new();
+        for (&uuid, &parent) in previous.iter() {
+            children.entry(parent).or_insert_with(Vec::new).push(uuid);
+        }
+
+        let mut table = RoutingTable {
+            from: *from,
+            previous,
+            next_hops: HashMap::new(),
+            children,
+        };
+
+        let next_hops = table.previous.keys()
+            .map(|uuid| (*uuid, table.walk_back(uuid)[1]))
+            .collect();
+        table.next_hops = next_hops;
+
+        table
+    }
+
+    /// Gets the nodes that come straight after a node on their paths.
+    ///
+    /// When broadcasting along a spanning tree, these are the nodes that a
+    /// node passes the broadcast on to.
+    pub fn children_of(&self, uuid: &Uuid) -> &[Uuid] {
+        self.children.get(uuid).map(|children| children.as_slice()).unwrap_or(&[])
+    }
+
+    /// Gets the path to a node, if it can be reached.
+    pub fn path_to(&self, to: &Uuid) -> Option<Path> {
+        if to != &self.from && !self.previous.contains_key(to) { return None }
+
+        // Paths are stored with the destination at the front.
+        Some(Path::new(self.walk_back(to).into_iter().rev().collect()))
+    }
+
+    /// Gets the node to send a packet to first, to get it to another node.
+    pub fn next_hop(&self, to: &Uuid) -> Option<&Uuid> {
+        self.next_hops.get(to)
+    }
+
+    /// Gets the nodes on the path to a node, starting from the start of the path.
+    fn walk_back(&self, to: &Uuid) -> VecDeque<Uuid> {
+        let mut hops = VecDeque::new();
+        let mut current = *to;
+
+        hops.push_front(current);
+        while let Some(previous) = self.previous.get(&current) {
+            current = *previous;
+            hops.push_front(current);
+        }
+
+        hops
+    }
+}
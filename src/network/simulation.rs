@@ -0,0 +1,517 @@
+//! Simulated networks of nodes, for testing.
+//!
+//! Every node in a simulation is a real `local::connected::Node`, but the
+//! nodes talk over memory streams rather than sockets, share a simulated
+//! clock, make their random choices from a fixed seed, and run their work
+//! in a sandbox that doesn't spawn processes.
+//! Packets are carried between nodes by the simulation itself, so that it
+//! can delay them, lose them, or cut nodes off from each other.
+
+use {Config, Clock, Network, PacketKind, Random};
+use network::{self, local, Connection, MemoryStream};
+use {ci, protocol};
+
+use mio;
+use rand::{Rng, SeedableRng, XorShiftRng};
+use uuid::Uuid;
+
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// How much time passes in each step of a simulation.
+pub const STEP_MS: u64 = 10;
+/// The latency of a link, unless it is changed.
+const DEFAULT_LATENCY_MS: u64 = 1;
+/// When simulations start, so that every run sees the same times.
+const START_SECS: u64 = 1_500_000_000;
+/// Where the random choices of a simulation come from, so that every run
+/// makes the same choices.
+const SEED: [u32; 4] = [0x5eed, 0xba5e, 0xcafe, 0xf00d];
+
+/// A network of nodes running in a single process.
+pub struct Simulation
+{
+    pub clock: Clock,
+    pub nodes: Vec<local::connected::Node>,
+    /// The chance of each packet being lost, between zero and one.
+    pub drop_rate: f32,
+
+    links: Vec<Link>,
+    /// Packets on their way between nodes, in the order they were sent.
+    in_flight: Vec<InFlight>,
+    /// Decides which packets are lost, always in the same way.
+    rng: XorShiftRng,
+}
+
+/// A direct connection between two nodes.
+struct Link
+{
+    nodes: [usize; 2],
+    /// Our ends of the streams to each of the nodes.
+    ends: [Connection; 2],
+    latency: Duration,
+    /// Whether the nodes have been cut off from each other.
+    cut: bool,
+}
+
+struct InFlight
+{
+    deliver_at: SystemTime,
+    link: usize,
+    /// Which end of the link the packet is going to.
+    to: usize,
+    packet: ::Packet,
+}
+
+/// A sandbox that prints the arguments of each command, and fails if the
+/// command is `false`.
+pub struct Echo;
+
+impl Simulation
+{
+    /// Creates a network of nodes with direct connections between pairs of them.
+    ///
+    /// Every node already knows about every other node and connection.
+    pub fn new(node_count: usize, links: &[(usize, usize)]) -> Self {
+        let clock = Clock::simulated(UNIX_EPOCH + Duration::from_secs(START_SECS));
+        let uuids: Vec<_> = (0..node_count).map(node_uuid).collect();
+
+        let mut nodes: Vec<_> = uuids.iter().enumerate().map(|(index, &uuid)| {
+            let mut network = Network::new(uuid, clock.now());
+
+            for (other_index, &other_uuid) in uuids.iter().enumerate().filter(|&(_, &other)| other != uuid) {
+                network.insert(network::Node {
+                    uuid: other_uuid,
+                    name: Some(node_name(other_index)),
+                    labels: BTreeMap::new(),
+                    address: None,
+                    connection: None,
+                    status: network::Status::Remote(Default::default()),
+                    last_seen: clock.now(),
+                    latency: None,
+                    public_key: None,
+                });
+            }
+
+            for &(a, b) in links {
+                network.connect(&uuids[a], &uuids[b]);
+            }
+
+            let mut config = Config::default();
+            config.identity.uuid = uuid;
+            config.identity.name = Some(node_name(index));
+            config.discovery_group = None;
+            config.clock = clock.clone();
+            // Each node gets its own sequence, so that nodes don't affect each other's choices.
+            config.random = Random::seeded([SEED[0], SEED[1], SEED[2], SEED[3] ^ (index as u32 + 1)]);
+
+            let mut node = local::connected::Node::new(uuid, None, network, &config);
+            node.builder = ci::Builder::inline(|| Box::new(Echo));
+            node
+        }).collect();
+
+        let links = links.iter().map(|&(a, b)| {
+            let ends = [connect(&mut nodes, a, b), connect(&mut nodes, b, a)];
+
+            Link {
+                nodes: [a, b],
+                ends: ends,
+                latency: Duration::from_millis(DEFAULT_LATENCY_MS),
+                cut: false,
+            }
+        }).collect();
+
+        Simulation {
+            clock: clock,
+            nodes: nodes,
+            drop_rate: 0.0,
+            links: links,
+            in_flight: Vec::new(),
+            rng: XorShiftRng::from_seed(SEED),
+        }
+    }
+
+    pub fn uuid(&self, index: usize) -> Uuid {
+        self.nodes[index].uuid
+    }
+
+    /// Sets how long packets take to cross the link between two nodes.
+    pub fn set_latency(&mut self, a: usize, b: usize, latency: Duration) {
+        for link in self.links.iter_mut().filter(|link| link.joins(a, b)) {
+            link.latency = latency;
+        }
+    }
+
+    /// Cuts a group of nodes off from the rest of the network.
+    ///
+    /// Packets already on their way across the cut are lost.
+    pub fn partition(&mut self, group: &[usize]) {
+        for link in self.links.iter_mut() {
+            if group.contains(&link.nodes[0]) != group.contains(&link.nodes[1]) {
+                link.cut = true;
+            }
+        }
+    }
+
+    /// Cuts the link between two nodes, leaving the rest of the network alone.
+    pub fn cut(&mut self, a: usize, b: usize) {
+        for link in self.links.iter_mut().filter(|link| link.joins(a, b)) {
+            link.cut = true;
+        }
+    }
+
+    /// Joins every partition back together.
+    pub fn heal(&mut self) {
+        for link in self.links.iter_mut() {
+            link.cut = false;
+        }
+    }
+
+    /// Runs the simulation until a condition holds, giving up after some time.
+    ///
+    /// Returns whether the condition ever held.
+    pub fn run_until<F>(&mut self, limit: Duration, condition: F) -> bool
+        where F: Fn(&Simulation) -> bool {
+        let give_up_at = self.clock.now() + limit;
+
+        while self.clock.now() < give_up_at {
+            if condition(self) { return true }
+            self.step();
+        }
+
+        condition(self)
+    }
+
+    /// Runs the simulation for some time.
+    pub fn run_for(&mut self, duration: Duration) {
+        self.run_until(duration, |_| false);
+    }
+
+    /// Moves the simulation forward by one step.
+    pub fn step(&mut self) {
+        self.deliver_packets();
+
+        for index in 0..self.nodes.len() {
+            self.receive_packets(index);
+        }
+
+        for node in self.nodes.iter_mut() {
+            node.tick().expect("node failed to tick");
+        }
+
+        self.collect_packets();
+        self.clock.advance(Duration::from_millis(STEP_MS));
+    }
+
+    /// Hands every packet that has crossed its link to the node it is for.
+    fn deliver_packets(&mut self) {
+        let now = self.clock.now();
+        let (due, in_flight) = self.in_flight.drain(..).partition(|packet| packet.deliver_at <= now);
+        self.in_flight = in_flight;
+
+        for packet in due.into_iter() {
+            let link = &mut self.links[packet.link];
+            if link.cut { continue }
+
+            // The node may have dropped its end of the link.
+            link.ends[packet.to].send_packet(&packet.packet).ok();
+        }
+    }
+
+    /// Lets a node handle the packets that have been delivered to it, the
+    /// same way it would if they came over sockets.
+    fn receive_packets(&mut self, index: usize) {
+        let node = &mut self.nodes[index];
+        let now = self.clock.now();
+
+        let neighbour_uuids: Vec<_> = node.network.nodes()
+            .filter(|neighbour| neighbour.connection.is_some())
+            .map(|neighbour| neighbour.uuid)
+            .collect();
+
+        for neighbour_uuid in neighbour_uuids {
+            let packets = match node.network.get_mut(&neighbour_uuid) {
+                Some(ref mut neighbour) if neighbour.connection.is_some() => {
+                    let packets = neighbour.connection.as_mut().unwrap().receive_packets();
+                    if packets.as_ref().map_or(false, |packets| !packets.is_empty()) {
+                        neighbour.last_seen = now;
+                    }
+
+                    packets
+                },
+                // Handling an earlier packet evicted the node, or dropped our connection to it.
+                _ => continue,
+            };
+
+            for packet in packets.expect("memory streams can't fail") {
+                if let PacketKind::Terminate(..) = packet.kind {
+                    node.evict_node(&neighbour_uuid).unwrap();
+                    break;
+                }
+
+                node.receive_packet(&neighbour_uuid, packet);
+            }
+        }
+    }
+
+    /// Picks up the packets that nodes have sent, and starts them across their links.
+    fn collect_packets(&mut self) {
+        let now = self.clock.now();
+
+        for (link_index, link) in self.links.iter_mut().enumerate() {
+            for from in 0..2 {
+                for packet in link.ends[from].receive_packets().expect("memory streams can't fail") {
+                    if self.rng.next_f32() < self.drop_rate { continue }
+
+                    self.in_flight.push(InFlight {
+                        deliver_at: now + link.latency,
+                        link: link_index,
+                        to: 1 - from,
+                        packet: packet,
+                    });
+                }
+            }
+        }
+    }
+}
+
+impl Link
+{
+    fn joins(&self, a: usize, b: usize) -> bool {
+        (self.nodes[0] == a && self.nodes[1] == b) || (self.nodes[0] == b && self.nodes[1] == a)
+    }
+}
+
+impl ci::Sandbox for Echo
+{
+    fn run(&mut self, command: ci::Command, _: &Path) -> ci::build::TaskOutput {
+        ci::build::TaskOutput {
+            output: format!("{}\n", command.arguments.join(" ")).into_bytes(),
+            result_code: if command.executable == "false" { 1 } else { 0 },
+        }
+    }
+}
+
+/// Gives a node a connection to another node, returning the other end of it.
+fn connect(nodes: &mut [local::connected::Node], from: usize, to: usize) -> Connection {
+    let (node_end, our_end) = MemoryStream::pair();
+    let to_uuid = nodes[to].uuid;
+
+    let mut connection = Connection::new(mio::Token(to), Box::new(node_end));
+    // Both ends of a simulated link always speak the same protocol.
+    connection.user_agent = Some(::user_agent());
+    connection.features = protocol::feature::all();
+    nodes[from].network.set_connection(&to_uuid, connection);
+
+    Connection::new(mio::Token(from), Box::new(our_end))
+}
+
+fn node_uuid(index: usize) -> Uuid {
+    let mut bytes = [0; 16];
+    bytes[15] = index as u8;
+    bytes[14] = (index >> 8) as u8;
+
+    Uuid::from_bytes(&bytes).unwrap()
+}
+
+fn node_name(index: usize) -> String {
+    format!("node-{}", index)
+}
+
+#[cfg(test)]
+mod test
+{
+    pub use super::*;
+
+    /// How long any simulation in these tests should need. Lossy links can
+    /// need many retransmissions, each waiting longer than the last.
+    const TIME_LIMIT_SECS: u64 = 600;
+
+    fn job(arguments: &[&str]) -> ci::Job {
+        ci::Job {
+            uuid: Uuid::new_v4(),
+            tasks: arguments.iter().map(|argument| {
+                ci::Task::new(ci::Command::new("echo", vec![argument.to_string()]))
+            }).collect(),
+        }
+    }
+
+    /// Runs a simulation until the first node has no work left, and takes its completed jobs.
+    fn run_to_completion(simulation: &mut Simulation) -> Vec<ci::dispatcher::CompletedJob> {
+        let finished = simulation.run_until(Duration::from_secs(TIME_LIMIT_SECS), |simulation| {
+            !simulation.nodes[0].dispatcher.has_work()
+        });
+        assert!(finished, "the job never finished");
+
+        simulation.nodes[0].dispatcher.completed_jobs().collect()
+    }
+
+    fn outputs(job: &ci::dispatcher::CompletedJob) -> Vec<String> {
+        let mut outputs: Vec<_> = job.task_results.iter()
+            .map(|result| String::from_utf8(result.output.output.clone()).unwrap())
+            .collect();
+        outputs.sort();
+        outputs
+    }
+
+    #[test]
+    fn work_is_done_by_a_neighbour() {
+        let mut simulation = Simulation::new(2, &[(0, 1)]);
+        simulation.nodes[0].dispatcher.enqueue(job(&["foo", "bar"])).unwrap();
+
+        let completed_jobs = run_to_completion(&mut simulation);
+        assert_eq!(completed_jobs.len(), 1);
+        assert_eq!(outputs(&completed_jobs[0]), vec!["bar\n", "foo\n"]);
+    }
+
+    #[test]
+    fn work_is_done_by_nodes_several_hops_away() {
+        let mut simulation = Simulation::new(4, &[(0, 1), (1, 2), (2, 3)]);
+        simulation.set_latency(0, 1, Duration::from_millis(50));
+        simulation.nodes[0].dispatcher.enqueue(job(&["a", "b", "c", "d"])).unwrap();
+
+        let completed_jobs = run_to_completion(&mut simulation);
+        assert_eq!(outputs(&completed_jobs[0]), vec!["a\n", "b\n", "c\n", "d\n"]);
+    }
+
+    #[test]
+    fn lost_work_is_resent() {
+        let mut simulation = Simulation::new(3, &[(0, 1), (1, 2)]);
+        simulation.drop_rate = 0.3;
+        simulation.nodes[0].dispatcher.enqueue(job(&["a", "b", "c"])).unwrap();
+
+        let completed_jobs = run_to_completion(&mut simulation);
+        assert_eq!(completed_jobs.len(), 1);
+        assert_eq!(outputs(&completed_jobs[0]), vec!["a\n", "b\n", "c\n"]);
+    }
+
+    #[test]
+    fn work_given_to_a_node_that_is_cut_off_is_reassigned() {
+        let mut simulation = Simulation::new(3, &[(0, 1), (0, 2)]);
+        simulation.nodes[0].dispatcher.enqueue(job(&["only once"])).unwrap();
+
+        // Cut off whichever node is given the work before it hears about it.
+        assert!(simulation.run_until(Duration::from_secs(TIME_LIMIT_SECS), |simulation| {
+            !simulation.nodes[0].dispatcher.executors().is_empty()
+        }));
+        // Nodes are always asked in order of UUID, so the first node to ask gets it.
+        let executor = simulation.uuid(1);
+        assert_eq!(simulation.nodes[0].dispatcher.executors(), vec![executor]);
+        simulation.partition(&[1]);
+
+        assert!(simulation.run_until(Duration::from_secs(TIME_LIMIT_SECS), |simulation| {
+            simulation.nodes[0].dispatcher.executors() == vec![simulation.uuid(2)]
+        }));
+
+        let completed_jobs = run_to_completion(&mut simulation);
+        assert_eq!(outputs(&completed_jobs[0]), vec!["only once\n"]);
+        assert!(simulation.nodes[0].network.get(&executor).is_none());
+    }
+
+    #[test]
+    fn simulations_run_the_same_way_every_time() {
+        let run = || {
+            let mut simulation = Simulation::new(4, &[(0, 1), (1, 2), (2, 3), (0, 3)]);
+            simulation.drop_rate = 0.3;
+            simulation.nodes[0].dispatcher.enqueue(job(&["a", "b", "c", "d"])).unwrap();
+
+            run_to_completion(&mut simulation);
+            (simulation.clock.now(), simulation.nodes.iter().map(|node| node.drops.clone()).collect::<Vec<_>>())
+        };
+
+        assert_eq!(run(), run());
+    }
+
+    #[test]
+    fn losing_one_link_of_a_triangle_keeps_every_node() {
+        let mut simulation = Simulation::new(3, &[(0, 1), (1, 2), (0, 2)]);
+        let uuids: Vec<_> = (0..3).map(|index| simulation.uuid(index)).collect();
+        simulation.cut(0, 2);
+
+        // Long enough for both ends of the cut link to time out.
+        simulation.run_for(Duration::from_secs(30));
+
+        for node in simulation.nodes.iter() {
+            assert_eq!(node.network.node_count(), 3);
+            assert!(node.network.edge(&uuids[0], &uuids[2]).is_none());
+            assert!(node.network.edge(&uuids[0], &uuids[1]).is_some());
+            assert!(node.network.edge(&uuids[1], &uuids[2]).is_some());
+        }
+
+        assert!(!simulation.nodes[0].is_directly_connected(&uuids[2]));
+        simulation.nodes[0].dispatcher.enqueue(job(&["a", "b", "c"])).unwrap();
+        simulation.nodes[1].config.accepts_work = false;
+
+        let completed_jobs = run_to_completion(&mut simulation);
+        assert_eq!(outputs(&completed_jobs[0]), vec!["a\n", "b\n", "c\n"]);
+    }
+
+    #[test]
+    fn topology_changes_reach_nodes_that_disagree_about_the_network() {
+        let mut simulation = Simulation::new(3, &[(0, 1), (1, 2)]);
+        let (first_uuid, last_uuid) = (simulation.uuid(0), simulation.uuid(2));
+
+        // The middle node wrongly thinks the last node hears from the first
+        // directly, so it isn't the last node's parent in its spanning tree.
+        simulation.nodes[1].network.connect(&first_uuid, &last_uuid);
+
+        let new_uuid = node_uuid(3);
+        simulation.nodes[0].broadcast_packet(&PacketKind::NodeJoined(::protocol::NodeJoined {
+            node: ::protocol::Node {
+                uuid: new_uuid,
+                name: Some(node_name(3)),
+                labels: BTreeMap::new(),
+                address: None,
+                status: None,
+                public_key: Vec::new(),
+            },
+        })).unwrap();
+
+        assert!(simulation.run_until(Duration::from_secs(1), |simulation| {
+            simulation.nodes[2].network.get(&new_uuid).is_some()
+        }));
+    }
+
+    #[test]
+    fn failed_tasks_cancel_their_dependants_across_the_network() {
+        let mut simulation = Simulation::new(3, &[(0, 1), (1, 2)]);
+
+        let build = ci::Task::new(ci::Command::new("false", Vec::new()));
+        let mut test = ci::Task::new(ci::Command::new("echo", vec!["test".to_owned()]));
+        let lint = ci::Task::new(ci::Command::new("echo", vec!["lint".to_owned()]));
+        test.dependencies = vec![build.uuid];
+
+        simulation.nodes[0].dispatcher.enqueue(ci::Job {
+            uuid: Uuid::new_v4(),
+            tasks: vec![test.clone(), build, lint],
+        }).unwrap();
+
+        let completed_jobs = run_to_completion(&mut simulation);
+        assert_eq!(outputs(&completed_jobs[0]), vec!["\n", "lint\n"]);
+        assert_eq!(completed_jobs[0].cancelled_tasks, vec![test]);
+    }
+
+    #[test]
+    fn nodes_that_do_not_accept_work_are_never_given_any() {
+        let mut simulation = Simulation::new(2, &[(0, 1)]);
+        simulation.nodes[1].config.accepts_work = false;
+        simulation.nodes[0].dispatcher.enqueue(job(&["nobody"])).unwrap();
+
+        simulation.run_for(Duration::from_secs(5));
+        assert!(simulation.nodes[0].dispatcher.has_work());
+        assert!(simulation.nodes[0].dispatcher.executors().is_empty());
+    }
+
+    #[test]
+    fn simulations_measure_the_latency_of_links() {
+        let mut simulation = Simulation::new(2, &[(0, 1)]);
+        simulation.set_latency(0, 1, Duration::from_millis(40));
+        simulation.run_for(Duration::from_secs(5));
+
+        let uuid = simulation.uuid(1);
+        let latency = simulation.nodes[0].network.get(&uuid).unwrap().latency.unwrap();
+        // Packets only move once per step, so each way can take up to a step longer.
+        assert!(latency >= Duration::from_millis(80) && latency <= Duration::from_millis(80 + 2 * STEP_MS));
+    }
+}
@@ -0,0 +1,161 @@
+//! The streams that nodes talk to each other over.
+//!
+//! Nodes on different hosts talk over TCP. Nodes and clients on the same
+//! host can talk over a Unix domain socket instead, which skips the network
+//! stack entirely.
+
+use mio::Evented;
+use mio::net::TcpStream;
+use mio_uds::UnixStream;
+use libc;
+
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, ToSocketAddrs};
+use std::os::unix::io::AsRawFd;
+use std::path::PathBuf;
+
+/// Written before a path to say it is the path of a Unix domain socket.
+pub const UNIX_PREFIX: &str = "unix:";
+
+/// A stream that a connection can be made over.
+pub trait Transport : Read + Write + Evented + fmt::Debug
+{
+    /// Reads data without taking it off the stream.
+    fn peek(&self, buf: &mut [u8]) -> io::Result<usize>;
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()>;
+
+    /// The address of the other end, if it can be reached over the network.
+    fn peer_addr(&self) -> Option<SocketAddr>;
+}
+
+/// Somewhere a node can be connected to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Address
+{
+    Tcp(SocketAddr),
+    /// The path of a Unix domain socket.
+    Unix(PathBuf),
+}
+
+impl Address
+{
+    /// Parses an address, looking up host names.
+    ///
+    /// Unix domain sockets are written as `unix:PATH`, anything else is
+    /// taken to be a host and port.
+    pub fn resolve(address: &str) -> io::Result<Self> {
+        if let Some(path) = address.strip_prefix(UNIX_PREFIX) {
+            return Ok(Address::Unix(PathBuf::from(path)));
+        }
+
+        match address.to_socket_addrs()?.next() {
+            Some(address) => Ok(Address::Tcp(address)),
+            None => Err(io::Error::new(io::ErrorKind::InvalidInput, format!("could not resolve address '{}'", address))),
+        }
+    }
+
+    /// Starts connecting to the address.
+    ///
+    /// TCP connections may not have opened yet when this returns.
+    pub fn connect(&self) -> io::Result<Box<dyn Transport>> {
+        match *self {
+            Address::Tcp(ref address) => Ok(Box::new(TcpStream::connect(address)?)),
+            Address::Unix(ref path) => Ok(Box::new(UnixStream::connect(path)?)),
+        }
+    }
+}
+
+impl From<SocketAddr> for Address
+{
+    fn from(address: SocketAddr) -> Self {
+        Address::Tcp(address)
+    }
+}
+
+impl fmt::Display for Address
+{
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Address::Tcp(ref address) => write!(fmt, "{}", address),
+            Address::Unix(ref path) => write!(fmt, "{}{}", UNIX_PREFIX, path.display()),
+        }
+    }
+}
+
+impl Transport for TcpStream
+{
+    fn peek(&self, buf: &mut [u8]) -> io::Result<usize> {
+        TcpStream::peek(self, buf)
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        TcpStream::shutdown(self, how)
+    }
+
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        TcpStream::peer_addr(self).ok()
+    }
+}
+
+impl Transport for UnixStream
+{
+    fn peek(&self, buf: &mut [u8]) -> io::Result<usize> {
+        // The standard library can't peek at Unix sockets yet.
+        let result = unsafe {
+            libc::recv(self.as_raw_fd(), buf.as_mut_ptr() as *mut libc::c_void, buf.len(), libc::MSG_PEEK)
+        };
+
+        if result < 0 { Err(io::Error::last_os_error()) } else { Ok(result as usize) }
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        UnixStream::shutdown(self, how)
+    }
+
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod test
+{
+    pub use super::*;
+
+    #[test]
+    fn unix_socket_paths_are_resolved() {
+        assert_eq!(Address::resolve("unix:/tmp/parapet.sock").unwrap(), Address::Unix(PathBuf::from("/tmp/parapet.sock")));
+    }
+
+    #[test]
+    fn other_addresses_are_resolved_as_tcp() {
+        assert_eq!(Address::resolve("127.0.0.1:53371").unwrap(), Address::Tcp("127.0.0.1:53371".parse().unwrap()));
+    }
+
+    #[test]
+    fn addresses_are_displayed_how_they_are_written() {
+        for address in &["unix:/tmp/parapet.sock", "127.0.0.1:53371"] {
+            assert_eq!(Address::resolve(address).unwrap().to_string(), *address);
+        }
+    }
+
+    #[test]
+    fn unix_sockets_can_be_peeked_at() {
+        let (mut a, b) = UnixStream::pair().unwrap();
+        let mut buf = [0; 1];
+
+        assert_eq!(Transport::peek(&b, &mut buf).unwrap_err().kind(), io::ErrorKind::WouldBlock);
+
+        a.write_all(&[42]).unwrap();
+        assert_eq!(Transport::peek(&b, &mut buf).unwrap(), 1);
+        assert_eq!(Transport::peek(&b, &mut buf).unwrap(), 1);
+        assert_eq!(buf, [42]);
+
+        drop(a);
+        let mut data = Vec::new();
+        (&b).read_to_end(&mut data).unwrap();
+        assert_eq!(Transport::peek(&b, &mut buf).unwrap(), 0);
+    }
+}
@@ -1,73 +1,115 @@
-use {Error, Network};
-use {network, ci};
-use network::Connection;
+use {Error, Network, Config, Control};
+use network;
+use network::{Connection, Discovery, Address};
 
 use mio;
-use mio::net::*;
 use slab::Slab;
-use uuid::Uuid;
 
 use std;
-use proto;
+use std::collections::HashMap;
 
 pub struct Parapet
 {
     pub node: network::local::Node,
     pub poll: mio::Poll,
+    /// Answers nodes on the local network segment that are looking for a network.
+    pub discovery: Option<Discovery>,
+    /// Answers programs on the same host that want to control us.
+    pub control: Option<Control>,
 }
 
 impl Parapet
 {
-    /// Create a new network.
-    pub fn new<A>(addr: A) -> Result<Self, Error>
-        where A: std::net::ToSocketAddrs {
-        let mut poll = mio::Poll::new()?;
+    /// Create a new network, listening on the address from the configuration.
+    pub fn new(config: Config) -> Result<Self, Error> {
+        let poll = mio::Poll::new()?;
+
+        let listener = network::local::tcp::listen(&config)?;
+        network::local::tcp::register(&poll, &listener)?;
+        println!("listening on {}", listener.local_addr()?);
+        let uuid = config.identity.uuid;
+
+        println!("using UUID {}", uuid);
 
-        let listener = network::local::tcp::bind(&mut poll, addr)?;
-        let uuid = Uuid::new_v4();
+        let discovery = Discovery::start(&config);
+        let control = Control::start(&config);
 
-        println!("assigning UUID {}", uuid);
+        let mut node = network::local::connected::Node::new(uuid, Some(listener), Network::new(uuid, config.clock.now()), &config);
+        node.unix_listener = network::local::unix::start(&poll, &config);
 
         Ok(Parapet {
             node: network::local::Node::Connected {
-                node: network::local::connected::Node {
-                    uuid: uuid,
-                    listener: Some(listener),
-                    network: Network::new(uuid),
-                    notify: network::Notify::new(),
-                    builder: ci::Builder::new(),
-                    dispatcher: ci::Dispatcher::new(),
-                },
-                pending_connections: Slab::with_capacity(1024),
+                node,
+                pending_connections: Slab::with_capacity(network::local::node::MAX_PENDING_CONNECTIONS),
+                outgoing_connections: HashMap::new(),
+                next_node_token: mio::Token(network::local::node::MAX_PENDING_CONNECTIONS),
             },
             poll: poll,
+            discovery,
+            control,
         })
     }
 
     /// Connect to an existing network.
     /// * `addr` - Any node on the network.
-    pub fn connect<A>(addr: A) -> Result<Self, std::io::Error>
+    pub fn connect<A>(addr: A, config: Config) -> Result<Self, std::io::Error>
         where A: std::net::ToSocketAddrs {
         let mut addresses = addr.to_socket_addrs()?;
         let address = addresses.next().expect("could not resolve address");
 
-        let stream = TcpStream::connect(&address)?;
+        Parapet::connect_to(&Address::Tcp(address), config)
+    }
+
+    /// Connect to an existing network, possibly over a Unix domain socket.
+    /// * `address` - Any node on the network.
+    pub fn connect_to(address: &Address, config: Config) -> Result<Self, std::io::Error> {
+        let stream = address.connect()?;
 
         let poll = mio::Poll::new()?;
-        poll.register(&stream, network::local::node::NEW_CONNECTION_TOKEN, mio::Ready::writable() | mio::Ready::readable(),
+        poll.register(&*stream, network::local::node::NEW_CONNECTION_TOKEN, network::local::node::connection_interest(),
             mio::PollOpt::edge())?;
 
-        let connection = Connection {
-            token: network::local::node::NEW_CONNECTION_TOKEN,
-            protocol: proto::wire::stream::Connection::new(stream, proto::wire::middleware::pipeline::default()),
-        };
+        let connection = Connection::new(network::local::node::NEW_CONNECTION_TOKEN, stream);
+        let listener = network::local::tcp::listen(&config)?;
+        let discovery = Discovery::start(&config);
+        let control = Control::start(&config);
 
         Ok(Parapet {
-            node: network::local::Node::Pending(network::local::pending::Node::new(connection)),
+            node: network::local::Node::Pending(network::local::pending::Node::new(connection, listener, config)),
             poll: poll,
+            discovery,
+            control,
         })
     }
 
+    /// Join a network on the local network segment if we can find one,
+    /// otherwise create a new network.
+    pub fn discover(config: Config) -> Result<Self, Error> {
+        let address = match Discovery::start(&config) {
+            Some(discovery) => {
+                println!("looking for a network to join");
+                discovery.find_network(config.discovery_timeout)?
+            },
+            None => None,
+        };
+
+        if let Some(address) = address {
+            println!("connecting to existing network on {}", address);
+            Ok(Parapet::connect(address, config)?)
+        } else {
+            println!("no network found, running new network");
+            Parapet::new(config)
+        }
+    }
+
+    /// Merge our network with another network.
+    /// * `address` - Any node on the other network.
+    pub fn merge(&mut self, address: &str) -> Result<(), Error> {
+        let address = Address::resolve(address)?;
+
+        self.node.merge_with(&address, &self.poll)
+    }
+
     pub fn run(&mut self) -> Result<(), Error> {
         loop {
             match self.tick() {
@@ -84,6 +126,24 @@ impl Parapet
     }
 
     pub fn tick(&mut self) -> Result<(), Error> {
-        self.node.tick(&mut self.poll)
+        self.node.tick(&mut self.poll)?;
+
+        if let (Some(ref discovery), network::local::Node::Connected { node, .. }) = (&self.discovery, &self.node) {
+            // Discovery is only a convenience, so it shouldn't bring the node down.
+            if let Err(e) = discovery.answer_queries(node) {
+                println!("warning: could not answer discovery queries: {}", e);
+            }
+        }
+
+        if let Some(ref mut control) = self.control {
+            control.serve(&mut self.node);
+        }
+
+        Ok(())
+    }
+
+    /// Gracefully leave the network.
+    pub fn terminate(&mut self, reason: &str) {
+        self.node.terminate(reason)
     }
 }
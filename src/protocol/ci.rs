@@ -2,10 +2,16 @@ use ci;
 
 use uuid::Uuid;
 
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::time::Duration;
+
 // A single command to execute.
 define_composite_type!(Task {
     uuid: Uuid,
-    command: Command
+    name: Option<String>,
+    command: Command,
+    dependencies: Vec<Uuid>
 });
 
 // A list of tasks to complete.
@@ -15,9 +21,14 @@ define_composite_type!(Work {
 });
 
 // A command to execute.
+//
+// The timeout is in milliseconds.
 define_composite_type!(Command {
     executable: String,
-    arguments: Vec<String>
+    arguments: Vec<String>,
+    environment: BTreeMap<String, String>,
+    working_dir: Option<String>,
+    timeout: Option<u64>
 });
 
 // The result for a single task.
@@ -38,21 +49,33 @@ define_packet!(WorkRequest);
 
 // Sent to from a node to another node, dishing out tasks for the
 // other node to complete.
+//
+// Sent reliably, so it must be acknowledged.
 define_packet!(WorkResponse {
+    sequence: u64,
     work: Work
 });
 
 // Sent from a node to a node indicating that it has finished
 // executing a piece of work.
+//
+// Sent reliably, so it must be acknowledged.
 define_packet!(WorkFinished {
+    sequence: u64,
     uuid: Uuid,
     tasks: Vec<TaskResult>
 });
 
+// Acknowledges a packet that was sent reliably.
+define_packet!(Acknowledgement {
+    sequence: u64
+});
+
 impl WorkResponse
 {
-    pub fn from_work(work: &ci::build::Work) -> Self {
+    pub fn from_work(work: &ci::build::Work, sequence: u64) -> Self {
         WorkResponse {
+            sequence,
             work: Work {
                 uuid: work.uuid.clone(),
                 tasks: work.tasks.iter().map(|task| Task::from_task(task)).collect(),
@@ -66,7 +89,9 @@ impl Task
     pub fn from_task(task: &ci::job::Task) -> Self {
         Task {
             uuid: task.uuid.clone(),
-            command: Command::from_command(&task.command)
+            name: task.name.clone(),
+            command: Command::from_command(&task.command),
+            dependencies: task.dependencies.clone(),
         }
     }
 }
@@ -88,6 +113,9 @@ impl Command
         Command {
             executable: command.executable.clone(),
             arguments: command.arguments.clone(),
+            environment: command.environment.clone(),
+            working_dir: command.working_dir.as_ref().map(|dir| dir.to_string_lossy().into_owned()),
+            timeout: command.timeout.map(|timeout| timeout.as_secs() * 1000 + timeout.subsec_millis() as u64),
         }
     }
 }
@@ -107,7 +135,9 @@ impl Into<ci::job::Task> for Task
     fn into(self) -> ci::job::Task {
         ci::job::Task {
             uuid: self.uuid,
+            name: self.name,
             command: self.command.into(),
+            dependencies: self.dependencies,
         }
     }
 }
@@ -130,6 +160,9 @@ impl Into<ci::job::Command> for Command {
         ci::job::Command {
             executable: self.executable,
             arguments: self.arguments,
+            environment: self.environment,
+            working_dir: self.working_dir.map(PathBuf::from),
+            timeout: self.timeout.map(Duration::from_millis),
         }
     }
 }
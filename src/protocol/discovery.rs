@@ -0,0 +1,26 @@
+use protocol::UserAgent;
+
+use uuid::Uuid;
+
+// Multicast by a node looking for a network to join.
+define_packet!(DiscoveryQuery {
+    user_agent: UserAgent
+});
+
+// Sent back to a node looking for a network, by a node that is part of one.
+define_packet!(Announcement {
+    user_agent: UserAgent,
+    uuid: Uuid,
+    // A human-readable name for the node.
+    name: Option<String>,
+    // The address the node can be connected to on, if it knows it.
+    address: Option<String>,
+    // The port the node is listening on, for when it doesn't know its address.
+    port: u16
+});
+
+// The datagrams sent over the discovery socket.
+define_packet_kind!(Datagram: u32 {
+    0x00 => DiscoveryQuery,
+    0x01 => Announcement
+});
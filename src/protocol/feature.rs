@@ -0,0 +1,55 @@
+//! Optional protocol features.
+//!
+//! Features are advertised in the `Ping` and `Pong` during the handshake. A
+//! feature is only used on a connection if both ends support it, which
+//! lets nodes of different protocol revisions share a network.
+
+/// Nodes periodically ping their neighbours and evict the unresponsive ones.
+pub const HEARTBEAT: &str = "heartbeat";
+
+/// Nodes connect directly to other nodes they learn the address of.
+pub const MESH: &str = "mesh";
+
+/// Nodes merge their network with another network they connect to.
+pub const MERGE: &str = "merge";
+
+/// Every feature supported by this client.
+pub const ALL: &[&str] = &[
+    HEARTBEAT,
+    MESH,
+    MERGE,
+];
+
+/// Every feature supported by this client, ready to be advertised.
+pub fn all() -> Vec<String> {
+    ALL.iter().map(|f| f.to_string()).collect()
+}
+
+/// Checks if this client supports a feature.
+pub fn is_supported(feature: &str) -> bool {
+    ALL.contains(&feature)
+}
+
+/// Gets the features that both clients support.
+pub fn common(ours: &[String], theirs: &[String]) -> Vec<String> {
+    ours.iter().filter(|f| theirs.contains(f)).cloned().collect()
+}
+
+#[cfg(test)]
+mod test
+{
+    pub use super::*;
+
+    fn features(features: &[&str]) -> Vec<String> {
+        features.iter().map(|f| f.to_string()).collect()
+    }
+
+    #[test]
+    fn common_features_only_includes_features_both_sides_support() {
+        let old = features(&["heartbeat"]);
+        let new = features(&["heartbeat", "shiny"]);
+
+        assert_eq!(common(&new, &old), vec!["heartbeat".to_owned()]);
+        assert_eq!(common(&old, &new), vec!["heartbeat".to_owned()]);
+    }
+}
@@ -1,24 +1,59 @@
 pub use self::network::{Network, Node, Edge};
 pub use self::user_agent::UserAgent;
 pub use self::ci::*;
+pub use self::topology::*;
+pub use self::discovery::{Datagram, DiscoveryQuery, Announcement};
 
 pub mod network;
 pub mod user_agent;
+pub mod feature;
 pub mod ci;
+pub mod topology;
+pub mod discovery;
 
 use network::Path;
+use proto::{self, Parcel};
 use uuid::Uuid;
 
+use std::collections::{BTreeMap, VecDeque};
+use std::io::prelude::*;
+use std::io::Cursor;
+
+/// The kinds of a `Ping` and a `Pong`, which must never change.
+const PING_KIND: u32 = 0x00;
+const PONG_KIND: u32 = 0x01;
+
 // Ping a node with some information.
+//
+// The user agent must stay the first field, so that nodes of every protocol
+// version can read it (see `read_user_agent`). New fields go at the end.
 define_packet!(Ping {
     user_agent: UserAgent,
-    data: Vec<u8>
+    data: Vec<u8>,
+    // An ephemeral public key for encrypting the connection.
+    // Empty if the connection won't be encrypted.
+    public_key: Vec<u8>,
+    // The optional features that the client supports.
+    features: Vec<String>
 });
 
 // Response to a ping.
+//
+// Like the `Ping`, the user agent must stay the first field.
 define_packet!(Pong {
     user_agent: UserAgent,
-    data: Vec<u8>
+    data: Vec<u8>,
+    // A random challenge to prove knowledge of the network key with.
+    // Empty if the network doesn't have a key.
+    challenge: Vec<u8>,
+    // The data from the `Ping`, hashed with the network key.
+    // Empty if the network doesn't have a key.
+    proof: Vec<u8>,
+    // An ephemeral public key for encrypting the connection.
+    // Empty if the connection won't be encrypted.
+    public_key: Vec<u8>,
+    // The optional features that the client supports.
+    features: Vec<String>
 });
 
 // Immediately disconnect.
@@ -26,8 +61,33 @@ define_packet!(Terminate {
     reason: String
 });
 
+// Sent back to the sender of a packet that couldn't be delivered.
+define_packet!(Unreachable {
+    // The node the packet was for.
+    destination: Uuid,
+    reason: String
+});
+
 // Request to join a network.
-define_packet!(JoinRequest);
+define_packet!(JoinRequest {
+    // The challenge from the `Pong`, hashed with the network key.
+    proof: Vec<u8>,
+    // The UUID we would like to keep, if we have been part of a network before.
+    uuid: Option<Uuid>,
+    // The UUID we would like to keep, hashed with a secret shared between
+    // our identity key and the public key from the `Pong`.
+    // Empty if we don't have an identity key.
+    identity_proof: Vec<u8>,
+    // A human-readable name for the node.
+    name: Option<String>,
+    // Free-form information about the node, such as its hostname.
+    labels: BTreeMap<String, String>,
+    // The address we can be connected to on.
+    address: Option<String>,
+    // The public key of our long-term identity.
+    // Empty if we don't have one.
+    public_key: Vec<u8>
+});
 
 // Response for a network join request.
 define_packet!(JoinResponse {
@@ -36,23 +96,110 @@ define_packet!(JoinResponse {
     network: Network
 });
 
-define_packet!(Packet {
-    path: Path,
-    kind: PacketKind
+// Request to connect directly to a node that is already in our network.
+define_packet!(ConnectRequest {
+    // The UUID of the node making the request.
+    uuid: Uuid,
+    // The challenge from the `Pong`, hashed with the network key.
+    proof: Vec<u8>,
+    // The UUID, hashed with a secret shared between the identity key of the
+    // node making the request and the public key from the `Pong`.
+    identity_proof: Vec<u8>
+});
+
+// Response for a connect request.
+define_packet!(ConnectResponse {
+    uuid: Uuid,
+    // Our UUID, hashed with a secret shared between our identity key and
+    // the public key from the `Ping`. Empty if we don't have an identity key.
+    identity_proof: Vec<u8>
+});
+
+// Request to merge the network of the sender with the network of the recipient.
+define_packet!(MergeRequest {
+    // The UUID of the node making the request.
+    uuid: Uuid,
+    // The challenge from the `Pong`, hashed with the network key.
+    proof: Vec<u8>,
+    // The UUID, hashed with a secret shared between the identity key of the
+    // node making the request and the public key from the `Pong`.
+    identity_proof: Vec<u8>,
+    // The network the sender is a part of.
+    network: Network
+});
+
+// Response for a merge request.
+define_packet!(MergeResponse {
+    uuid: Uuid,
+    // Our UUID, hashed with a secret shared between our identity key and
+    // the public key from the `Ping`. Empty if we don't have an identity key.
+    identity_proof: Vec<u8>,
+    // The network the sender is a part of.
+    network: Network
 });
 
+/// A packet sent between two nodes.
+///
+/// Every packet starts with the hops of its path and its kind, followed by
+/// the packet itself and then the rest of the path. The start must never
+/// change, so that nodes of every protocol version can read the user agent
+/// of a `Ping` or `Pong` and tell each other that they're incompatible.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Packet
+{
+    pub path: Path,
+    pub kind: PacketKind,
+}
+
+impl Parcel for Packet
+{
+    fn read(read: &mut dyn Read) -> Result<Self, proto::Error> {
+        let hops = Parcel::read(read)?;
+        let kind = Parcel::read(read)?;
+
+        Ok(Packet {
+            path: Path {
+                hops,
+                hop_limit: Parcel::read(read)?,
+                broadcast_id: Parcel::read(read)?,
+            },
+            kind,
+        })
+    }
+
+    fn write(&self, write: &mut dyn Write) -> Result<(), proto::Error> {
+        self.path.hops.write(write)?;
+        self.kind.write(write)?;
+        self.path.hop_limit.write(write)?;
+        self.path.broadcast_id.write(write)?;
+
+        Ok(())
+    }
+}
+
 // Defines a packet kind enum.
 define_packet_kind!(PacketKind: u32 {
     0x00 => Ping,
     0x01 => Pong,
     0x05 => Terminate,
+    0x06 => Unreachable,
     0x10 => JoinRequest,
     0x11 => JoinResponse,
+    0x12 => ConnectRequest,
+    0x13 => ConnectResponse,
+    0x14 => MergeRequest,
+    0x15 => MergeResponse,
+    0x20 => NodeJoined,
+    0x21 => EdgeAdded,
+    0x22 => NodeLeft,
+    0x23 => NetworkMerged,
+    0x24 => EdgeRemoved,
     0x35 => WorkAvailable,
     0x36 => WorkComplete,
     0x40 => WorkRequest,
     0x41 => WorkResponse,
-    0x42 => WorkFinished
+    0x42 => WorkFinished,
+    0x43 => Acknowledgement
 });
 
 impl Packet
@@ -71,3 +218,66 @@ impl Packet
     }
 }
 
+/// Reads the user agent from a `Ping` or `Pong` of any protocol version.
+///
+/// Returns `None` if the data isn't a `Ping` or `Pong`.
+pub fn read_user_agent(data: &[u8]) -> Option<UserAgent> {
+    let mut read = Cursor::new(data);
+
+    let _hops: VecDeque<Uuid> = Parcel::read(&mut read).ok()?;
+    match Parcel::read(&mut read).ok()? {
+        PING_KIND | PONG_KIND => UserAgent::read(&mut read).ok(),
+        _ => None,
+    }
+}
+
+
+#[cfg(test)]
+mod test
+{
+    pub use super::*;
+
+    fn ping() -> Packet {
+        Packet {
+            path: Path::empty(),
+            kind: PacketKind::Ping(Ping {
+                user_agent: ::user_agent(),
+                data: vec![1, 2, 3],
+                public_key: vec![4, 5, 6],
+                features: feature::all(),
+            }),
+        }
+    }
+
+    #[test]
+    fn the_user_agent_can_be_read_from_a_ping() {
+        assert_eq!(read_user_agent(&ping().raw_bytes().unwrap()), Some(::user_agent()));
+    }
+
+    #[test]
+    fn the_user_agent_is_only_read_from_a_ping_or_pong() {
+        let terminate = Packet {
+            path: Path::empty(),
+            kind: PacketKind::Terminate(Terminate { reason: "goodbye".to_owned() }),
+        };
+
+        assert_eq!(read_user_agent(&terminate.raw_bytes().unwrap()), None);
+    }
+
+    #[test]
+    fn the_first_version_of_the_protocol_can_read_our_ping() {
+        let data = ping().raw_bytes().unwrap();
+        let mut read = Cursor::new(&data);
+
+        // The first version had a path of only hops, and a ping of only a user agent and data.
+        let hops: VecDeque<Uuid> = Parcel::read(&mut read).unwrap();
+        let kind: u32 = Parcel::read(&mut read).unwrap();
+        let user_agent = UserAgent::read(&mut read).unwrap();
+        let ping_data: Vec<u8> = Parcel::read(&mut read).unwrap();
+
+        assert!(hops.is_empty());
+        assert_eq!(kind, PING_KIND);
+        assert_eq!(user_agent, ::user_agent());
+        assert_eq!(ping_data, vec![1, 2, 3]);
+    }
+}
@@ -1,6 +1,9 @@
 use network;
 use uuid::Uuid;
 
+use std::collections::BTreeMap;
+use std::time::SystemTime;
+
 define_composite_type!(Network {
     nodes: Vec<Node>,
     edges: Vec<Edge>
@@ -9,8 +12,17 @@ define_composite_type!(Network {
 define_composite_type!(Node {
     // The UUID of the node.
     uuid: Uuid,
+    // A human-readable name for the node.
+    name: Option<String>,
+    // Free-form information about the node, such as its hostname.
+    labels: BTreeMap<String, String>,
+    // The address the node can be connected to on, if it is listening.
+    address: Option<String>,
     // The status of the node, if it is remote.
-    status: Option<NodeStatus>
+    status: Option<NodeStatus>,
+    // The public key of the node's long-term identity.
+    // Empty if it doesn't have one.
+    public_key: Vec<u8>
 });
 
 // Defines the status of a remote node.
@@ -20,7 +32,9 @@ define_composite_type!(NodeStatus {
 
 define_composite_type!(Edge {
     a: Uuid,
-    b: Uuid
+    b: Uuid,
+    // The round-trip latency of the edge in milliseconds, if it has been measured.
+    latency: Option<f32>
 });
 
 impl Network
@@ -38,20 +52,25 @@ impl Node
     pub fn from_node(node: &network::Node) -> Self {
         Node {
             uuid: node.uuid.clone(),
+            name: node.name.clone(),
+            labels: node.labels.clone(),
+            address: node.address.map(|address| address.to_string()),
             status: match node.status {
                 network::Status::Local => None,
                 network::Status::Remote(ref status) => Some(NodeStatus::from_remote_status(status)),
             },
+            public_key: node.public_key.clone().unwrap_or_default(),
         }
     }
 }
 
 impl Edge
 {
-    fn from_edge(edge: &network::Edge) -> Self {
+    pub fn from_edge(edge: &network::Edge) -> Self {
         Edge {
             a: edge.a.clone(),
             b: edge.b.clone(),
+            latency: edge.weight.map(|weight| weight.0),
         }
     }
 }
@@ -65,26 +84,34 @@ impl NodeStatus
     }
 }
 
-impl Into<network::Network> for Network
+impl Network
 {
-    fn into(self) -> network::Network {
-        network::Network {
-            nodes: self.nodes.into_iter().map(|n| (n.uuid.clone(), n.into())).collect(),
-            edges: self.edges.into_iter().map(|n| n.into()).collect(),
-        }
+    /// Builds our view of a network, as if we had just heard from every node.
+    pub fn into_network(self, last_seen: SystemTime) -> network::Network {
+        let mut network = network::Network::empty();
+        network.nodes = self.nodes.into_iter().map(|n| (n.uuid, n.into_node(last_seen))).collect();
+        network.edges = self.edges.into_iter().map(|n| n.into()).collect();
+        network
     }
 }
 
-impl Into<network::Node> for Node
+impl Node
 {
-    fn into(self) -> network::Node {
+    /// Builds our view of a node, as if we had just heard from it.
+    pub fn into_node(self, last_seen: SystemTime) -> network::Node {
         network::Node {
             uuid: self.uuid,
+            name: self.name,
+            labels: self.labels,
+            address: self.address.and_then(|address| address.parse().ok()),
             connection: None,
             status: match self.status {
                 Some(status) => network::Status::Remote(status.into()),
                 None => network::Status::Local,
-            }
+            },
+            last_seen,
+            latency: None,
+            public_key: if self.public_key.is_empty() { None } else { Some(self.public_key) },
         }
     }
 }
@@ -95,6 +122,7 @@ impl Into<network::Edge> for Edge
         network::Edge {
             a: self.a,
             b: self.b,
+            weight: self.latency.map(network::Weight),
         }
     }
 }
@@ -0,0 +1,30 @@
+use protocol::{Network, Node, Edge};
+
+use uuid::Uuid;
+
+// Broadcasted when a new node joins the network.
+define_packet!(NodeJoined {
+    node: Node
+});
+
+// Broadcasted when two nodes become directly connected, or when the
+// latency of the connection between them changes.
+define_packet!(EdgeAdded {
+    edge: Edge
+});
+
+// Broadcasted when two directly connected nodes lose their connection.
+define_packet!(EdgeRemoved {
+    edge: Edge
+});
+
+// Broadcasted when a node leaves the network.
+define_packet!(NodeLeft {
+    uuid: Uuid
+});
+
+// Broadcasted when our network is merged with another network.
+define_packet!(NetworkMerged {
+    // The nodes and edges of the other network, including the edge joining the two.
+    network: Network
+});
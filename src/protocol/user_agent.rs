@@ -1,4 +1,6 @@
 
+// Nodes of every protocol version must be able to read a user agent, so
+// its fields must never change.
 define_composite_type!(UserAgent {
     // The client name.
     client: String,
@@ -17,3 +19,25 @@ impl UserAgent
     }
 }
 
+#[cfg(test)]
+mod test
+{
+    pub use super::*;
+
+    fn user_agent(protocol_major: u16) -> UserAgent {
+        UserAgent {
+            client: "test".to_owned(),
+            protocol_major: protocol_major,
+            protocol_revision: 0,
+        }
+    }
+
+    #[test]
+    fn is_compatible_only_checks_the_major_version() {
+        let mut newer_revision = user_agent(1);
+        newer_revision.protocol_revision = 3;
+
+        assert!(user_agent(1).is_compatible(&newer_revision));
+        assert!(!user_agent(1).is_compatible(&user_agent(2)));
+    }
+}
@@ -0,0 +1,87 @@
+//! Making random choices.
+//!
+//! Nodes make their random choices through a `Random` rather than asking
+//! the system, so that simulated networks make the same choices on every
+//! run. Secrets such as keys and challenges always come from the system.
+
+use rand::{self, Rng, SeedableRng, XorShiftRng};
+use uuid::Uuid;
+
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+/// Where a node gets its random numbers from.
+#[derive(Clone)]
+pub struct Random
+{
+    /// The generator, if it is seeded.
+    ///
+    /// Every copy of a seeded generator shares the same sequence.
+    seeded: Option<Arc<Mutex<XorShiftRng>>>,
+}
+
+impl Random
+{
+    /// Random numbers from the system.
+    pub fn system() -> Self {
+        Random { seeded: None }
+    }
+
+    /// Random numbers that are always the same for the same seed.
+    pub fn seeded(seed: [u32; 4]) -> Self {
+        Random { seeded: Some(Arc::new(Mutex::new(XorShiftRng::from_seed(seed)))) }
+    }
+
+    /// Generates a random (version 4) UUID.
+    pub fn uuid(&mut self) -> Uuid {
+        let mut bytes = [0; 16];
+        self.fill_bytes(&mut bytes);
+        Uuid::from_random_bytes(bytes)
+    }
+
+    pub fn is_seeded(&self) -> bool { self.seeded.is_some() }
+}
+
+impl Rng for Random
+{
+    fn next_u32(&mut self) -> u32 {
+        match self.seeded {
+            Some(ref rng) => rng.lock().unwrap().next_u32(),
+            None => rand::thread_rng().next_u32(),
+        }
+    }
+}
+
+impl Default for Random
+{
+    fn default() -> Self { Random::system() }
+}
+
+impl fmt::Debug for Random
+{
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_seeded() { "Random::seeded".fmt(fmt) } else { "Random::system".fmt(fmt) }
+    }
+}
+
+#[cfg(test)]
+mod test
+{
+    pub use super::*;
+
+    #[test]
+    fn the_same_seed_gives_the_same_numbers() {
+        let (mut a, mut b) = (Random::seeded([1, 2, 3, 4]), Random::seeded([1, 2, 3, 4]));
+
+        assert_eq!(a.uuid(), b.uuid());
+        assert_eq!(a.gen::<u64>(), b.gen::<u64>());
+    }
+
+    #[test]
+    fn copies_of_a_seeded_generator_share_the_sequence() {
+        let mut random = Random::seeded([1, 2, 3, 4]);
+        let mut copy = random.clone();
+
+        assert!(random.uuid() != copy.uuid());
+    }
+}
@@ -1,15 +1,32 @@
 extern crate parapet as pp;
 extern crate clap;
+#[macro_use]
+extern crate serde_json;
 
-use pp::{Parapet, Interactive};
+use pp::{Parapet, Interactive, Config, Identity, Session};
+use pp::ci;
+use pp::network::Address;
+
+use clap::ArgMatches;
+use serde_json::Value;
+
+use std::collections::HashSet;
+use std::{fs, process, thread};
+use std::time::{Duration, Instant};
+
+/// How often `submit` asks how far its job has got.
+const SUBMIT_POLL_INTERVAL_MS: u64 = 200;
+/// The exit code of `submit` when the job takes too long, the same as `timeout(1)`.
+const SUBMIT_TIMED_OUT_EXIT_CODE: i32 = 124;
 
 const DESCRIPTION: &'static str = "
     If you pass an address, it will connect to an existing node on
-    some network, otherwise a new network will be created.
+    some network. Otherwise, it will look for a network to join on the
+    local network segment, and create a new network if it can't find one.
 ";
 
 fn main() {
-    use clap::{App, Arg};
+    use clap::{App, AppSettings, Arg, SubCommand};
 
     let matches = App::new("parapet")
         // .version("1.0")
@@ -17,7 +34,7 @@ fn main() {
         .about("Peer-to-peer build system")
         .after_help(DESCRIPTION)
         .arg(Arg::with_name("address")
-            .help("The address of an existing node on a network to connect to")
+            .help("The address of an existing node on a network to connect to, or unix:PATH for a node on the same host")
             .index(1))
         .arg(Arg::with_name("interactive")
             .long("interactive")
@@ -28,8 +45,240 @@ fn main() {
             .long("local")
             .short("l")
             .help("Connect to an existing node running locally on the default port"))
+        .arg(Arg::with_name("heartbeat-interval")
+            .long("heartbeat-interval")
+            .takes_value(true)
+            .value_name("MILLISECONDS")
+            .help("How often to ping directly connected nodes"))
+        .arg(Arg::with_name("heartbeat-timeout")
+            .long("heartbeat-timeout")
+            .takes_value(true)
+            .value_name("MILLISECONDS")
+            .help("How long a directly connected node can be silent before it is evicted"))
+        .arg(Arg::with_name("reconnect-delay")
+            .long("reconnect-delay")
+            .takes_value(true)
+            .value_name("MILLISECONDS")
+            .help("How long to wait after failing to rejoin the network, doubling after every failure"))
+        .arg(Arg::with_name("max-reconnect-delay")
+            .long("max-reconnect-delay")
+            .takes_value(true)
+            .value_name("MILLISECONDS")
+            .help("The longest to wait between attempts to rejoin the network"))
+        .arg(Arg::with_name("max-rejoin-attempts")
+            .long("max-rejoin-attempts")
+            .takes_value(true)
+            .value_name("COUNT")
+            .help("How many times to try to rejoin the network before carrying on alone"))
+        .arg(Arg::with_name("retransmit-interval")
+            .long("retransmit-interval")
+            .takes_value(true)
+            .value_name("MILLISECONDS")
+            .help("How long to wait for work to be acknowledged before sending it again, doubling after every attempt"))
+        .arg(Arg::with_name("network-key")
+            .long("network-key")
+            .takes_value(true)
+            .value_name("KEY")
+            .env("PARAPET_NETWORK_KEY")
+            .help("A pre-shared key that nodes must know in order to join the network"))
+        .arg(Arg::with_name("state-dir")
+            .long("state-dir")
+            .takes_value(true)
+            .value_name("DIRECTORY")
+            .env("PARAPET_STATE_DIR")
+            .help("A directory to keep the identity of the node in across restarts"))
+        .arg(Arg::with_name("name")
+            .long("name")
+            .takes_value(true)
+            .value_name("NAME")
+            .help("A human-readable name for the node"))
+        .arg(Arg::with_name("bind")
+            .long("bind")
+            .takes_value(true)
+            .value_name("IP")
+            .help("The address to listen for other nodes on, defaults to 127.0.0.1"))
+        .arg(Arg::with_name("port")
+            .long("port")
+            .takes_value(true)
+            .value_name("PORT")
+            .help("The port to listen for other nodes on, 0 picks a random port"))
+        .arg(Arg::with_name("advertise-address")
+            .long("advertise-address")
+            .takes_value(true)
+            .value_name("IP:PORT")
+            .help("The address other nodes should use to connect to us"))
+        .arg(Arg::with_name("fan-out")
+            .long("fan-out")
+            .takes_value(true)
+            .value_name("COUNT")
+            .help("How many nodes to connect to directly, 0 only connects to the node we join through"))
+        .arg(Arg::with_name("no-discovery")
+            .long("no-discovery")
+            .help("Don't look for other nodes on the local network segment, or answer nodes that are looking"))
+        .arg(Arg::with_name("discovery-group")
+            .long("discovery-group")
+            .takes_value(true)
+            .value_name("IP:PORT")
+            .help("The multicast group to find other nodes on the local network segment with"))
+        .arg(Arg::with_name("discovery-timeout")
+            .long("discovery-timeout")
+            .takes_value(true)
+            .value_name("MILLISECONDS")
+            .help("How long to look for a network to join before creating a new one"))
+        .arg(Arg::with_name("unix-socket")
+            .long("unix-socket")
+            .takes_value(true)
+            .value_name("PATH")
+            .help("A Unix domain socket to listen on for nodes and clients on the same host"))
+        .arg(Arg::with_name("control")
+            .long("control")
+            .takes_value(true)
+            .value_name("ADDRESS")
+            .help("A loopback address or unix:PATH to answer JSON-RPC control requests on, loopback addresses need --state-dir"))
+        .arg(Arg::with_name("label")
+            .long("label")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .value_name("KEY=VALUE")
+            .help("Free-form information about the node, such as its owner or rack"))
+        .subcommand(SubCommand::with_name("submit")
+            .about("Runs a job through the control API of a running node, waits for it to finish, and exits with its result code")
+            .setting(AppSettings::TrailingVarArg)
+            .arg(Arg::with_name("node")
+                .long("node")
+                .takes_value(true)
+                .required(true)
+                .value_name("ADDRESS")
+                .help("The control address of the node to submit the job through, a loopback address or unix:PATH"))
+            .arg(Arg::with_name("timeout")
+                .long("timeout")
+                .takes_value(true)
+                .value_name("SECONDS")
+                .help("How long to wait for the job before cancelling it"))
+            .arg(Arg::with_name("file")
+                .long("file")
+                .short("f")
+                .takes_value(true)
+                .value_name("PATH")
+                .help("The job file to run, defaults to parapet.toml"))
+            .arg(Arg::with_name("command")
+                .multiple(true)
+                .conflicts_with("file")
+                .help("A command to run instead of a job file")))
         .get_matches();
 
+    let mut config = Config::default();
+
+    if let Some(interval) = matches.value_of("heartbeat-interval") {
+        config.heartbeat_interval = Duration::from_millis(interval.parse().expect("heartbeat interval must be a number"));
+    }
+
+    if let Some(timeout) = matches.value_of("heartbeat-timeout") {
+        config.heartbeat_timeout = Duration::from_millis(timeout.parse().expect("heartbeat timeout must be a number"));
+    }
+
+    if let Some(delay) = matches.value_of("reconnect-delay") {
+        config.reconnect_delay = Duration::from_millis(delay.parse().expect("reconnect delay must be a number"));
+    }
+
+    if let Some(delay) = matches.value_of("max-reconnect-delay") {
+        config.max_reconnect_delay = Duration::from_millis(delay.parse().expect("max reconnect delay must be a number"));
+    }
+
+    if let Some(attempts) = matches.value_of("max-rejoin-attempts") {
+        config.max_rejoin_attempts = attempts.parse().expect("max rejoin attempts must be a number");
+    }
+
+    if let Some(interval) = matches.value_of("retransmit-interval") {
+        config.retransmit_interval = Duration::from_millis(interval.parse().expect("retransmit interval must be a number"));
+    }
+
+    config.network_key = matches.value_of("network-key").map(|key| key.to_owned());
+
+    if let Some(state_dir) = matches.value_of("state-dir") {
+        config.state_dir = Some(state_dir.into());
+        config.identity = match Identity::load_or_create(state_dir) {
+            Ok(identity) => identity,
+            Err(pp::Error::InvalidIdentity { line: 0, reason }) => {
+                eprintln!("error: {}/identity: {}", state_dir, reason);
+                process::exit(1);
+            },
+            Err(pp::Error::InvalidIdentity { line, reason }) => {
+                eprintln!("error: {}/identity:{}: {}", state_dir, line, reason);
+                process::exit(1);
+            },
+            Err(e) => {
+                eprintln!("error: could not load the identity from {}: {:?}", state_dir, e);
+                process::exit(1);
+            },
+        };
+    }
+
+    if let Some(name) = matches.value_of("name") {
+        config.identity.name = Some(name.to_owned());
+
+        if let Some(state_dir) = matches.value_of("state-dir") {
+            config.identity.save(state_dir).expect("could not save the identity");
+        }
+    }
+
+    for label in matches.values_of("label").into_iter().flat_map(|labels| labels) {
+        let mut parts = label.splitn(2, '=');
+
+        match (parts.next(), parts.next()) {
+            (Some(key), Some(value)) => { config.labels.insert(key.to_owned(), value.to_owned()); },
+            _ => {
+                eprintln!("error: labels must look like KEY=VALUE, but got '{}'", label);
+                process::exit(1);
+            },
+        }
+    }
+
+    if let Some(bind_address) = matches.value_of("bind") {
+        config.bind_address = bind_address.parse().expect("bind address must be an IP address");
+    }
+
+    if let Some(port) = matches.value_of("port") {
+        config.port = Some(port.parse().expect("port must be a number"));
+    }
+
+    if let Some(fan_out) = matches.value_of("fan-out") {
+        config.fan_out = fan_out.parse().expect("fan out must be a number");
+    }
+
+    if let Some(advertised_address) = matches.value_of("advertise-address") {
+        config.advertised_address = Some(advertised_address.parse().expect("advertised address must look like IP:PORT"));
+    }
+
+    if let Some(group) = matches.value_of("discovery-group") {
+        config.discovery_group = Some(group.parse().expect("discovery group must look like IP:PORT"));
+    }
+
+    if matches.is_present("no-discovery") {
+        config.discovery_group = None;
+    }
+
+    if let Some(timeout) = matches.value_of("discovery-timeout") {
+        config.discovery_timeout = Duration::from_millis(timeout.parse().expect("discovery timeout must be a number"));
+    }
+
+    config.unix_socket = matches.value_of("unix-socket").map(|path| path.into());
+
+    if let Some(address) = matches.value_of("control") {
+        config.control_address = match Address::resolve(address) {
+            Ok(address) => Some(address),
+            Err(e) => {
+                eprintln!("error: could not resolve the control address {}: {}", address, e);
+                process::exit(1);
+            },
+        };
+    }
+
+    if let Some(matches) = matches.subcommand_matches("submit") {
+        process::exit(submit(matches, config));
+    }
+
     let address: Option<String> = if let Some(address) = matches.value_of("address") {
         Some(address.to_string())
     } else if matches.is_present("local") {
@@ -39,24 +288,222 @@ fn main() {
         None
     };
 
-    let mut parapet = if let Some(address) = address {
+    let parapet = if let Some(address) = address {
         println!("connecting to existing network on {}", address);
 
-        Parapet::connect(&*address).unwrap()
+        Address::resolve(&address).and_then(|address| Parapet::connect_to(&address, config)).map_err(pp::Error::from)
+    } else if config.discovery_group.is_some() {
+        Parapet::discover(config)
     } else {
-        println!("running new network on {}:{}", pp::SERVER_ADDRESS.0, pp::SERVER_ADDRESS.1);
+        println!("running new network");
 
         // Create a new network.
-        Parapet::new(pp::SERVER_ADDRESS).unwrap()
+        Parapet::new(config)
     };
 
-    if matches.is_present("interactive") {
+    let mut parapet = match parapet {
+        Ok(parapet) => parapet,
+        Err(e) => {
+            eprintln!("error: could not start the node: {:?}", e);
+            process::exit(1);
+        },
+    };
+
+    let result = if matches.is_present("interactive") {
         println!("starting interactive console");
 
         let mut interactive = Interactive(parapet);
-        interactive.run().unwrap();
+        interactive.run()
     } else {
-        parapet.run().unwrap();
+        parapet.run()
+    };
+
+    if let Err(e) = result {
+        fail(e);
+    }
+}
+
+/// Runs a job through the control API of a running node, returning the exit code.
+fn submit(matches: &ArgMatches, config: Config) -> i32 {
+    let timeout = matches.value_of("timeout").map(|timeout| {
+        Duration::from_secs(timeout.parse().expect("timeout must be a number of seconds"))
+    });
+
+    let (params, task_count) = match matches.values_of("command") {
+        Some(words) => {
+            let words: Vec<_> = words.collect();
+            (json!({ "tasks": [{ "command": words }] }), 1)
+        },
+        None => {
+            let path = matches.value_of("file").unwrap_or(ci::job_file::FILE_NAME);
+
+            // Check the file here, so mistakes point at the right line.
+            let job = match ci::job_file::load(path) {
+                Ok(job) => job,
+                Err(pp::Error::InvalidJobFile { line: 0, reason }) => {
+                    eprintln!("error: {}: {}", path, reason);
+                    return 1;
+                },
+                Err(pp::Error::InvalidJobFile { line, reason }) => {
+                    eprintln!("error: {}:{}: {}", path, line, reason);
+                    return 1;
+                },
+                Err(e) => {
+                    eprintln!("error: could not load the job file {}: {:?}", path, e);
+                    return 1;
+                },
+            };
+
+            // The node may have been started in another directory.
+            let path = fs::canonicalize(path).expect("the job file was just loaded");
+            (json!({ "file": path }), job.tasks.len())
+        },
+    };
+
+    let address = matches.value_of("node").unwrap();
+    let session = Address::resolve(address).map_err(pp::Error::from)
+        .and_then(|address| Session::connect(&address, config.state_dir.as_ref().map(|path| path.as_path())));
+
+    let mut session = match session {
+        Ok(session) => session,
+        Err(e) => {
+            eprintln!("error: could not connect to the node at {}: {:?}", address, e);
+            return 1;
+        },
+    };
+
+    let job = match session.call("submit", params) {
+        Ok(submitted) => submitted["job"].clone(),
+        Err(e) => fail(e),
+    };
+    println!("submitted job {} with {} tasks", job.as_str().unwrap_or(""), task_count);
+
+    let started_at = Instant::now();
+    let mut reported_tasks = HashSet::new();
+
+    loop {
+        let status = match session.call("job_status", json!({ "job": job })) {
+            Ok(status) => status,
+            Err(e) => fail(e),
+        };
+
+        report_tasks(&status, &mut reported_tasks);
+
+        match status["result_code"].as_i64() {
+            Some(0) => {
+                println!("job succeeded");
+                return 0;
+            },
+            Some(result_code) => {
+                println!("job failed with result code {}", result_code);
+                return exit_code(result_code);
+            },
+            None => (),
+        }
+
+        if timeout.map_or(false, |timeout| started_at.elapsed() >= timeout) {
+            println!("job did not finish in time, cancelling it");
+
+            if let Err(e) = session.call("cancel", json!({ "job": job })) {
+                eprintln!("error: could not cancel the job: {:?}", e);
+            }
+
+            return SUBMIT_TIMED_OUT_EXIT_CODE;
+        }
+
+        thread::sleep(Duration::from_millis(SUBMIT_POLL_INTERVAL_MS));
+    }
+}
+
+/// Turns the result code of a failed job into the exit code of `submit`.
+///
+/// Exit codes are a single byte, and must not look like success.
+fn exit_code(result_code: i64) -> i32 {
+    if result_code > 0 && result_code < 256 { result_code as i32 } else { 1 }
+}
+
+/// Prints the tasks of a job that have finished since we last looked.
+fn report_tasks(status: &Value, reported_tasks: &mut HashSet<String>) {
+    let tasks = status["tasks"].as_array().map(|tasks| &tasks[..]).unwrap_or(&[]);
+
+    for task in tasks {
+        let state = task["state"].as_str().unwrap_or("");
+        if state != "succeeded" && state != "failed" && state != "cancelled" { continue }
+
+        let uuid = task["uuid"].as_str().unwrap_or("").to_owned();
+        if !reported_tasks.insert(uuid.clone()) { continue }
+
+        let name = match task["name"].as_str() {
+            Some(name) => format!("`{}`", name),
+            None => uuid,
+        };
+        let progress = format!("[{}/{}]", reported_tasks.len(), tasks.len());
+
+        match state {
+            "succeeded" => println!("{} task {} succeeded", progress, name),
+            "failed" => println!("{} task {} failed with result code {}", progress, name, task["result_code"]),
+            _ => println!("{} task {} was cancelled", progress, name),
+        }
     }
 }
 
+/// Explains why the node stopped, and exits.
+fn fail(error: pp::Error) -> ! {
+    match error {
+        pp::Error::IncompatibleProtocol { ours, theirs } => {
+            eprintln!("error: the remote node speaks protocol v{}.{} ({}), but we speak v{}.{} ({})",
+                      theirs.protocol_major, theirs.protocol_revision, theirs.client,
+                      ours.protocol_major, ours.protocol_revision, ours.client);
+            process::exit(1);
+        },
+        pp::Error::NetworkKeyRequired => {
+            eprintln!("error: the network requires a key, pass one with --network-key");
+            process::exit(1);
+        },
+        pp::Error::Terminated { reason } => {
+            eprintln!("error: the remote node terminated the connection: {}", reason);
+            process::exit(1);
+        },
+        pp::Error::Control { message, .. } => {
+            eprintln!("error: the node refused the request: {}", message);
+            process::exit(1);
+        },
+        e => {
+            eprintln!("error: {:?}", e);
+            process::exit(1);
+        },
+    }
+}
+
+
+#[cfg(test)]
+mod test
+{
+    use super::*;
+    pub use std::collections::BTreeMap;
+    pub use std::env;
+
+    use pp::ci::Sandbox;
+
+    #[test]
+    fn failed_jobs_never_exit_successfully() {
+        for &result_code in &[1, 139, 255, 256, 512, -1] {
+            assert!(exit_code(result_code) != 0, "result code {} exited successfully", result_code);
+        }
+    }
+
+    #[test]
+    fn tasks_killed_by_a_signal_fail_the_submission() {
+        let command = ci::Command {
+            executable: "sh".to_owned(),
+            arguments: vec!["-c".to_owned(), "kill -SEGV $$".to_owned()],
+            environment: BTreeMap::new(),
+            working_dir: None,
+            timeout: None,
+        };
+        let output = ci::sandbox::Basic.run(command, &env::temp_dir());
+
+        assert!(!output.is_successful());
+        assert!(exit_code(output.result_code) != 0);
+    }
+}